@@ -0,0 +1,438 @@
+//! Why sleep was prevented or allowed, one decision at a time
+//!
+//! Every sleep-state decision is appended to `audit.jsonl`, next to
+//! `asp.log`, together with what it was based on: the sessions and holds
+//! asking for prevention, the manual switch, the thermal pause, the battery
+//! and lid policy and the safety cap. The same decision is re-evaluated every
+//! second, so a decision is only written when it or its inputs changed since
+//! the last one. Sessions being reaped, holds expiring, thermal transitions
+//! and the lid closing are written as they happen. `asp why` explains the
+//! current state from the same inputs.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::detection::AgentKind;
+use crate::holds::Hold;
+use crate::sessions::SessionRecord;
+
+/// `audit.jsonl` is moved to `audit.jsonl.1` once it grows past this
+const MAX_LOG_BYTES: u64 = 5 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// Whether sleep should be prevented, from `sync_sleep_state` or the
+    /// daemon's cleanup
+    Decision,
+    /// A dead session was removed or a stale one parked as idle
+    Reap,
+    /// A hold ran out
+    HoldExpired,
+    /// The safety cap expired a session
+    Cap,
+    /// The thermal level or pause changed
+    Thermal,
+    /// The lid was closed
+    Lid,
+}
+
+/// What a decision did to the sleep state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    DisableSleep,
+    EnableSleep,
+    KeepDisabled,
+    KeepEnabled,
+}
+
+impl Action {
+    pub fn new(prevent: bool, was_disabled: bool) -> Self {
+        match (prevent, was_disabled) {
+            (true, false) => Action::DisableSleep,
+            (false, true) => Action::EnableSleep,
+            (true, true) => Action::KeepDisabled,
+            (false, false) => Action::KeepEnabled,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Action::DisableSleep => "disabled sleep",
+            Action::EnableSleep => "re-enabled sleep",
+            Action::KeepDisabled => "kept sleep disabled",
+            Action::KeepEnabled => "left sleep enabled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInput {
+    pub pid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<AgentKind>,
+    pub state: String,
+    pub location: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+impl SessionInput {
+    pub fn new(record: &SessionRecord) -> Self {
+        Self {
+            pid: record.pid,
+            agent: record.agent.clone(),
+            state: record.state.label().to_string(),
+            location: record.location(),
+            command: record.command.clone(),
+        }
+    }
+
+    /// e.g. "PID 4242 (claude, running_tool) in asp git:(main)"
+    pub fn describe(&self) -> String {
+        let agent = self.agent.as_ref().map_or("unknown", AgentKind::as_str);
+        let location = self.command.as_deref().unwrap_or(&self.location);
+        format!(
+            "PID {} ({}, {}) in {}",
+            self.pid, agent, self.state, location
+        )
+    }
+}
+
+/// Everything a decision is based on
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Inputs {
+    /// Sessions whose state asks for sleep to be prevented
+    pub active_sessions: Vec<SessionInput>,
+    #[serde(default)]
+    pub holds: Vec<Hold>,
+    /// The on/off switch in the menu bar and settings
+    pub manual_enabled: bool,
+    pub thermal_level: String,
+    pub thermal_paused: bool,
+    /// Matching policy rules; `None` when nothing asked for prevention, so
+    /// the policy wasn't consulted
+    #[serde(default)]
+    pub policy: Option<PolicyInput>,
+    /// Why the safety cap expired sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capped: Option<String>,
+    pub lid_closed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyInput {
+    pub allow_prevention: bool,
+    pub summary: String,
+}
+
+impl Inputs {
+    /// Whether anything asks for sleep to be prevented, before the switch,
+    /// heat and the policy have their say.
+    pub fn wanted(&self) -> bool {
+        !self.active_sessions.is_empty() || !self.holds.is_empty()
+    }
+
+    /// Whether these inputs call for preventing sleep.
+    pub fn should_prevent(&self) -> bool {
+        self.manual_enabled
+            && self.wanted()
+            && !self.thermal_paused
+            && self
+                .policy
+                .as_ref()
+                .is_none_or(|policy| policy.allow_prevention)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    /// Unix seconds
+    pub ts: u64,
+    /// What ran the decision, e.g. `cleanup` or `hook-Stop`
+    pub source: String,
+    pub kind: EventKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<Action>,
+    /// What happened, or why a decision couldn't be carried out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub inputs: Inputs,
+}
+
+impl Event {
+    pub fn decision(source: &str, ts: u64, action: Action, inputs: Inputs) -> Self {
+        Self {
+            ts,
+            source: source.to_string(),
+            kind: EventKind::Decision,
+            action: Some(action),
+            detail: None,
+            inputs,
+        }
+    }
+
+    pub fn new(source: &str, ts: u64, kind: EventKind, detail: String, inputs: Inputs) -> Self {
+        Self {
+            ts,
+            source: source.to_string(),
+            kind,
+            action: None,
+            detail: Some(detail),
+            inputs,
+        }
+    }
+
+    /// Whether this decision only repeats `last`: same outcome from the
+    /// same inputs, whoever made it.
+    pub fn repeats(&self, last: &Event) -> bool {
+        self.kind == EventKind::Decision
+            && last.kind == EventKind::Decision
+            && self.action == last.action
+            && self.detail == last.detail
+            && self.inputs == last.inputs
+    }
+}
+
+pub fn path() -> PathBuf {
+    crate::logging::log_dir().join("audit.jsonl")
+}
+
+/// Append `event` to the log at `path` unless it repeats the decision saved
+/// in `last`, which is updated with every decision written.
+pub fn record(path: &Path, last: &Path, event: &Event) -> Result<()> {
+    if event.kind == EventKind::Decision {
+        if load_last(last).is_some_and(|last| event.repeats(&last)) {
+            return Ok(());
+        }
+        crate::registry::write_atomic(last, serde_json::to_string(event)?)?;
+    }
+    append(path, event)
+}
+
+/// The last decision written by `record`.
+pub fn load_last(path: &Path) -> Option<Event> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+}
+
+/// Add one event as a single line, moving a full log aside first.
+fn append(path: &Path, event: &Event) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    if fs::metadata(path).is_ok_and(|metadata| metadata.len() > MAX_LOG_BYTES) {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        let _ = fs::rename(path, rotated);
+    }
+    let mut line = serde_json::to_string(event)?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// The last `count` readable events, oldest first.
+pub fn recent(path: &Path, count: usize) -> Vec<Event> {
+    let events = fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect::<Vec<Event>>();
+    let skip = events.len().saturating_sub(count);
+    events.into_iter().skip(skip).collect()
+}
+
+/// Why sleep is or isn't disabled given `inputs`, one reason per line,
+/// most important first.
+pub fn explain(sleep_disabled: bool, inputs: &Inputs, now: u64) -> Vec<String> {
+    let mut reasons = Vec::new();
+    let requests = || {
+        let sessions = inputs
+            .active_sessions
+            .iter()
+            .map(|session| format!("session {}", session.describe()));
+        let holds = inputs
+            .holds
+            .iter()
+            .map(move |hold| format!("hold {}", hold.describe(now)));
+        sessions.chain(holds)
+    };
+
+    if inputs.should_prevent() {
+        reasons.extend(requests().map(|request| format!("Kept awake by {}", request)));
+        if let Some(policy) = &inputs.policy {
+            reasons.push(format!("Policy allows it: {}", policy.summary));
+        }
+        if !sleep_disabled {
+            reasons.push(
+                "Sleep should be disabled but isn't yet; the next sync takes care of it"
+                    .to_string(),
+            );
+        }
+        return reasons;
+    }
+
+    if let Some(capped) = &inputs.capped {
+        reasons.push(format!(
+            "Safety cap reached: {} (asp extend keeps it awake longer)",
+            capped
+        ));
+    }
+    if !inputs.wanted() {
+        reasons.push("No working session or hold asks to keep the machine awake".to_string());
+    } else {
+        reasons.extend(requests().map(|request| format!("Overridden: {}", request)));
+        if !inputs.manual_enabled {
+            reasons.push("Sleep prevention is turned off".to_string());
+        }
+        if inputs.thermal_paused {
+            reasons.push(format!(
+                "Paused while the machine is hot (thermal: {})",
+                inputs.thermal_level
+            ));
+        }
+        if let Some(policy) = inputs
+            .policy
+            .as_ref()
+            .filter(|policy| !policy.allow_prevention)
+        {
+            reasons.push(format!("Policy allows sleep: {}", policy.summary));
+        }
+    }
+    if sleep_disabled {
+        reasons.push(
+            "Sleep is still disabled although nothing needs it; the next sync or the \
+             watchdog re-enables it, or run asp reset"
+                .to_string(),
+        );
+    }
+    reasons
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(pid: u32) -> SessionInput {
+        SessionInput {
+            pid,
+            agent: Some(AgentKind::new("claude")),
+            state: "working".to_string(),
+            location: "asp git:(main)".to_string(),
+            command: None,
+        }
+    }
+
+    fn inputs(sessions: Vec<SessionInput>) -> Inputs {
+        Inputs {
+            active_sessions: sessions,
+            manual_enabled: true,
+            thermal_level: "nominal".to_string(),
+            policy: Some(PolicyInput {
+                allow_prevention: true,
+                summary: "no rule applies".to_string(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn repeated_decisions_are_written_once() {
+        let dir = std::env::temp_dir().join(format!("asp-audit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (log, last) = (dir.join("audit.jsonl"), dir.join("audit.json"));
+        let working = inputs(vec![session(42)]);
+
+        for (ts, source) in [(1, "hook-PreToolUse"), (2, "cleanup"), (3, "hook-Stop")] {
+            let action = Action::new(true, ts > 1);
+            record(
+                &log,
+                &last,
+                &Event::decision(source, ts, action, working.clone()),
+            )
+            .unwrap();
+        }
+        let reap = Event::new(
+            "cleanup",
+            4,
+            EventKind::Reap,
+            "PID 42 exited".into(),
+            inputs(vec![]),
+        );
+        record(&log, &last, &reap).unwrap();
+        let allowed = Event::decision("cleanup", 5, Action::EnableSleep, inputs(vec![]));
+        record(&log, &last, &allowed).unwrap();
+
+        let events = recent(&log, 10);
+        let _ = fs::remove_dir_all(&dir);
+
+        let written = events
+            .iter()
+            .map(|event| (event.ts, event.kind, event.action))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            written,
+            vec![
+                (1, EventKind::Decision, Some(Action::DisableSleep)),
+                (2, EventKind::Decision, Some(Action::KeepDisabled)),
+                (4, EventKind::Reap, None),
+                (5, EventKind::Decision, Some(Action::EnableSleep)),
+            ]
+        );
+        assert_eq!(events[0].inputs.active_sessions[0].pid, 42);
+    }
+
+    #[test]
+    fn explains_who_keeps_the_machine_awake() {
+        let mut working = inputs(vec![session(42)]);
+        working.holds.push(Hold {
+            id: 1,
+            reason: Some("overnight eval".to_string()),
+            created_at: 0,
+            expires_at: 3_600,
+        });
+
+        assert_eq!(
+            explain(true, &working, 0),
+            vec![
+                "Kept awake by session PID 42 (claude, working) in asp git:(main)",
+                "Kept awake by hold #1 overnight eval (1h left)",
+                "Policy allows it: no rule applies",
+            ]
+        );
+    }
+
+    #[test]
+    fn explains_what_overrides_sessions() {
+        let mut hot = inputs(vec![session(42)]);
+        hot.thermal_paused = true;
+        hot.thermal_level = "serious".to_string();
+        hot.policy = None;
+
+        assert_eq!(
+            explain(false, &hot, 0),
+            vec![
+                "Overridden: session PID 42 (claude, working) in asp git:(main)",
+                "Paused while the machine is hot (thermal: serious)",
+            ]
+        );
+
+        let idle = inputs(vec![]);
+        let reasons = explain(true, &idle, 0);
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons[0].starts_with("No working session"));
+        assert!(reasons[1].contains("asp reset"));
+    }
+}
@@ -0,0 +1,61 @@
+//! Latency figures for `asp bench-hook`
+
+use std::time::Duration;
+
+/// What `asp bench-hook` holds a steady-state hook to, end to end.
+pub const HOOK_BUDGET: Duration = Duration::from_millis(10);
+
+/// Timings from a benchmark run, sorted fastest first.
+pub struct Latencies {
+    samples: Vec<Duration>,
+}
+
+impl Latencies {
+    pub fn new(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        Latencies { samples }
+    }
+
+    /// Nearest-rank percentile: the smallest sample at least `p` percent of
+    /// the samples don't exceed.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = ((p / 100.0) * self.samples.len() as f64).ceil() as usize;
+        self.samples[rank.clamp(1, self.samples.len()) - 1]
+    }
+
+    pub fn max(&self) -> Duration {
+        self.samples.last().copied().unwrap_or_default()
+    }
+}
+
+pub fn format_ms(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let latencies = Latencies::new((1..=100).rev().map(Duration::from_millis).collect());
+
+        assert_eq!(latencies.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(latencies.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(latencies.percentile(100.0), Duration::from_millis(100));
+        assert_eq!(latencies.percentile(0.0), Duration::from_millis(1));
+        assert_eq!(latencies.max(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn empty_run_reports_zero() {
+        let latencies = Latencies::new(Vec::new());
+
+        assert_eq!(latencies.percentile(50.0), Duration::ZERO);
+        assert_eq!(latencies.max(), Duration::ZERO);
+        assert_eq!(format_ms(Duration::from_micros(2340)), "2.3 ms");
+    }
+}
@@ -0,0 +1,230 @@
+//! Hard limits on how long sleep can be prevented without a break
+//!
+//! A session whose `Stop` hook never fires, or whose process tree keeps the
+//! CPU heuristic busy, would otherwise keep the Mac awake indefinitely. Each
+//! session gets a deadline counted from when it started preventing sleep,
+//! and sleep prevention as a whole gets one counted from when it was last
+//! switched on. `asp extend` pushes both deadlines out.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::registry::JsonState;
+use crate::sessions::{SessionRecord, SessionState};
+
+/// Maximum continuous sleep prevention. `None` disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SafetyCap {
+    #[serde(default = "default_max_total_minutes")]
+    pub max_total_minutes: Option<u64>,
+    #[serde(default = "default_max_session_minutes")]
+    pub max_session_minutes: Option<u64>,
+}
+
+impl Default for SafetyCap {
+    fn default() -> Self {
+        Self {
+            max_total_minutes: default_max_total_minutes(),
+            max_session_minutes: default_max_session_minutes(),
+        }
+    }
+}
+
+fn default_max_total_minutes() -> Option<u64> {
+    Some(12 * 60)
+}
+
+fn default_max_session_minutes() -> Option<u64> {
+    Some(6 * 60)
+}
+
+/// When sleep prevention as a whole was switched on, and any deadline set by
+/// `asp extend`. Persisted next to the session records.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapState {
+    #[serde(default)]
+    pub preventing_since: Option<u64>,
+    #[serde(default)]
+    pub extended_until: Option<u64>,
+}
+
+impl JsonState for CapState {}
+
+impl CapState {
+    /// Track whether sleep is being prevented. Returns whether anything changed.
+    pub fn track(&mut self, preventing: bool, now: u64) -> bool {
+        let before = *self;
+        if preventing {
+            self.preventing_since.get_or_insert(now);
+        } else {
+            *self = CapState::default();
+        }
+        *self != before
+    }
+
+    /// The total deadline, pushed out by `asp extend` and for as long as
+    /// a hold expiring at `held_until` is live.
+    pub fn deadline(&self, cap: &SafetyCap, held_until: Option<u64>) -> Option<u64> {
+        deadline(
+            self.preventing_since,
+            cap.max_total_minutes,
+            self.extended_until.max(held_until),
+        )
+    }
+}
+
+fn deadline(
+    since: Option<u64>,
+    max_minutes: Option<u64>,
+    extended_until: Option<u64>,
+) -> Option<u64> {
+    let capped = since
+        .zip(max_minutes)
+        .map(|(since, max)| since.saturating_add(max.saturating_mul(60)))?;
+    Some(capped.max(extended_until.unwrap_or(0)))
+}
+
+impl SafetyCap {
+    pub fn session_deadline(&self, record: &SessionRecord) -> Option<u64> {
+        deadline(
+            record.preventing_since(),
+            self.max_session_minutes,
+            record.cap_extended_until,
+        )
+    }
+
+    /// Why `record` has to stop preventing sleep, if it does.
+    pub fn session_exceeded(&self, record: &SessionRecord, now: u64) -> Option<String> {
+        if !record.state.prevents_sleep() {
+            return None;
+        }
+        let deadline = self.session_deadline(record)?;
+        (now >= deadline).then(|| {
+            format!(
+                "session kept the Mac awake for {} min (limit {} min)",
+                now.saturating_sub(record.preventing_since().unwrap_or(now)) / 60,
+                self.max_session_minutes.unwrap_or(0)
+            )
+        })
+    }
+
+    /// Why sleep prevention as a whole has to stop, if it does.
+    pub fn total_exceeded(
+        &self,
+        state: &CapState,
+        held_until: Option<u64>,
+        now: u64,
+    ) -> Option<String> {
+        let deadline = state.deadline(self, held_until)?;
+        (now >= deadline).then(|| {
+            format!(
+                "sleep prevented continuously for {} min (limit {} min)",
+                now.saturating_sub(state.preventing_since.unwrap_or(now)) / 60,
+                self.max_total_minutes.unwrap_or(0)
+            )
+        })
+    }
+}
+
+/// Stop `record` from preventing sleep until the user extends it or starts
+/// a new prompt.
+pub fn expire(record: &mut SessionRecord, reason: &str, now: u64) {
+    record.set_state(SessionState::Expired, now);
+    record.expired_reason = Some(reason.to_string());
+}
+
+/// When an extension of `minutes` from `now` runs out.
+pub fn extension_end(minutes: u64, now: u64) -> Result<u64> {
+    minutes
+        .checked_mul(60)
+        .and_then(|secs| now.checked_add(secs))
+        .with_context(|| format!("Extension of {} min is too long", minutes))
+}
+
+/// Keep `record` going at least `until`, reviving it if it had expired.
+pub fn extend(record: &mut SessionRecord, until: u64, now: u64) {
+    if record.state == SessionState::Expired {
+        record.set_state(SessionState::Working, now);
+    }
+    record.cap_extended_until = Some(record.cap_extended_until.unwrap_or(0).max(until));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3_600;
+
+    #[test]
+    fn session_expires_after_its_limit() {
+        let cap = SafetyCap {
+            max_total_minutes: None,
+            max_session_minutes: Some(60),
+        };
+        let mut record = SessionRecord::new(42, "test", 1_000);
+
+        assert_eq!(cap.session_exceeded(&record, 1_000 + HOUR - 1), None);
+        let reason = cap.session_exceeded(&record, 1_000 + HOUR).unwrap();
+        assert_eq!(
+            reason,
+            "session kept the Mac awake for 60 min (limit 60 min)"
+        );
+
+        expire(&mut record, &reason, 1_000 + HOUR);
+        assert!(!record.state.prevents_sleep());
+        assert_eq!(cap.session_exceeded(&record, 1_000 + 2 * HOUR), None);
+    }
+
+    #[test]
+    fn extend_revives_expired_session() {
+        let cap = SafetyCap::default();
+        let now = 10 * HOUR;
+        let mut record = SessionRecord::new(42, "test", 0);
+        expire(&mut record, "limit", now);
+
+        extend(&mut record, extension_end(30, now).unwrap(), now);
+
+        assert_eq!(record.state, SessionState::Working);
+        assert_eq!(record.expired_reason, None);
+        // A revived session starts a fresh window, never shorter than the extension.
+        assert_eq!(cap.session_deadline(&record), Some(now + 6 * HOUR));
+
+        let mut running = SessionRecord::new(43, "test", 0);
+        extend(&mut running, extension_end(60, 7 * HOUR).unwrap(), 7 * HOUR);
+        assert_eq!(cap.session_deadline(&running), Some(8 * HOUR));
+        assert!(extension_end(400_000_000_000_000_000, 7 * HOUR).is_err());
+        assert!(extension_end(u64::MAX / 60, 7 * HOUR).is_err());
+    }
+
+    #[test]
+    fn total_limit_follows_prevention() {
+        let cap = SafetyCap {
+            max_total_minutes: Some(120),
+            max_session_minutes: None,
+        };
+        let mut state = CapState::default();
+
+        assert!(state.track(true, 100));
+        assert!(!state.track(true, 200));
+        assert_eq!(cap.total_exceeded(&state, None, 100 + 2 * HOUR - 1), None);
+        assert!(cap.total_exceeded(&state, None, 100 + 2 * HOUR).is_some());
+
+        // A live hold pushes the deadline out without being remembered.
+        let held_until = Some(100 + 4 * HOUR);
+        assert_eq!(cap.total_exceeded(&state, held_until, 100 + 2 * HOUR), None);
+        assert_eq!(state.extended_until, None);
+
+        state.extended_until = Some(100 + 3 * HOUR);
+        assert_eq!(cap.total_exceeded(&state, None, 100 + 2 * HOUR), None);
+        assert_eq!(state.deadline(&cap, held_until), Some(100 + 4 * HOUR));
+
+        let huge = SafetyCap {
+            max_total_minutes: Some(u64::MAX),
+            max_session_minutes: None,
+        };
+        assert_eq!(state.deadline(&huge, None), Some(u64::MAX));
+
+        assert!(state.track(false, 300));
+        assert_eq!(state, CapState::default());
+    }
+}
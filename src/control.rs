@@ -0,0 +1,543 @@
+//! Control socket of the resident process
+//!
+//! `asp agent`, `asp menubar` and `asp daemon` listen on `control.sock` in
+//! the session registry. Hooks and the CLI try it first: the resident
+//! process already samples the process table and holds the power backend,
+//! so a hook no longer scans every process or runs pmset itself. When
+//! nobody is listening they fall back to the registry files as before.
+//!
+//! Messages are JSON-RPC 2.0, one per line. Only the user who owns the
+//! registry is served. A `subscribe` call keeps its connection open and
+//! gets a `status` notification whenever the sleep state, the sessions or
+//! the holds change.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::hooks::HookPayload;
+
+pub const SOCKET_NAME: &str = "control.sock";
+/// Hooks have a few seconds before the agent gives up on them; leave time
+/// for the file-based fallback.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Hook payloads are trimmed to a few fields, so requests stay small.
+const MAX_REQUEST_BYTES: u64 = 64 * 1024;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The call was understood but failed
+pub const SERVER_ERROR: i64 = -32000;
+
+pub fn socket_path() -> PathBuf {
+    crate::registry::dir().join(SOCKET_NAME)
+}
+
+static REGISTRY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Held by each control call and by the resident process's own ticks while
+/// they update the registry, so neither writes back a record the other has
+/// just changed. Not reentrant: handlers must not take it again.
+pub fn registry_lock() -> MutexGuard<'static, ()> {
+    REGISTRY_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl Response {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+/// A session to start tracking, or a hook event for one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Registration {
+    /// `asp hook`/`asp start`: apply `event` to the session of the agent
+    /// `client_pid` descends from
+    Hook {
+        event: String,
+        payload: HookPayload,
+        client_pid: u32,
+    },
+    /// `asp run`/`asp watch`: keep awake while `pid` runs
+    Process { pid: u32, command: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Unregistration {
+    /// `asp stop`: the agent behind `client_pid` finished its turn
+    Hook {
+        payload: HookPayload,
+        client_pid: u32,
+    },
+    /// The process of an `asp run`/`asp watch` session is gone
+    Process { pid: u32 },
+}
+
+/// Keep a session from being parked as idle, by agent PID or session id
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Heartbeat {
+    #[serde(default)]
+    pub pid: Option<u32>,
+    #[serde(default)]
+    pub session_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HoldRequest {
+    Add {
+        secs: u64,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    Release {
+        release: u32,
+    },
+}
+
+/// `asp extend`: push every safety cap deadline `minutes` from now
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Extension {
+    pub minutes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Call {
+    Register(Registration),
+    Unregister(Unregistration),
+    Heartbeat(Heartbeat),
+    List,
+    Status,
+    Hold(HoldRequest),
+    Extend(Extension),
+    Reset,
+    Subscribe,
+}
+
+impl Call {
+    pub fn parse(method: &str, params: Value) -> Result<Self, RpcError> {
+        fn params_of<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+            serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+        }
+        match method {
+            "register" => params_of(params).map(Call::Register),
+            "unregister" => params_of(params).map(Call::Unregister),
+            "heartbeat" => params_of(params).map(Call::Heartbeat),
+            "list" => Ok(Call::List),
+            "status" => Ok(Call::Status),
+            "hold" => params_of(params).map(Call::Hold),
+            "extend" => params_of(params).map(Call::Extend),
+            "reset" => Ok(Call::Reset),
+            "subscribe" => Ok(Call::Subscribe),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {:?}", method),
+            )),
+        }
+    }
+}
+
+/// Runs a call and returns its result. Errors go back to the client.
+pub type Handler = fn(Call) -> Result<Value>;
+/// The status subscribers are sent after each call
+pub type StatusFn = fn() -> Value;
+
+pub struct Server {
+    handle: Handler,
+    status: StatusFn,
+    subscribers: Mutex<Vec<UnixStream>>,
+    last_status: Mutex<Option<Value>>,
+    /// Held for as long as the server runs; see `start`.
+    _lock: fs::File,
+}
+
+/// Taken by whoever listens on `socket`, so two processes starting at once
+/// don't both remove and rebind it.
+pub fn lock_path(socket: &Path) -> PathBuf {
+    socket.with_extension("lock")
+}
+
+impl Server {
+    /// Listen on `path` unless another process already does. Returns `None`
+    /// in that case.
+    pub fn start(path: &Path, handle: Handler, status: StatusFn) -> Result<Option<Arc<Self>>> {
+        let lock_path = lock_path(path);
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open {}", lock_path.display()))?;
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Ok(None);
+        }
+        // A resident from before the lock file may still be listening.
+        if UnixStream::connect(path).is_ok() {
+            return Ok(None);
+        }
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind {}", path.display()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to restrict {}", path.display()))?;
+
+        let server = Arc::new(Self {
+            handle,
+            status,
+            subscribers: Mutex::new(Vec::new()),
+            last_status: Mutex::new(None),
+            _lock: lock,
+        });
+        let accepting = Arc::clone(&server);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        crate::logging::log(&format!("[control] Accept failed: {}", e));
+                        continue;
+                    }
+                };
+                let server = Arc::clone(&accepting);
+                std::thread::spawn(move || {
+                    if let Err(e) = server.serve(stream) {
+                        crate::logging::log(&format!("[control] Connection failed: {}", e));
+                    }
+                });
+            }
+        });
+        Ok(Some(server))
+    }
+
+    /// Answer requests on `stream` until the client hangs up or goes quiet.
+    /// A subscriber's connection is kept for notifications instead.
+    fn serve(&self, stream: UnixStream) -> Result<()> {
+        let uid = crate::helper::peer_uid(&stream)?;
+        if uid != unsafe { libc::geteuid() } {
+            bail!("uid {} is not allowed", uid);
+        }
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        loop {
+            let mut line = String::new();
+            match (&mut reader).take(MAX_REQUEST_BYTES).read_line(&mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let (response, subscribed) = self.answer(line.trim());
+            writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+            if subscribed {
+                self.subscribers.lock().unwrap().push(writer);
+                return Ok(());
+            }
+        }
+    }
+
+    /// The response to one request line, and whether it subscribed.
+    fn answer(&self, line: &str) -> (Response, bool) {
+        let request = match serde_json::from_str::<Request>(line) {
+            Ok(request) if request.jsonrpc == "2.0" => request,
+            Ok(request) => {
+                let error = RpcError::new(INVALID_REQUEST, "Expected jsonrpc 2.0");
+                return (Response::new(request.id, Err(error)), false);
+            }
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, e.to_string());
+                return (Response::new(Value::Null, Err(error)), false);
+            }
+        };
+        let call = match Call::parse(&request.method, request.params) {
+            Ok(call) => call,
+            Err(error) => return (Response::new(request.id, Err(error)), false),
+        };
+        let subscribed = call == Call::Subscribe;
+        // Subscribers are written to after the registry lock is released,
+        // so a slow one doesn't hold up the next hook.
+        let (outcome, status) = {
+            let _registry = registry_lock();
+            let outcome =
+                (self.handle)(call).map_err(|e| RpcError::new(SERVER_ERROR, format!("{:#}", e)));
+            (outcome, (!subscribed).then(self.status))
+        };
+        if subscribed {
+            if let Ok(status) = &outcome {
+                *self.last_status.lock().unwrap() = Some(status.clone());
+            }
+        }
+        if let Some(status) = status {
+            self.publish(status);
+        }
+        (Response::new(request.id, outcome), subscribed)
+    }
+
+    /// Send `status` to every subscriber if it changed since the last time.
+    /// Subscribers that hung up are dropped.
+    pub fn publish(&self, status: Value) {
+        {
+            let mut last = self.last_status.lock().unwrap();
+            if last.as_ref() == Some(&status) {
+                return;
+            }
+            *last = Some(status.clone());
+        }
+        let notification = json!({"jsonrpc": "2.0", "method": "status", "params": status});
+        let line = format!("{}\n", notification);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain_mut(|subscriber| subscriber.write_all(line.as_bytes()).is_ok());
+    }
+}
+
+/// Call `method` on the resident process. `Ok(None)` means nobody is
+/// listening, or the call is one it doesn't know, and the caller should do
+/// the work itself. Once the request is sent it may have been carried out,
+/// so no answer is an error rather than a reason to do it again.
+pub fn call(path: &Path, method: &str, params: Value) -> Result<Option<Value>> {
+    let Ok(stream) = UnixStream::connect(path) else {
+        return Ok(None);
+    };
+    let response = exchange(stream, method, params)
+        .with_context(|| format!("No answer from the resident process to {}", method))?;
+    match response.error {
+        // An older resident process that doesn't know the call
+        Some(error) if error.code == METHOD_NOT_FOUND || error.code == INVALID_PARAMS => Ok(None),
+        Some(error) => bail!("{}", error.message),
+        None => Ok(Some(response.result.unwrap_or(Value::Null))),
+    }
+}
+
+fn exchange(mut stream: UnixStream, method: &str, params: Value) -> Result<Response> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let request = Request {
+        jsonrpc: "2.0".to_string(),
+        id: json!(1),
+        method: method.to_string(),
+        params,
+    };
+    writeln!(stream, "{}", serde_json::to_string(&request)?)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(line.trim())?)
+}
+
+/// Subscribe to status changes. Returns `None` when nobody is listening;
+/// otherwise the current status and then every change, until the resident
+/// process goes away.
+pub fn subscribe(path: &Path) -> Result<Option<impl Iterator<Item = Value>>> {
+    let Ok(mut stream) = UnixStream::connect(path) else {
+        return Ok(None);
+    };
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": "subscribe"});
+    writeln!(stream, "{}", request)?;
+    let messages = BufReader::new(stream)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<Value>(&line).ok())
+        .filter_map(|mut message| match message.get("method") {
+            Some(_) => message.get_mut("params").map(Value::take),
+            None => message.get_mut("result").map(Value::take),
+        });
+    Ok(Some(messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> Value {
+        json!({"sleep_disabled": false})
+    }
+
+    fn handle(call: Call) -> Result<Value> {
+        match call {
+            Call::Register(Registration::Hook { event, .. }) => Ok(json!({"state": event})),
+            Call::Register(Registration::Process { pid, .. }) => Ok(json!({"pid": pid})),
+            Call::Status | Call::Subscribe => Ok(status()),
+            Call::Hold(HoldRequest::Release { release }) => bail!("No hold #{}", release),
+            _ => Ok(Value::Null),
+        }
+    }
+
+    fn socket(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("asp-control-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn parses_calls() {
+        let hook = Call::parse(
+            "register",
+            json!({"event": "PreToolUse", "payload": {"session_id": "abc"}, "client_pid": 7}),
+        )
+        .unwrap();
+        let Call::Register(Registration::Hook { payload, .. }) = hook else {
+            panic!("expected a hook registration, got {:?}", hook);
+        };
+        assert_eq!(payload.session_id(), Some("abc"));
+
+        assert_eq!(
+            Call::parse("unregister", json!({"pid": 42})).unwrap(),
+            Call::Unregister(Unregistration::Process { pid: 42 })
+        );
+        assert_eq!(
+            Call::parse("hold", json!({"secs": 60})).unwrap(),
+            Call::Hold(HoldRequest::Add {
+                secs: 60,
+                reason: None
+            })
+        );
+        assert_eq!(
+            Call::parse("extend", json!({"minutes": 30})).unwrap(),
+            Call::Extend(Extension { minutes: 30 })
+        );
+        assert_eq!(
+            Call::parse("frobnicate", Value::Null).unwrap_err().code,
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            Call::parse("heartbeat", json!({"tab": 1}))
+                .unwrap_err()
+                .code,
+            INVALID_PARAMS
+        );
+    }
+
+    #[test]
+    fn serves_calls_and_falls_back_without_server() {
+        let path = socket("calls");
+        assert_eq!(call(&path, "status", Value::Null).unwrap(), None);
+
+        let server = Server::start(&path, handle, status).unwrap().unwrap();
+        assert!(Server::start(&path, handle, status).unwrap().is_none());
+
+        let registered = call(
+            &path,
+            "register",
+            json!({"event": "Stop", "payload": {}, "client_pid": 1}),
+        )
+        .unwrap();
+        assert_eq!(registered, Some(json!({"state": "Stop"})));
+        assert_eq!(call(&path, "nope", Value::Null).unwrap(), None);
+        let error = call(&path, "hold", json!({"release": 3})).unwrap_err();
+        assert_eq!(error.to_string(), "No hold #3");
+        drop(server);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(lock_path(&path));
+    }
+
+    #[test]
+    fn start_backs_off_while_another_process_starts() {
+        let path = socket("locked");
+        let lock = fs::File::create(lock_path(&path)).unwrap();
+        assert_eq!(
+            unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) },
+            0
+        );
+
+        assert!(Server::start(&path, handle, status).unwrap().is_none());
+        assert!(!path.exists());
+        let _ = fs::remove_file(lock_path(&path));
+    }
+
+    #[test]
+    fn silent_server_is_an_error_not_a_fallback() {
+        let path = socket("silent");
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+        });
+
+        assert!(call(&path, "status", Value::Null).is_err());
+        server.join().unwrap();
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn subscribers_get_status_changes() {
+        let path = socket("subscribe");
+        let server = Server::start(&path, handle, status).unwrap().unwrap();
+        let mut statuses = subscribe(&path).unwrap().unwrap();
+        assert_eq!(statuses.next(), Some(json!({"sleep_disabled": false})));
+
+        // Wait for the server to file the connection as a subscriber.
+        while server.subscribers.lock().unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        server.publish(json!({"sleep_disabled": false}));
+        server.publish(json!({"sleep_disabled": true}));
+
+        assert_eq!(statuses.next(), Some(json!({"sleep_disabled": true})));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(lock_path(&path));
+    }
+}
@@ -0,0 +1,329 @@
+//! Which processes are coding agents
+//!
+//! Detection is a list of rules matched against the process table. Each rule
+//! names the agent it detects and the `argv` shape that gives it away. Built-in
+//! rules cover the agents we know; extra ones can be added in `agents.json`
+//! next to `settings.json` and are tried first.
+//!
+//! Several agents ship as a small native binary launched by a node or python
+//! wrapper, so the same session shows up twice. Rules with
+//! `yield_to_child` drop the wrapper when one of its children was detected as
+//! the same agent.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::process_table::ProcessInfo;
+
+/// Name of a coding agent, e.g. `claude` or `gemini`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AgentKind(String);
+
+impl AgentKind {
+    pub fn new(name: &str) -> Self {
+        Self(name.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for AgentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentRule {
+    /// Shown by `asp debug`, e.g. `codex-node-wrapper`
+    pub name: String,
+    pub agent: AgentKind,
+    /// Patterns for the executable name in `argv[0]` (or the kernel process
+    /// name). `*` matches any run of characters, e.g. `python3*`.
+    pub argv0: Vec<String>,
+    /// Patterns for the script name in `argv[1]`, for agents started by an
+    /// interpreter listed in `argv0`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub script: Vec<String>,
+    /// Patterns every one of which some later argument has to match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args_include: Vec<String>,
+    /// Patterns no later argument may match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args_exclude: Vec<String>,
+    /// Drop the match when a child process was detected as the same agent
+    #[serde(default)]
+    pub yield_to_child: bool,
+}
+
+impl AgentRule {
+    fn new(name: &str, agent: &str, argv0: &[&str]) -> Self {
+        Self {
+            name: name.to_string(),
+            agent: AgentKind::new(agent),
+            argv0: argv0.iter().map(|s| s.to_string()).collect(),
+            script: Vec::new(),
+            args_include: Vec::new(),
+            args_exclude: Vec::new(),
+            yield_to_child: false,
+        }
+    }
+
+    /// `interpreter script ...` where the agent is the script.
+    fn wrapper(name: &str, agent: &str, interpreters: &[&str]) -> Self {
+        Self {
+            script: vec![agent.to_string()],
+            yield_to_child: true,
+            ..Self::new(name, agent, interpreters)
+        }
+    }
+
+    fn excluding(mut self, args: &[&str]) -> Self {
+        self.args_exclude = args.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn matches(&self, process: &ProcessInfo) -> bool {
+        let argv0 = process.argv.first().map(String::as_str);
+        let name_matches = |token: &str, patterns: &[String]| {
+            let name = executable_name(token);
+            patterns.iter().any(|pattern| glob_match(pattern, name))
+        };
+
+        let (executable_matches, args_start) = if self.script.is_empty() {
+            let matches = argv0.is_some_and(|arg0| name_matches(arg0, &self.argv0))
+                || name_matches(&process.comm, &self.argv0);
+            (matches, 1)
+        } else {
+            let matches = argv0.is_some_and(|arg0| name_matches(arg0, &self.argv0))
+                && process
+                    .argv
+                    .get(1)
+                    .is_some_and(|arg1| name_matches(arg1, &self.script));
+            (matches, 2)
+        };
+        if !executable_matches {
+            return false;
+        }
+
+        let args = process.argv.get(args_start..).unwrap_or_default();
+        let any_arg = |pattern: &String| args.iter().any(|arg| glob_match(pattern, arg));
+        self.args_include.iter().all(any_arg) && !self.args_exclude.iter().any(any_arg)
+    }
+}
+
+/// Basename without a Windows `.exe` suffix.
+fn executable_name(token: &str) -> &str {
+    let basename = token.rsplit('/').next().unwrap_or(token);
+    basename.strip_suffix(".exe").unwrap_or(basename)
+}
+
+/// Match `text` against a pattern where `*` stands for any run of characters.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+const NODE: &[&str] = &["node", "bun"];
+const PYTHON: &[&str] = &["python", "python3*"];
+
+pub fn builtin_rules() -> Vec<AgentRule> {
+    vec![
+        AgentRule::new("claude", "claude", &["claude"]),
+        AgentRule::new("codex", "codex", &["codex"]).excluding(&["app-server"]),
+        AgentRule::wrapper("codex-node-wrapper", "codex", NODE).excluding(&["app-server"]),
+        AgentRule::new("gemini", "gemini", &["gemini"]),
+        AgentRule::wrapper("gemini-node-wrapper", "gemini", NODE),
+        AgentRule::new("aider", "aider", &["aider"]),
+        AgentRule::wrapper("aider-python", "aider", PYTHON),
+        AgentRule::new("opencode", "opencode", &["opencode"]),
+        AgentRule::wrapper("opencode-node-wrapper", "opencode", NODE),
+        AgentRule::new("amp", "amp", &["amp"]),
+        AgentRule::wrapper("amp-node-wrapper", "amp", NODE),
+        AgentRule::new("cursor-agent", "cursor", &["cursor-agent"]),
+        AgentRule {
+            script: vec!["cursor-agent".to_string()],
+            ..AgentRule::wrapper("cursor-agent-node-wrapper", "cursor", NODE)
+        },
+    ]
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentRules {
+    rules: Vec<AgentRule>,
+}
+
+impl AgentRules {
+    pub fn new(rules: Vec<AgentRule>) -> Self {
+        Self { rules }
+    }
+
+    /// `agents.json` next to `settings.json`.
+    pub fn path() -> PathBuf {
+        crate::settings::AppSettings::settings_path().with_file_name("agents.json")
+    }
+
+    /// Rules from `path` followed by the built-in ones. A missing or invalid
+    /// file only leaves the built-in rules.
+    pub fn load(path: &Path) -> Self {
+        let mut rules = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Vec<AgentRule>>(&content).ok())
+            .unwrap_or_default();
+        rules.extend(builtin_rules());
+        Self::new(rules)
+    }
+
+    pub fn agents(&self) -> impl Iterator<Item = &AgentKind> {
+        self.rules.iter().map(|rule| &rule.agent)
+    }
+
+    /// The first rule matching `process`.
+    pub fn classify(&self, process: &ProcessInfo) -> Option<&AgentRule> {
+        self.rules.iter().find(|rule| rule.matches(process))
+    }
+
+    /// Agent processes in `processes` with the rule that matched each, minus
+    /// wrappers whose child was detected as the same agent.
+    pub fn detect<'a>(
+        &self,
+        processes: impl IntoIterator<Item = &'a ProcessInfo>,
+    ) -> Vec<(&'a ProcessInfo, &AgentRule)> {
+        let matches = processes
+            .into_iter()
+            .filter_map(|process| self.classify(process).map(|rule| (process, rule)))
+            .collect::<Vec<_>>();
+        let parents = matches
+            .iter()
+            .map(|(process, rule)| (process.ppid, &rule.agent))
+            .collect::<HashSet<_>>();
+        matches
+            .into_iter()
+            .filter(|(process, rule)| {
+                !(rule.yield_to_child && parents.contains(&(process.pid, &rule.agent)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, ppid: u32, argv: &[&str]) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            ppid,
+            comm: executable_name(argv[0]).to_string(),
+            argv: argv.iter().map(|arg| arg.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn agent_of(rules: &AgentRules, argv: &[&str]) -> Option<String> {
+        rules
+            .classify(&process(1, 0, argv))
+            .map(|rule| rule.agent.to_string())
+    }
+
+    #[test]
+    fn builtin_rules_cover_known_agents() {
+        let rules = AgentRules::new(builtin_rules());
+
+        for (argv, expected) in [
+            (&["/opt/homebrew/bin/gemini"][..], Some("gemini")),
+            (
+                &["node", "/usr/local/bin/gemini", "-p", "hi"],
+                Some("gemini"),
+            ),
+            (&["python3.12", "/Users/me/.local/bin/aider"], Some("aider")),
+            (&["opencode"], Some("opencode")),
+            (&["node", "/opt/homebrew/bin/amp"], Some("amp")),
+            (&["cursor-agent", "--resume"], Some("cursor")),
+            (&["codex.exe"], Some("codex")),
+            (&["node", "/usr/local/bin/codex", "app-server"], None),
+            (&["python3", "manage.py"], None),
+            (&["node", "server.js"], None),
+        ] {
+            assert_eq!(agent_of(&rules, argv).as_deref(), expected, "{:?}", argv);
+        }
+    }
+
+    #[test]
+    fn wrapper_yields_to_native_child() {
+        let rules = AgentRules::new(builtin_rules());
+        let processes = vec![
+            process(10, 1, &["node", "/opt/homebrew/bin/codex"]),
+            process(11, 10, &["/opt/homebrew/lib/codex/codex"]),
+            process(20, 1, &["node", "/opt/homebrew/bin/gemini"]),
+        ];
+
+        let detected = rules
+            .detect(&processes)
+            .into_iter()
+            .map(|(process, rule)| (process.pid, rule.name.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(detected, vec![(11, "codex"), (20, "gemini-node-wrapper")]);
+    }
+
+    #[test]
+    fn config_rules_come_first() {
+        let path = std::env::temp_dir().join(format!("asp-agents-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"[{"name": "goose", "agent": "goose", "argv0": ["goose"], "args_include": ["session"]},
+                {"name": "my-claude", "agent": "claude-beta", "argv0": ["claude"]}]"#,
+        )
+        .unwrap();
+
+        let rules = AgentRules::load(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(
+            agent_of(&rules, &["goose", "session"]).as_deref(),
+            Some("goose")
+        );
+        assert_eq!(agent_of(&rules, &["goose", "run"]), None);
+        assert_eq!(
+            agent_of(&rules, &["claude"]).as_deref(),
+            Some("claude-beta")
+        );
+        assert_eq!(
+            AgentRules::load(Path::new("/nonexistent/agents.json")),
+            AgentRules::new(builtin_rules())
+        );
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("python3*", "python3.12"));
+        assert!(glob_match("python3*", "python3"));
+        assert!(!glob_match("python3*", "python2"));
+        assert!(glob_match("*agent*", "cursor-agent-cli"));
+        assert!(glob_match("a*b*c", "abbc"));
+        assert!(!glob_match("a*bc", "abc-"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+}
@@ -0,0 +1,376 @@
+//! Privileged helper
+//!
+//! `asp helper` runs as root from a LaunchDaemon and listens on a Unix
+//! socket. It replaces the old `NOPASSWD: /usr/bin/pmset` sudoers rule, which
+//! allowed any pmset argument, with a fixed set of requests. Each connection
+//! carries one JSON request line and gets one JSON response line back. Peers
+//! are identified by their socket credentials and only root and the uids
+//! given with `--allow-uid` are served.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+pub const SOCKET_PATH: &str = "/var/run/agents-sleep-preventer.sock";
+pub const LAUNCH_DAEMON_LABEL: &str = "com.charlontank.agents-sleep-preventer.helper";
+/// Root-owned copy of `asp` the LaunchDaemon runs, so the user can't swap it.
+pub const HELPER_BINARY_PATH: &str =
+    "/Library/PrivilegedHelperTools/com.charlontank.agents-sleep-preventer.helper";
+
+/// Requests are a few hundred bytes at most; anything longer is not ours.
+const MAX_REQUEST_BYTES: u64 = 1024;
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Clients send their request as soon as they connect.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// Connections served at once. Any more are closed right away, so a peer
+/// opening connections in a loop can't make root spawn threads unbounded.
+#[cfg(target_os = "macos")]
+const MAX_CONNECTIONS: usize = 4;
+/// Tries at 100ms apart while a freshly loaded helper starts listening.
+const HELPER_START_ATTEMPTS: u32 = 30;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Request {
+    SetDisablesleep {
+        enabled: bool,
+    },
+    Sleepnow,
+    Query,
+    /// Put back settings saved by `asp install`. Only the arguments
+    /// [`crate::power::snapshot::check_restore_args`] accepts are run.
+    RestorePowerSettings {
+        args: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sleep_disabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Response {
+    pub fn ok(sleep_disabled: Option<bool>) -> Self {
+        Self {
+            ok: true,
+            sleep_disabled,
+            error: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            sleep_disabled: None,
+            error: Some(message.into()),
+        }
+    }
+}
+
+/// Send one request to the helper and wait for its answer.
+pub fn request(request: Request) -> Result<Response> {
+    request_at(Path::new(SOCKET_PATH), request)
+}
+
+/// Like [`request`], but give a helper launchd has only just started a
+/// moment to open its socket. Only for requests that are safe to repeat.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+pub fn request_after_start(request: Request) -> Result<Response> {
+    let mut attempts = 0;
+    loop {
+        match self::request(request.clone()) {
+            Err(_) if attempts < HELPER_START_ATTEMPTS => {
+                attempts += 1;
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            result => return result,
+        }
+    }
+}
+
+fn request_at(socket: &Path, request: Request) -> Result<Response> {
+    let mut stream = UnixStream::connect(socket).with_context(|| {
+        format!(
+            "Privileged helper is not running ({}); run `asp install`",
+            socket.display()
+        )
+    })?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    writeln!(stream, "{}", serde_json::to_string(&request)?)?;
+
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .context("No answer from the privileged helper")?;
+    let response: Response =
+        serde_json::from_str(line.trim()).context("Invalid answer from the privileged helper")?;
+    if !response.ok {
+        bail!(
+            "Privileged helper refused {:?}: {}",
+            request,
+            response.error.as_deref().unwrap_or("unknown error")
+        );
+    }
+    Ok(response)
+}
+
+/// uid of the process at the other end of `stream`.
+pub fn peer_uid(stream: &UnixStream) -> Result<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    #[cfg(target_os = "linux")]
+    {
+        let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result != 0 {
+            bail!("getsockopt(SO_PEERCRED) failed");
+        }
+        Ok(cred.uid)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let mut uid: libc::uid_t = 0;
+        let mut gid: libc::gid_t = 0;
+        if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+            bail!("getpeereid failed");
+        }
+        Ok(uid)
+    }
+}
+
+/// Serve one connection: check the peer, read one request, run it through
+/// `handle` and write the response. Every request is logged.
+pub fn handle_connection(
+    stream: UnixStream,
+    allowed_uids: &[u32],
+    handle: &mut impl FnMut(Request) -> Result<Response>,
+) -> Result<()> {
+    let uid = peer_uid(&stream)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+
+    // Peers that aren't allowed are answered without reading anything, so
+    // they can't keep a connection busy.
+    let response = if uid != 0 && !allowed_uids.contains(&uid) {
+        crate::logging::log(&format!("[helper] uid {} denied", uid));
+        Response::error(format!("uid {} is not allowed", uid))
+    } else {
+        let mut line = String::new();
+        BufReader::new(stream.take(MAX_REQUEST_BYTES)).read_line(&mut line)?;
+        match serde_json::from_str::<Request>(line.trim()) {
+            Ok(request) => {
+                let response =
+                    handle(request.clone()).unwrap_or_else(|e| Response::error(e.to_string()));
+                crate::logging::log(&format!(
+                    "[helper] uid {} {:?} -> {}",
+                    uid,
+                    request,
+                    if response.ok { "ok" } else { "failed" }
+                ));
+                response
+            }
+            Err(e) => {
+                crate::logging::log(&format!(
+                    "[helper] uid {} sent an invalid request: {}",
+                    uid,
+                    line.trim()
+                ));
+                Response::error(format!("invalid request: {}", e))
+            }
+        }
+    };
+
+    writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    Ok(())
+}
+
+/// Run the helper: listen on [`SOCKET_PATH`] and apply requests with pmset.
+#[cfg(target_os = "macos")]
+pub fn run(allowed_uids: &[u32]) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    if unsafe { libc::geteuid() } != 0 {
+        bail!("asp helper must run as root");
+    }
+
+    let _ = std::fs::remove_file(SOCKET_PATH);
+    let listener = UnixListener::bind(SOCKET_PATH)
+        .with_context(|| format!("Failed to bind {}", SOCKET_PATH))?;
+    // Anyone may connect; peer credentials decide who is served.
+    std::fs::set_permissions(SOCKET_PATH, std::fs::Permissions::from_mode(0o666))?;
+    crate::logging::log(&format!(
+        "[helper] Listening on {} for uids {:?}",
+        SOCKET_PATH, allowed_uids
+    ));
+
+    // A thread per connection, up to MAX_CONNECTIONS, so a client that
+    // never sends its request can't hold up everyone else until it times out.
+    static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if CONNECTIONS.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                let allowed_uids = allowed_uids.to_vec();
+                std::thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, &allowed_uids, &mut perform) {
+                        crate::logging::log(&format!("[helper] Connection failed: {}", e));
+                    }
+                    CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) => crate::logging::log(&format!("[helper] Accept failed: {}", e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn perform(request: Request) -> Result<Response> {
+    match request {
+        Request::SetDisablesleep { enabled } => {
+            pmset(&["-a", "disablesleep", if enabled { "1" } else { "0" }])?;
+            Ok(Response::ok(Some(crate::power::is_sleep_disabled())))
+        }
+        Request::Sleepnow => {
+            pmset(&["sleepnow"])?;
+            Ok(Response::ok(None))
+        }
+        Request::Query => Ok(Response::ok(Some(crate::power::is_sleep_disabled()))),
+        Request::RestorePowerSettings { args } => {
+            crate::power::snapshot::check_restore_args(&args)?;
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            pmset(&args)?;
+            Ok(Response::ok(None))
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn pmset(args: &[&str]) -> Result<()> {
+    let output = std::process::Command::new("/usr/bin/pmset")
+        .args(args)
+        .output()
+        .context("Failed to run pmset")?;
+    if !output.status.success() {
+        bail!(
+            "pmset {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    fn exchange(allowed_uids: &[u32], line: &str) -> (Response, Vec<Request>) {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        writeln!(client, "{}", line).unwrap();
+        let mut seen = Vec::new();
+        handle_connection(server, allowed_uids, &mut |request| {
+            seen.push(request);
+            Ok(Response::ok(Some(true)))
+        })
+        .unwrap();
+        let mut answer = String::new();
+        BufReader::new(client).read_line(&mut answer).unwrap();
+        (serde_json::from_str(answer.trim()).unwrap(), seen)
+    }
+
+    fn own_uid() -> u32 {
+        unsafe { libc::getuid() }
+    }
+
+    #[test]
+    fn protocol_uses_fixed_messages() {
+        assert_eq!(
+            serde_json::to_string(&Request::SetDisablesleep { enabled: true }).unwrap(),
+            r#"{"op":"set-disablesleep","enabled":true}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"op":"sleepnow"}"#).unwrap(),
+            Request::Sleepnow
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(
+                r#"{"op":"restore-power-settings","args":["-c","sleep","30"]}"#
+            )
+            .unwrap(),
+            Request::RestorePowerSettings {
+                args: vec!["-c".into(), "sleep".into(), "30".into()]
+            }
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"op":"pmset","args":["-a"]}"#).is_err());
+        assert!(serde_json::from_str::<Request>(
+            r#"{"op":"set-disablesleep","enabled":true,"args":"-a sleep 0"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn serves_allowed_peer() {
+        let (response, seen) = exchange(&[own_uid()], r#"{"op":"query"}"#);
+
+        assert_eq!(response, Response::ok(Some(true)));
+        assert_eq!(seen, vec![Request::Query]);
+    }
+
+    #[test]
+    fn rejects_unknown_peer_and_bad_requests() {
+        if own_uid() != 0 {
+            let (response, seen) = exchange(&[], r#"{"op":"sleepnow"}"#);
+            assert!(!response.ok);
+            assert!(seen.is_empty());
+        }
+
+        let (response, seen) = exchange(&[own_uid()], r#"{"op":"rm","path":"/"}"#);
+        assert!(!response.ok);
+        assert!(response.error.unwrap().starts_with("invalid request"));
+        assert!(seen.is_empty());
+    }
+
+    #[test]
+    fn client_round_trip() {
+        let socket = std::env::temp_dir().join(format!("asp-helper-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &[own_uid()], &mut |_| Ok(Response::ok(Some(false))))
+                .unwrap();
+        });
+
+        let response = request_at(&socket, Request::SetDisablesleep { enabled: false }).unwrap();
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&socket);
+
+        assert_eq!(response.sleep_disabled, Some(false));
+    }
+}
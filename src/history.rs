@@ -0,0 +1,368 @@
+//! What the machine was kept awake for, after the session is gone
+//!
+//! Every stretch of time a session kept the machine awake becomes one line
+//! in `history.jsonl`, next to `settings.json`, once it ends: the session
+//! went idle, hit the safety cap, was paused for heat or battery, or went
+//! away. The file is only ever appended to. `asp history` lists the entries
+//! and `asp stats` adds them up per project and per day.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::detection::AgentKind;
+use crate::registry::JsonState;
+use crate::sessions::SessionRecord;
+
+/// Why a session stopped keeping the machine awake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    /// A hook said the agent finished its turn or is waiting on the user
+    Stop,
+    /// `SessionEnd` hook
+    SessionEnd,
+    /// Parked as idle after it stopped sending hooks and doing work
+    IdleReap,
+    /// The agent or `asp run`/`asp watch` process is gone
+    Exited,
+    /// Safety cap
+    Cap,
+    /// Paused while the machine is too hot
+    Thermal,
+    /// A battery or lid rule let the machine sleep
+    Policy,
+    /// Sleep prevention switched off from the menu bar
+    Disabled,
+    /// `asp reset`
+    Reset,
+}
+
+impl EndReason {
+    pub fn label(self) -> &'static str {
+        match self {
+            EndReason::Stop => "stop",
+            EndReason::SessionEnd => "session_end",
+            EndReason::IdleReap => "idle_reap",
+            EndReason::Exited => "exited",
+            EndReason::Cap => "cap",
+            EndReason::Thermal => "thermal",
+            EndReason::Policy => "policy",
+            EndReason::Disabled => "disabled",
+            EndReason::Reset => "reset",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub pid: u32,
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub agent: Option<AgentKind>,
+    #[serde(default)]
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub git_branch: Option<String>,
+    /// Command line for `asp run`/`asp watch` sessions
+    #[serde(default)]
+    pub command: Option<String>,
+    pub started_at: u64,
+    pub ended_at: u64,
+    /// Part of the stretch the lid was closed for
+    #[serde(default)]
+    pub lid_closed_secs: u64,
+    pub end_reason: EndReason,
+}
+
+impl HistoryEntry {
+    pub fn duration_secs(&self) -> u64 {
+        self.ended_at.saturating_sub(self.started_at)
+    }
+
+    /// Project directory, or "unknown".
+    pub fn project(&self) -> &str {
+        self.cwd.as_deref().unwrap_or("unknown")
+    }
+
+    /// Whether `filter` names this entry's project, by directory name or
+    /// by path.
+    pub fn matches_project(&self, filter: &str) -> bool {
+        let project = self.project();
+        let filter = filter.trim_end_matches('/');
+        project == filter
+            || Path::new(project)
+                .file_name()
+                .is_some_and(|name| name == filter)
+    }
+}
+
+/// `history.jsonl` next to `settings.json`.
+pub fn path() -> PathBuf {
+    if let Some(sandbox) = crate::registry::sandbox() {
+        return sandbox.join("history.jsonl");
+    }
+    crate::settings::AppSettings::settings_path().with_file_name("history.jsonl")
+}
+
+/// Add one entry as a single line, which concurrent writers can't interleave.
+pub fn append(path: &Path, entry: &HistoryEntry) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Every readable entry, oldest first.
+pub fn load(path: &Path) -> Vec<HistoryEntry> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// When the lid was closed recently, so a stretch can tell how much of it
+/// ran with the lid shut. Persisted next to the session records.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LidLog {
+    #[serde(default)]
+    pub closed_since: Option<u64>,
+    /// Finished (closed, opened) intervals, oldest first
+    #[serde(default)]
+    pub closed: Vec<(u64, u64)>,
+}
+
+/// Closed intervals older than this can't overlap a stretch still open.
+const LID_LOG_KEEP_SECS: u64 = 7 * 86_400;
+
+impl JsonState for LidLog {}
+
+impl LidLog {
+    /// Note whether the lid is closed now. Returns whether anything changed.
+    pub fn track(&mut self, lid_closed: bool, now: u64) -> bool {
+        match (self.closed_since, lid_closed) {
+            (None, true) => self.closed_since = Some(now),
+            (Some(since), false) => {
+                self.closed_since = None;
+                self.closed.push((since, now));
+                self.closed
+                    .retain(|(_, opened)| opened + LID_LOG_KEEP_SECS > now);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Seconds between `start` and `end` the lid was closed.
+    pub fn closed_secs(&self, start: u64, end: u64) -> u64 {
+        self.closed
+            .iter()
+            .copied()
+            .chain(self.closed_since.map(|since| (since, end)))
+            .map(|(closed, opened)| opened.min(end).saturating_sub(closed.max(start)))
+            .sum()
+    }
+}
+
+/// Close the stretch `record` has been keeping the machine awake for, if it
+/// has one open, and describe it for the history.
+pub fn finish(
+    record: &mut SessionRecord,
+    reason: EndReason,
+    now: u64,
+    lid: &LidLog,
+) -> Option<HistoryEntry> {
+    let started_at = record.awake_since.take()?;
+    Some(HistoryEntry {
+        pid: record.pid,
+        session_id: record.session_id.clone(),
+        agent: record.agent.clone(),
+        cwd: record.cwd.clone(),
+        git_branch: record.git_branch.clone(),
+        command: record.command.clone(),
+        started_at,
+        ended_at: now,
+        lid_closed_secs: lid.closed_secs(started_at, now),
+        end_reason: reason,
+    })
+}
+
+/// Awake time per project, and per local day
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    pub projects: Vec<ProjectStats>,
+    pub days: Vec<DayStats>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ProjectStats {
+    pub project: String,
+    pub stretches: usize,
+    pub awake_secs: u64,
+    pub lid_closed_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DayStats {
+    /// `YYYY-MM-DD` in local time, by when the stretch started
+    pub date: String,
+    pub awake_secs: u64,
+    pub lid_closed_secs: u64,
+}
+
+impl Stats {
+    /// Projects sorted by awake time, most first; days oldest first.
+    pub fn from_entries<'a>(entries: impl IntoIterator<Item = &'a HistoryEntry>) -> Self {
+        let mut projects = BTreeMap::<&str, ProjectStats>::new();
+        let mut days = BTreeMap::<String, DayStats>::new();
+        for entry in entries {
+            let project = projects.entry(entry.project()).or_default();
+            project.stretches += 1;
+            project.awake_secs += entry.duration_secs();
+            project.lid_closed_secs += entry.lid_closed_secs;
+
+            let date = local_date(entry.started_at);
+            let day = days.entry(date.clone()).or_insert_with(|| DayStats {
+                date,
+                ..DayStats::default()
+            });
+            day.awake_secs += entry.duration_secs();
+            day.lid_closed_secs += entry.lid_closed_secs;
+        }
+
+        let mut projects = projects
+            .into_iter()
+            .map(|(name, stats)| ProjectStats {
+                project: name.to_string(),
+                ..stats
+            })
+            .collect::<Vec<_>>();
+        projects.sort_by_key(|project| std::cmp::Reverse(project.awake_secs));
+        Self {
+            projects,
+            days: days.into_values().collect(),
+        }
+    }
+}
+
+fn local_tm(secs: u64) -> libc::tm {
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::localtime_r(&time, &mut tm);
+    }
+    tm
+}
+
+/// e.g. "2026-10-16"
+pub fn local_date(secs: u64) -> String {
+    let tm = local_tm(secs);
+    format!(
+        "{:04}-{:02}-{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday
+    )
+}
+
+/// e.g. "2026-10-16 14:05"
+pub fn local_datetime(secs: u64) -> String {
+    let tm = local_tm(secs);
+    format!("{} {:02}:{:02}", local_date(secs), tm.tm_hour, tm.tm_min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(cwd: &str, started_at: u64, ended_at: u64, lid_closed_secs: u64) -> HistoryEntry {
+        HistoryEntry {
+            pid: 1,
+            session_id: None,
+            agent: Some(AgentKind::new("claude")),
+            cwd: Some(cwd.to_string()),
+            git_branch: None,
+            command: None,
+            started_at,
+            ended_at,
+            lid_closed_secs,
+            end_reason: EndReason::Stop,
+        }
+    }
+
+    #[test]
+    fn finish_closes_the_open_stretch_once() {
+        let mut lid = LidLog::default();
+        assert!(lid.track(true, 1_100));
+        assert!(!lid.track(true, 1_150));
+        assert!(lid.track(false, 1_200));
+        assert!(lid.track(true, 1_500));
+
+        let mut record = SessionRecord::new(42, "test", 1_000);
+        record.cwd = Some("/src/asp".to_string());
+        record.awake_since = Some(1_000);
+
+        let finished = finish(&mut record, EndReason::Cap, 1_600, &lid).unwrap();
+
+        assert_eq!(finished.duration_secs(), 600);
+        assert_eq!(finished.lid_closed_secs, 100 + 100);
+        assert_eq!(finished.end_reason, EndReason::Cap);
+        assert_eq!(record.awake_since, None);
+        assert_eq!(finish(&mut record, EndReason::Stop, 1_700, &lid), None);
+    }
+
+    #[test]
+    fn appends_and_loads_lines() {
+        let path = std::env::temp_dir().join(format!("asp-history-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        append(&path, &entry("/src/asp", 0, 60, 0)).unwrap();
+        append(&path, &entry("/src/web", 60, 120, 30)).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "not json").unwrap();
+
+        let loaded = load(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].lid_closed_secs, 30);
+        assert!(loaded[0].matches_project("asp"));
+        assert!(loaded[0].matches_project("/src/asp/"));
+        assert!(!loaded[0].matches_project("web"));
+    }
+
+    #[test]
+    fn stats_add_up_per_project_and_day() {
+        let day = 86_400 * 20_000 + 12 * 3_600;
+        let entries = vec![
+            entry("/src/asp", day, day + 600, 0),
+            entry("/src/web", day + 700, day + 1_900, 300),
+            entry("/src/asp", day + 86_400, day + 86_400 + 60, 0),
+        ];
+
+        let stats = Stats::from_entries(&entries);
+
+        let projects = stats
+            .projects
+            .iter()
+            .map(|p| (p.project.as_str(), p.stretches, p.awake_secs))
+            .collect::<Vec<_>>();
+        assert_eq!(projects, vec![("/src/web", 1, 1_200), ("/src/asp", 2, 660)]);
+        assert_eq!(stats.days.len(), 2);
+        assert_eq!(stats.days[0].awake_secs, 1_800);
+        assert_eq!(stats.days[0].lid_closed_secs, 300);
+    }
+}
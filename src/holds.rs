@@ -0,0 +1,222 @@
+//! Timed manual holds: "stay awake for the next 90 minutes"
+//!
+//! A hold keeps the machine awake whether or not any agent is working. Each
+//! one is a `hold-<id>.json` file next to the session records, so every
+//! `asp` process sees the same holds, and expires on its own.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hold {
+    pub id: u32,
+    #[serde(default)]
+    pub reason: Option<String>,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl Hold {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+
+    pub fn remaining_secs(&self, now: u64) -> u64 {
+        self.expires_at.saturating_sub(now)
+    }
+
+    /// e.g. "#2 overnight eval (1h 20m left)"
+    pub fn describe(&self, now: u64) -> String {
+        let remaining = format_duration(self.remaining_secs(now));
+        match &self.reason {
+            Some(reason) => format!("#{} {} ({} left)", self.id, reason, remaining),
+            None => format!("#{} ({} left)", self.id, remaining),
+        }
+    }
+}
+
+pub fn hold_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("hold-{}.json", id))
+}
+
+/// Every hold in `dir`, expired or not, sorted by id.
+pub fn load_holds(dir: &Path) -> Vec<(PathBuf, Hold)> {
+    let mut holds = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("hold-"))
+        })
+        .filter_map(|path| {
+            let hold = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+            Some((path, hold))
+        })
+        .collect::<Vec<(PathBuf, Hold)>>();
+    holds.sort_by_key(|(_, hold)| hold.id);
+    holds
+}
+
+/// Create a hold lasting `secs`, numbered with the lowest free id. The file
+/// is created exclusively, so two `asp hold` runs at once never share an id.
+pub fn add_hold(dir: &Path, secs: u64, reason: Option<String>, now: u64) -> Result<Hold> {
+    let expires_at = now
+        .checked_add(secs)
+        .with_context(|| format!("Hold of {}s is too long", secs))?;
+    let taken = load_holds(dir)
+        .into_iter()
+        .map(|(_, hold)| hold.id)
+        .collect::<Vec<_>>();
+    let mut candidates = (1..).filter(|id| !taken.contains(id));
+    loop {
+        let id = candidates.next().context("No free hold id")?;
+        let path = hold_path(dir, id);
+        let mut file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {}", path.display()))
+            }
+        };
+        let hold = Hold {
+            id,
+            reason,
+            created_at: now,
+            expires_at,
+        };
+        file.write_all(serde_json::to_string(&hold)?.as_bytes())
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        return Ok(hold);
+    }
+}
+
+/// Parse durations like `90m`, `2h`, `1h30m` or `45s`. A bare number is
+/// minutes.
+pub fn parse_duration(input: &str) -> Result<u64> {
+    let input = input.trim();
+    let too_long = || anyhow::anyhow!("Duration {:?} is too long", input);
+    if let Ok(minutes) = input.parse::<u64>() {
+        return minutes.checked_mul(60).ok_or_else(too_long);
+    }
+
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for ch in input.chars() {
+        if ch.is_ascii_digit() {
+            number.push(ch);
+            continue;
+        }
+        let unit = match ch {
+            'd' => 86_400,
+            'h' => 3_600,
+            'm' => 60,
+            's' => 1,
+            _ => anyhow::bail!("Unknown unit '{}' in duration {:?}", ch, input),
+        };
+        let value: u64 = number
+            .parse()
+            .with_context(|| format!("Invalid duration {:?}", input))?;
+        total = value
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(too_long)?;
+        number.clear();
+    }
+    if !number.is_empty() || total == 0 {
+        anyhow::bail!("Invalid duration {:?} (try 90m, 2h or 1h30m)", input);
+    }
+    Ok(total)
+}
+
+/// e.g. "1h 20m", "45m", "30s"
+pub fn format_duration(secs: u64) -> String {
+    let (hours, minutes) = (secs / 3_600, secs % 3_600 / 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", secs),
+        (0, minutes) => format!("{}m", minutes),
+        (hours, 0) => format!("{}h", hours),
+        (hours, minutes) => format!("{}h {}m", hours, minutes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90").unwrap(), 90 * 60);
+        assert_eq!(parse_duration("2h").unwrap(), 7_200);
+        assert_eq!(parse_duration("1h30m").unwrap(), 5_400);
+        assert_eq!(parse_duration("45s").unwrap(), 45);
+        assert!(parse_duration("2 hours").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration(&u64::MAX.to_string()).is_err());
+        assert!(parse_duration("99999999999999999999d").is_err());
+        assert!(parse_duration("300000000000000d").is_err());
+        assert!(parse_duration(&format!("{}s1s", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn holds_take_lowest_free_id() {
+        let dir = std::env::temp_dir().join(format!("asp-holds-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let first = add_hold(&dir, 60, Some("overnight eval".to_string()), 0).unwrap();
+        let second = add_hold(&dir, 60, None, 0).unwrap();
+        fs::remove_file(hold_path(&dir, first.id)).unwrap();
+        let third = add_hold(&dir, 5_400, None, 0).unwrap();
+
+        assert_eq!((first.id, second.id, third.id), (1, 2, 1));
+        assert_eq!(load_holds(&dir).len(), 2);
+        assert_eq!(third.describe(600), "#1 (1h 20m left)");
+        assert!(second.is_expired(60));
+        assert!(add_hold(&dir, u64::MAX, None, 1).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn concurrent_holds_get_distinct_ids() {
+        let dir = std::env::temp_dir().join(format!("asp-holds-race-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let threads = (0..8)
+            .map(|_| {
+                let dir = dir.clone();
+                std::thread::spawn(move || add_hold(&dir, 60, None, 0).unwrap().id)
+            })
+            .collect::<Vec<_>>();
+        let mut ids = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        assert_eq!(ids, (1..=8).collect::<Vec<_>>());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn hold_files_are_not_sessions() {
+        let dir = std::env::temp_dir().join(format!("asp-holds-sessions-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        add_hold(&dir, 60, None, 0).unwrap();
+
+        assert!(crate::sessions::load_records(&dir).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,471 @@
+//! Hook payloads piped to `asp` by coding agents, and the per-session state
+//! machine they drive
+//!
+//! Claude Code and Codex write a JSON object to the hook command's stdin
+//! describing the session and the event that fired. Every field is optional
+//! so older agents, manual runs and unknown agents still work.
+
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+use crate::registry::JsonState;
+use crate::sessions::{OpenTool, SessionRecord, SessionState};
+
+/// Upper bound on how much stdin we'll buffer; tool payloads can embed whole files.
+const MAX_PAYLOAD_BYTES: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HookPayload {
+    pub session_id: Option<String>,
+    pub cwd: Option<String>,
+    pub hook_event_name: Option<String>,
+    pub transcript_path: Option<String>,
+    /// Tool hooks: pairs a `PreToolUse` with its `PostToolUse`
+    pub tool_use_id: Option<String>,
+    pub tool_name: Option<String>,
+    /// Claude `Notification` hooks: what the agent is asking the user for
+    pub notification_type: Option<String>,
+    pub message: Option<String>,
+}
+
+impl HookPayload {
+    /// Session id, if the agent sent a usable one.
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id
+            .as_deref()
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+    }
+
+    /// Key used to match a tool call's pre and post hooks.
+    fn tool_key(&self) -> String {
+        self.tool_use_id
+            .as_deref()
+            .or(self.tool_name.as_deref())
+            .unwrap_or("tool")
+            .to_string()
+    }
+}
+
+/// Hook events `asp hook` understands. Anything else is kept as `Other` and
+/// only refreshes the session heartbeat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookEvent {
+    SessionStart,
+    UserPromptSubmit,
+    PreToolUse,
+    PostToolUse,
+    PreCompact,
+    Notification,
+    PermissionRequest,
+    SubagentStop,
+    Stop,
+    SessionEnd,
+    Other(String),
+}
+
+impl HookEvent {
+    pub fn from_name(name: &str) -> Self {
+        const KNOWN: [(&str, HookEvent); 10] = [
+            ("SessionStart", HookEvent::SessionStart),
+            ("UserPromptSubmit", HookEvent::UserPromptSubmit),
+            ("PreToolUse", HookEvent::PreToolUse),
+            ("PostToolUse", HookEvent::PostToolUse),
+            ("PreCompact", HookEvent::PreCompact),
+            ("Notification", HookEvent::Notification),
+            ("PermissionRequest", HookEvent::PermissionRequest),
+            ("SubagentStop", HookEvent::SubagentStop),
+            ("Stop", HookEvent::Stop),
+            ("SessionEnd", HookEvent::SessionEnd),
+        ];
+        let name = name.trim();
+        KNOWN
+            .into_iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .map(|(_, event)| event)
+            .unwrap_or_else(|| HookEvent::Other(name.to_string()))
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            HookEvent::SessionStart => "SessionStart",
+            HookEvent::UserPromptSubmit => "UserPromptSubmit",
+            HookEvent::PreToolUse => "PreToolUse",
+            HookEvent::PostToolUse => "PostToolUse",
+            HookEvent::PreCompact => "PreCompact",
+            HookEvent::Notification => "Notification",
+            HookEvent::PermissionRequest => "PermissionRequest",
+            HookEvent::SubagentStop => "SubagentStop",
+            HookEvent::Stop => "Stop",
+            HookEvent::SessionEnd => "SessionEnd",
+            HookEvent::Other(name) => name,
+        }
+    }
+}
+
+fn is_permission_notification(payload: &HookPayload) -> bool {
+    if let Some(kind) = payload.notification_type.as_deref() {
+        return kind == "permission_prompt";
+    }
+    payload
+        .message
+        .as_deref()
+        .map(|message| message.to_lowercase().contains("permission"))
+        .unwrap_or(false)
+}
+
+fn is_idle_notification(payload: &HookPayload) -> bool {
+    payload.notification_type.as_deref() == Some("idle_prompt")
+}
+
+/// State a session moves to when `event` fires while it is in `current`.
+/// An expired session only comes back with a new prompt or `asp extend`,
+/// or leaves when its turn or session ends; nothing else from the turn that
+/// hit the cap, permission prompts included, revives it.
+pub fn next_state(current: SessionState, event: &HookEvent, payload: &HookPayload) -> SessionState {
+    if current == SessionState::Expired
+        && !matches!(
+            event,
+            HookEvent::UserPromptSubmit
+                | HookEvent::SessionStart
+                | HookEvent::Stop
+                | HookEvent::SessionEnd
+        )
+    {
+        return current;
+    }
+    match event {
+        HookEvent::SessionStart | HookEvent::Stop => SessionState::Idle,
+        HookEvent::UserPromptSubmit | HookEvent::PostToolUse => SessionState::Working,
+        HookEvent::PreToolUse => SessionState::RunningTool,
+        HookEvent::PreCompact => SessionState::Compacting,
+        HookEvent::PermissionRequest => SessionState::WaitingForPermission,
+        HookEvent::Notification if is_permission_notification(payload) => {
+            SessionState::WaitingForPermission
+        }
+        HookEvent::Notification if is_idle_notification(payload) => SessionState::Idle,
+        HookEvent::SessionEnd => SessionState::Ended,
+        HookEvent::Notification | HookEvent::SubagentStop | HookEvent::Other(_) => current,
+    }
+}
+
+/// Apply one hook event to a session: track open tool calls and advance the
+/// state machine. A `PostToolUse` only returns the session to `Working` once
+/// every tool it started has finished.
+pub fn apply_event(record: &mut SessionRecord, event: &HookEvent, payload: &HookPayload, now: u64) {
+    match event {
+        HookEvent::PreToolUse => record.open_tools.push(OpenTool {
+            id: payload.tool_key(),
+            started_at: now,
+        }),
+        HookEvent::PostToolUse => {
+            let key = payload.tool_key();
+            let idx = record
+                .open_tools
+                .iter()
+                .position(|tool| tool.id == key)
+                .or_else(|| record.open_tools.len().checked_sub(1));
+            if let Some(idx) = idx {
+                record.open_tools.remove(idx);
+            }
+        }
+        // A new turn, the end of a turn or the end of the session means no
+        // tool from an earlier turn can still be running.
+        HookEvent::UserPromptSubmit | HookEvent::Stop | HookEvent::SessionEnd => {
+            record.open_tools.clear()
+        }
+        _ => {}
+    }
+
+    let mut state = next_state(record.state, event, payload);
+    if state == SessionState::Working && !record.open_tools.is_empty() {
+        state = SessionState::RunningTool;
+    }
+    record.last_heartbeat = now;
+    record.set_state(state, now);
+}
+
+/// How long a sleep sync stays good enough for hooks that change nothing.
+/// The resident process syncs every second; without one this bounds how
+/// stale thermal and policy checks get between tool calls.
+pub const SYNC_FRESH_SECS: u64 = 15;
+
+/// When sleep was last synced with the sessions, so hooks that don't
+/// change whether a session keeps the machine awake can skip the sync.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncStamp {
+    pub synced_at: u64,
+}
+
+impl JsonState for SyncStamp {}
+
+impl SyncStamp {
+    pub fn is_fresh(&self, now: u64) -> bool {
+        self.synced_at <= now && now - self.synced_at < SYNC_FRESH_SECS
+    }
+}
+
+/// Whether a hook can leave the sleep state alone: it moved a session that
+/// was already tracked (`previous`) to a state that keeps the machine awake
+/// exactly when the old one did, and sleep was synced recently.
+pub fn can_skip_sync(
+    previous: Option<SessionState>,
+    state: SessionState,
+    stamp: &SyncStamp,
+    now: u64,
+) -> bool {
+    previous.is_some_and(|previous| previous.prevents_sleep() == state.prevents_sleep())
+        && stamp.is_fresh(now)
+}
+
+pub fn parse_payload(input: &str) -> HookPayload {
+    serde_json::from_str(input).unwrap_or_default()
+}
+
+/// Read the payload from stdin. Returns an empty payload when stdin is a
+/// terminal (someone ran `asp start` by hand) or isn't JSON.
+pub fn read_payload() -> HookPayload {
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+        return HookPayload::default();
+    }
+
+    let mut input = String::new();
+    if std::io::stdin()
+        .take(MAX_PAYLOAD_BYTES)
+        .read_to_string(&mut input)
+        .is_err()
+    {
+        return HookPayload::default();
+    }
+    parse_payload(&input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_claude_payload() {
+        let payload = parse_payload(
+            r#"{
+                "session_id": "abc123",
+                "transcript_path": "/Users/me/.claude/projects/x/abc123.jsonl",
+                "cwd": "/Users/me/src/asp",
+                "hook_event_name": "PreToolUse",
+                "tool_name": "Bash",
+                "tool_input": { "command": "cargo test" }
+            }"#,
+        );
+
+        assert_eq!(payload.session_id(), Some("abc123"));
+        assert_eq!(payload.cwd.as_deref(), Some("/Users/me/src/asp"));
+        assert_eq!(payload.hook_event_name.as_deref(), Some("PreToolUse"));
+        assert_eq!(
+            payload.transcript_path.as_deref(),
+            Some("/Users/me/.claude/projects/x/abc123.jsonl")
+        );
+    }
+
+    #[test]
+    fn missing_or_invalid_payload_is_empty() {
+        assert_eq!(parse_payload(""), HookPayload::default());
+        assert_eq!(parse_payload("not json"), HookPayload::default());
+        assert_eq!(parse_payload("{}"), HookPayload::default());
+    }
+
+    #[test]
+    fn event_names_are_case_insensitive() {
+        assert_eq!(HookEvent::from_name("PreToolUse"), HookEvent::PreToolUse);
+        assert_eq!(HookEvent::from_name("pretooluse"), HookEvent::PreToolUse);
+        assert_eq!(
+            HookEvent::from_name("BeforeModel"),
+            HookEvent::Other("BeforeModel".to_string())
+        );
+    }
+
+    #[test]
+    fn tool_and_compaction_events_keep_session_busy() {
+        let payload = HookPayload::default();
+        let mut state = SessionState::Idle;
+        for (event, expected) in [
+            (HookEvent::UserPromptSubmit, SessionState::Working),
+            (HookEvent::PreToolUse, SessionState::RunningTool),
+            (HookEvent::PostToolUse, SessionState::Working),
+            (HookEvent::PreCompact, SessionState::Compacting),
+            (HookEvent::Stop, SessionState::Idle),
+            (HookEvent::SessionEnd, SessionState::Ended),
+        ] {
+            state = next_state(state, &event, &payload);
+            assert_eq!(state, expected, "after {}", event.name());
+        }
+    }
+
+    #[test]
+    fn permission_prompt_waits_for_user() {
+        let payload = parse_payload(
+            r#"{"notification_type": "permission_prompt", "message": "Claude needs your permission to use Bash"}"#,
+        );
+
+        let state = next_state(
+            SessionState::RunningTool,
+            &HookEvent::Notification,
+            &payload,
+        );
+
+        assert_eq!(state, SessionState::WaitingForPermission);
+        assert!(!state.prevents_sleep());
+    }
+
+    #[test]
+    fn unrelated_notifications_keep_state() {
+        let payload = parse_payload(r#"{"message": "Task finished"}"#);
+        for event in [
+            HookEvent::Notification,
+            HookEvent::SubagentStop,
+            HookEvent::Other("Custom".to_string()),
+        ] {
+            assert_eq!(
+                next_state(SessionState::Compacting, &event, &payload),
+                SessionState::Compacting
+            );
+        }
+    }
+
+    #[test]
+    fn expired_session_waits_for_next_prompt() {
+        let payload = HookPayload::default();
+        for event in [
+            HookEvent::PreToolUse,
+            HookEvent::PostToolUse,
+            HookEvent::PreCompact,
+            HookEvent::Notification,
+            HookEvent::PermissionRequest,
+            HookEvent::SubagentStop,
+        ] {
+            assert_eq!(
+                next_state(SessionState::Expired, &event, &payload),
+                SessionState::Expired
+            );
+        }
+        let permission_prompt = parse_payload(r#"{"notification_type": "permission_prompt"}"#);
+        assert_eq!(
+            next_state(
+                SessionState::Expired,
+                &HookEvent::Notification,
+                &permission_prompt
+            ),
+            SessionState::Expired
+        );
+
+        // A permission prompt and the tool call after it stay expired.
+        let mut record = SessionRecord::new(1, "test", 0);
+        record.set_state(SessionState::Expired, 0);
+        apply_event(&mut record, &HookEvent::PermissionRequest, &payload, 1);
+        apply_event(&mut record, &HookEvent::Notification, &permission_prompt, 2);
+        apply_event(&mut record, &HookEvent::PostToolUse, &tool_payload("a"), 3);
+        assert_eq!(record.state, SessionState::Expired);
+        assert_eq!(
+            next_state(SessionState::Expired, &HookEvent::Stop, &payload),
+            SessionState::Idle
+        );
+        assert_eq!(
+            next_state(
+                SessionState::Expired,
+                &HookEvent::UserPromptSubmit,
+                &payload
+            ),
+            SessionState::Working
+        );
+    }
+
+    fn tool_payload(id: &str) -> HookPayload {
+        parse_payload(&format!(
+            r#"{{"tool_name": "Bash", "tool_use_id": "{}"}}"#,
+            id
+        ))
+    }
+
+    #[test]
+    fn parallel_tools_keep_session_running_until_all_finish() {
+        let mut record = SessionRecord::new(1, "UserPromptSubmit", 0);
+
+        apply_event(&mut record, &HookEvent::PreToolUse, &tool_payload("a"), 1);
+        apply_event(&mut record, &HookEvent::PreToolUse, &tool_payload("b"), 2);
+        apply_event(&mut record, &HookEvent::PostToolUse, &tool_payload("a"), 3);
+
+        assert_eq!(record.state, SessionState::RunningTool);
+        assert_eq!(record.oldest_open_tool(), Some(2));
+
+        apply_event(&mut record, &HookEvent::PostToolUse, &tool_payload("b"), 4);
+
+        assert_eq!(record.state, SessionState::Working);
+        assert!(record.open_tools.is_empty());
+        assert_eq!(record.last_heartbeat, 4);
+    }
+
+    #[test]
+    fn post_tool_without_ids_closes_latest_tool() {
+        let mut record = SessionRecord::new(1, "UserPromptSubmit", 0);
+        let payload = HookPayload::default();
+
+        apply_event(&mut record, &HookEvent::PreToolUse, &payload, 1);
+        apply_event(&mut record, &HookEvent::PostToolUse, &payload, 2);
+
+        assert!(record.open_tools.is_empty());
+        assert_eq!(record.state, SessionState::Working);
+    }
+
+    #[test]
+    fn stop_clears_interrupted_tools() {
+        let mut record = SessionRecord::new(1, "UserPromptSubmit", 0);
+
+        apply_event(&mut record, &HookEvent::PreToolUse, &tool_payload("a"), 1);
+        apply_event(&mut record, &HookEvent::Stop, &HookPayload::default(), 2);
+
+        assert!(record.open_tools.is_empty());
+        assert_eq!(record.state, SessionState::Idle);
+    }
+
+    #[test]
+    fn skips_sync_only_for_known_sessions_that_stay_busy_or_quiet() {
+        let stamp = SyncStamp { synced_at: 100 };
+
+        assert!(can_skip_sync(
+            Some(SessionState::Working),
+            SessionState::RunningTool,
+            &stamp,
+            105
+        ));
+        assert!(can_skip_sync(
+            Some(SessionState::Idle),
+            SessionState::WaitingForPermission,
+            &stamp,
+            105
+        ));
+        assert!(!can_skip_sync(
+            Some(SessionState::RunningTool),
+            SessionState::Idle,
+            &stamp,
+            105
+        ));
+        assert!(!can_skip_sync(None, SessionState::Working, &stamp, 105));
+        assert!(!can_skip_sync(
+            Some(SessionState::Working),
+            SessionState::Working,
+            &stamp,
+            100 + SYNC_FRESH_SECS
+        ));
+        assert!(!can_skip_sync(
+            Some(SessionState::Working),
+            SessionState::Working,
+            &SyncStamp::default(),
+            105
+        ));
+    }
+
+    #[test]
+    fn blank_session_id_is_ignored() {
+        let payload = parse_payload(r#"{"session_id": "  "}"#);
+        assert_eq!(payload.session_id(), None);
+    }
+}
@@ -0,0 +1,1031 @@
+//! Hook configuration for each coding agent `asp install` knows about
+//!
+//! Every agent keeps its hooks in a config file of its own. An
+//! `AgentIntegration` knows which one, and how to add or remove our hooks
+//! there without touching anyone else's. Each hook runs `asp hook <event>`
+//! with one of the event names `asp hook` understands, whatever the agent
+//! itself calls the event.
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub trait AgentIntegration {
+    /// e.g. "Claude Code"
+    fn name(&self) -> &'static str;
+    /// Short name used in `--<agent>-dir`, e.g. `claude`
+    fn agent(&self) -> &'static str;
+    /// Environment variable that moves the agent's config directory
+    fn config_env_var(&self) -> Option<&'static str> {
+        None
+    }
+    /// Config directory when nothing moves it, e.g. `~/.claude`
+    fn default_config_dir(&self, home: &Path) -> PathBuf;
+    /// Whether the agent uses `dir`: it exists.
+    fn detect(&self, dir: &Path) -> bool {
+        dir.is_dir()
+    }
+    fn install(&self, dir: &Path, app_binary: &str) -> Result<()>;
+    /// Remove our hooks. Returns whether there were any.
+    fn uninstall(&self, dir: &Path) -> Result<bool>;
+    fn is_installed(&self, dir: &Path) -> bool;
+    /// Check that every hook we install is there and runs `app_binary`.
+    fn verify(&self, dir: &Path, app_binary: &str) -> Result<()>;
+}
+
+pub fn all() -> Vec<&'static dyn AgentIntegration> {
+    vec![&ClaudeCode, &Codex, &GeminiCli]
+}
+
+/// An integration and one of its config directories
+pub type Target = (&'static dyn AgentIntegration, PathBuf);
+
+/// Where each agent keeps its config
+pub struct ConfigDirs<'a> {
+    pub home: &'a Path,
+    /// Directories given with `--<agent>-dir`, as (agent, dir)
+    pub overrides: Vec<(&'static str, PathBuf)>,
+    /// Values an environment variable has for the user. There can be
+    /// several: the shell's and the launchd session's.
+    pub env: &'a dyn Fn(&str) -> Vec<String>,
+}
+
+impl ConfigDirs<'_> {
+    /// Config directories of `integration`: the `--<agent>-dir` flags if
+    /// any were given, else wherever its environment variable points, else
+    /// the default.
+    pub fn dirs(&self, integration: &dyn AgentIntegration) -> Vec<PathBuf> {
+        let overridden = self.overridden_dirs(integration);
+        let dirs = if !overridden.is_empty() {
+            overridden
+        } else {
+            self.env_dirs(integration)
+        };
+        if dirs.is_empty() {
+            return vec![integration.default_config_dir(self.home)];
+        }
+        unique(dirs)
+    }
+
+    /// Every directory `integration` might have hooks in: the flags, the
+    /// environment variable and the default together. Used on uninstall, so
+    /// hooks installed before a variable changed are removed too.
+    pub fn all_dirs(&self, integration: &dyn AgentIntegration) -> Vec<PathBuf> {
+        let mut dirs = self.overridden_dirs(integration);
+        dirs.extend(self.env_dirs(integration));
+        dirs.push(integration.default_config_dir(self.home));
+        unique(dirs)
+    }
+
+    fn overridden_dirs(&self, integration: &dyn AgentIntegration) -> Vec<PathBuf> {
+        self.overrides
+            .iter()
+            .filter(|(agent, _)| *agent == integration.agent())
+            .map(|(_, dir)| dir.clone())
+            .collect()
+    }
+
+    fn env_dirs(&self, integration: &dyn AgentIntegration) -> Vec<PathBuf> {
+        integration
+            .config_env_var()
+            .map(|var| (self.env)(var))
+            .unwrap_or_default()
+            .iter()
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .map(|value| match value.strip_prefix("~/") {
+                Some(rest) => self.home.join(rest),
+                None => PathBuf::from(value),
+            })
+            .collect()
+    }
+
+    /// Every integration with each of its config directories.
+    pub fn targets(&self) -> Vec<Target> {
+        all()
+            .into_iter()
+            .flat_map(|integration| {
+                self.dirs(integration)
+                    .into_iter()
+                    .map(move |dir| (integration, dir))
+            })
+            .collect()
+    }
+
+    /// Every integration with each directory it might have hooks in.
+    pub fn all_targets(&self) -> Vec<Target> {
+        all()
+            .into_iter()
+            .flat_map(|integration| {
+                self.all_dirs(integration)
+                    .into_iter()
+                    .map(move |dir| (integration, dir))
+            })
+            .collect()
+    }
+
+    /// Targets `asp install` sets up: every agent found on this machine or
+    /// named with `--<agent>-dir`, or Claude Code and Codex when there are
+    /// none yet.
+    pub fn wanted(&self) -> Vec<Target> {
+        let (found, missing): (Vec<_>, Vec<_>) =
+            self.targets().into_iter().partition(|(integration, dir)| {
+                integration.detect(dir)
+                    || self
+                        .overrides
+                        .iter()
+                        .any(|(agent, _)| *agent == integration.agent())
+            });
+        if !found.is_empty() {
+            return found;
+        }
+        missing
+            .into_iter()
+            .filter(|(integration, _)| integration.agent() != GeminiCli.agent())
+            .collect()
+    }
+}
+
+fn unique(dirs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut unique = Vec::new();
+    for dir in dirs {
+        if !unique.contains(&dir) {
+            unique.push(dir);
+        }
+    }
+    unique
+}
+
+const OWNED_HOOK_MARKERS: [&str; 7] = [
+    "AgentsSleepPreventer.app/Contents/MacOS/asp",
+    "/.local/bin/asp",
+    "/usr/local/bin/asp",
+    "/usr/local/bin/agents-sleep-preventer",
+    "claude-sleep-preventer",
+    "/hooks/prevent-sleep.sh",
+    "/hooks/allow-sleep.sh",
+];
+
+fn toml_section_name(line: &str) -> Option<&str> {
+    let code = line.split('#').next().unwrap_or("").trim();
+    if !code.starts_with('[') || !code.ends_with(']') {
+        return None;
+    }
+    Some(code.trim_matches(&['[', ']'][..]).trim())
+}
+
+fn set_toml_feature_true(content: &str, feature: &str) -> String {
+    let mut lines = content.lines().map(ToOwned::to_owned).collect::<Vec<_>>();
+    let mut features_start = None;
+    let mut features_end = lines.len();
+
+    for (idx, line) in lines.iter().enumerate() {
+        let Some(section) = toml_section_name(line) else {
+            continue;
+        };
+        if section == "features" {
+            features_start = Some(idx);
+            features_end = lines.len();
+        } else if features_start.is_some() {
+            features_end = idx;
+            break;
+        }
+    }
+
+    if let Some(start) = features_start {
+        for line in lines.iter_mut().take(features_end).skip(start + 1) {
+            let code = line.split('#').next().unwrap_or("").trim_start();
+            if let Some(rest) = code.strip_prefix(feature) {
+                if rest.trim_start().starts_with('=') {
+                    let indent = line
+                        .chars()
+                        .take_while(|ch| ch.is_whitespace())
+                        .collect::<String>();
+                    *line = format!("{}{} = true", indent, feature);
+                    return format!("{}\n", lines.join("\n"));
+                }
+            }
+        }
+        let mut insert_at = features_end;
+        while insert_at > start + 1
+            && lines
+                .get(insert_at - 1)
+                .map(|line| line.trim().is_empty())
+                .unwrap_or(false)
+        {
+            insert_at -= 1;
+        }
+        lines.insert(insert_at, format!("{} = true", feature));
+    } else {
+        if !lines.is_empty()
+            && lines
+                .last()
+                .map(|line| !line.trim().is_empty())
+                .unwrap_or(false)
+        {
+            lines.push(String::new());
+        }
+        lines.push("[features]".to_string());
+        lines.push(format!("{} = true", feature));
+    }
+
+    format!("{}\n", lines.join("\n"))
+}
+
+fn remove_toml_feature(content: &str, feature: &str) -> String {
+    let mut changed = false;
+    let mut in_features = false;
+    let mut lines = Vec::new();
+
+    for line in content.lines() {
+        if let Some(section) = toml_section_name(line) {
+            in_features = section == "features";
+        }
+
+        if in_features {
+            let code = line.split('#').next().unwrap_or("").trim_start();
+            if let Some(rest) = code.strip_prefix(feature) {
+                if rest.trim_start().starts_with('=') {
+                    changed = true;
+                    continue;
+                }
+            }
+        }
+
+        lines.push(line.to_string());
+    }
+
+    if changed {
+        format!("{}\n", lines.join("\n"))
+    } else {
+        content.to_string()
+    }
+}
+
+fn set_codex_hooks_feature(content: &str) -> String {
+    let without_legacy = remove_toml_feature(content, "codex_hooks");
+    set_toml_feature_true(&without_legacy, "hooks")
+}
+
+fn enable_codex_hooks_feature(config_file: &Path) -> Result<()> {
+    let content = fs::read_to_string(config_file).unwrap_or_default();
+    let updated = set_codex_hooks_feature(&content);
+    if updated != content {
+        fs::write(config_file, updated)
+            .with_context(|| format!("Failed to write {}", config_file.display()))?;
+    }
+    Ok(())
+}
+
+fn hook_value_contains_owned_command(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::String(text) => OWNED_HOOK_MARKERS
+            .iter()
+            .any(|marker| text.contains(marker)),
+        serde_json::Value::Array(values) => values.iter().any(hook_value_contains_owned_command),
+        serde_json::Value::Object(map) => map.values().any(hook_value_contains_owned_command),
+        _ => false,
+    }
+}
+
+fn remove_owned_hooks_from_group(group: &mut serde_json::Value) -> bool {
+    let Some(hooks) = group
+        .get_mut("hooks")
+        .and_then(serde_json::Value::as_array_mut)
+    else {
+        return false;
+    };
+
+    let before = hooks.len();
+    hooks.retain(|hook| !hook_value_contains_owned_command(hook));
+    before != hooks.len()
+}
+
+fn remove_owned_hook_groups(hooks: &mut serde_json::Value) -> bool {
+    let Some(events) = hooks.as_object_mut() else {
+        return false;
+    };
+
+    let mut changed = false;
+    for groups in events.values_mut() {
+        let Some(groups) = groups.as_array_mut() else {
+            continue;
+        };
+
+        for group in groups.iter_mut() {
+            if remove_owned_hooks_from_group(group) {
+                changed = true;
+            }
+        }
+
+        let before = groups.len();
+        groups.retain(|group| {
+            group
+                .get("hooks")
+                .and_then(serde_json::Value::as_array)
+                .map(|hooks| !hooks.is_empty())
+                .unwrap_or(true)
+        });
+        changed |= before != groups.len();
+    }
+
+    changed
+}
+
+fn prune_empty_hook_events(hooks: &mut serde_json::Value) {
+    if let Some(events) = hooks.as_object_mut() {
+        events.retain(|_, groups| {
+            groups
+                .as_array()
+                .map(|groups| !groups.is_empty())
+                .unwrap_or(true)
+        });
+    }
+}
+
+fn command_hook_group(command: &str, matcher: Option<&str>) -> serde_json::Value {
+    let mut group = json!({
+        "hooks": [
+            {
+                "type": "command",
+                "command": command,
+                "timeout": 5
+            }
+        ]
+    });
+    if let Some(matcher) = matcher {
+        group["matcher"] = json!(matcher);
+    }
+    group
+}
+
+fn append_hook_group(
+    hooks: &mut serde_json::Map<String, serde_json::Value>,
+    event_name: &str,
+    group: serde_json::Value,
+) {
+    let event = hooks
+        .entry(event_name.to_string())
+        .or_insert_with(|| json!([]));
+    if !event.is_array() {
+        *event = json!([]);
+    }
+    if let Some(groups) = event.as_array_mut() {
+        groups.push(group);
+    }
+}
+
+/// Replace our hook groups in a settings/hooks document, keeping every
+/// hook we don't own. Re-running with the same groups is a no-op.
+fn merge_owned_hook_groups(
+    root: &mut serde_json::Value,
+    groups: Vec<(&str, serde_json::Value)>,
+) -> Result<()> {
+    if !root.is_object() {
+        *root = json!({});
+    }
+    if !root
+        .get("hooks")
+        .map(serde_json::Value::is_object)
+        .unwrap_or(false)
+    {
+        root["hooks"] = json!({});
+    }
+
+    if let Some(hooks) = root.get_mut("hooks") {
+        remove_owned_hook_groups(hooks);
+        prune_empty_hook_events(hooks);
+    }
+
+    let hooks = root
+        .get_mut("hooks")
+        .and_then(serde_json::Value::as_object_mut)
+        .context("Failed to prepare hooks object")?;
+    for (event_name, group) in groups {
+        append_hook_group(hooks, event_name, group);
+    }
+
+    Ok(())
+}
+
+/// Strip our hooks from a settings/hooks document, dropping the `hooks` key
+/// only when nothing else is left in it. Returns whether anything changed.
+fn remove_owned_hooks(root: &mut serde_json::Value) -> bool {
+    let changed = root
+        .get_mut("hooks")
+        .map(remove_owned_hook_groups)
+        .unwrap_or(false);
+    if !changed {
+        return false;
+    }
+
+    if let Some(hooks) = root.get_mut("hooks") {
+        prune_empty_hook_events(hooks);
+    }
+
+    if let Some(root) = root.as_object_mut() {
+        let hooks_empty = root
+            .get("hooks")
+            .and_then(serde_json::Value::as_object)
+            .map(|hooks| hooks.is_empty())
+            .unwrap_or(false);
+        if hooks_empty {
+            root.remove("hooks");
+        }
+    }
+
+    true
+}
+
+/// Shell command a hook runs: forwards the event to `asp hook`, and never
+/// fails the agent's turn if the app has been removed.
+fn hook_command(app_binary: &str, event: &str) -> String {
+    format!("[ -x \"{app_binary}\" ] && \"{app_binary}\" hook {event} 2>/dev/null || true")
+}
+
+const CLAUDE_HOOK_EVENTS: [&str; 7] = [
+    "UserPromptSubmit",
+    "PreToolUse",
+    "PostToolUse",
+    "PreCompact",
+    "Notification",
+    "Stop",
+    "SessionEnd",
+];
+
+const CODEX_HOOK_EVENTS: [&str; 4] = ["UserPromptSubmit", "PreToolUse", "PostToolUse", "Stop"];
+
+fn claude_hook_groups(app_binary: &str) -> Vec<(&'static str, serde_json::Value)> {
+    CLAUDE_HOOK_EVENTS
+        .iter()
+        .map(|event| {
+            (
+                *event,
+                command_hook_group(&hook_command(app_binary, event), None),
+            )
+        })
+        .collect()
+}
+
+fn codex_hook_groups(app_binary: &str) -> Vec<(&'static str, serde_json::Value)> {
+    CODEX_HOOK_EVENTS
+        .iter()
+        .map(|event| {
+            let matcher = matches!(*event, "PreToolUse" | "PostToolUse").then_some("*");
+            (
+                *event,
+                command_hook_group(&hook_command(app_binary, event), matcher),
+            )
+        })
+        .collect()
+}
+
+/// Gemini CLI event names and the `asp hook` event each one stands for
+const GEMINI_HOOK_EVENTS: [(&str, &str); 6] = [
+    ("BeforeAgent", "UserPromptSubmit"),
+    ("BeforeTool", "PreToolUse"),
+    ("AfterTool", "PostToolUse"),
+    ("PreCompress", "PreCompact"),
+    ("AfterAgent", "Stop"),
+    ("SessionEnd", "SessionEnd"),
+];
+
+fn gemini_hook_groups(app_binary: &str) -> Vec<(&'static str, serde_json::Value)> {
+    GEMINI_HOOK_EVENTS
+        .iter()
+        .map(|(event, asp_event)| {
+            let mut group = command_hook_group(&hook_command(app_binary, asp_event), None);
+            // Gemini CLI counts hook timeouts in milliseconds.
+            group["hooks"][0]["timeout"] = json!(5_000);
+            (*event, group)
+        })
+        .collect()
+}
+
+fn read_json(path: &Path) -> Result<serde_json::Value> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Merge `groups` into the `hooks` of the JSON file at `path`, creating it
+/// if needed.
+fn install_hooks_file(path: &Path, groups: Vec<(&str, serde_json::Value)>) -> Result<()> {
+    let existed = path.exists();
+    let mut json = if existed {
+        read_json(path)?
+    } else {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        json!({})
+    };
+
+    merge_owned_hook_groups(&mut json, groups)?;
+
+    fs::write(path, serde_json::to_string_pretty(&json)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    if existed {
+        println!("  Updated {}", path.display());
+    } else {
+        println!("  Created {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Strip our hooks from the JSON file at `path`. With `remove_if_empty`, a
+/// file left with nothing in it is deleted.
+fn remove_hooks_file(path: &Path, remove_if_empty: bool) -> Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+
+    let Ok(mut json) = read_json(path) else {
+        eprintln!(
+            "Warning: could not parse {}, leaving it unchanged",
+            path.display()
+        );
+        return Ok(false);
+    };
+
+    if !remove_owned_hooks(&mut json) {
+        return Ok(false);
+    }
+
+    let root_empty = json
+        .as_object()
+        .map(|root| root.is_empty())
+        .unwrap_or(false);
+    if remove_if_empty && root_empty {
+        fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+        return Ok(true);
+    }
+
+    fs::write(path, serde_json::to_string_pretty(&json)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    Ok(true)
+}
+
+fn hooks_file_has_owned_hooks(path: &Path) -> bool {
+    read_json(path)
+        .ok()
+        .and_then(|json| json.get("hooks").map(hook_value_contains_owned_command))
+        .unwrap_or(false)
+}
+
+/// Check that every group in `groups` is registered under its event.
+fn verify_hooks(json: &serde_json::Value, groups: Vec<(&str, serde_json::Value)>) -> Result<()> {
+    for (event, group) in groups {
+        let installed = json["hooks"][event]
+            .as_array()
+            .is_some_and(|installed| installed.contains(&group));
+        if !installed {
+            anyhow::bail!("{} hook missing or outdated", event);
+        }
+    }
+    Ok(())
+}
+
+/// Claude Code: hooks in `settings.json` under `~/.claude` or
+/// `$CLAUDE_CONFIG_DIR`
+pub struct ClaudeCode;
+
+impl ClaudeCode {
+    fn settings_file(dir: &Path) -> PathBuf {
+        dir.join("settings.json")
+    }
+}
+
+impl AgentIntegration for ClaudeCode {
+    fn name(&self) -> &'static str {
+        "Claude Code"
+    }
+
+    fn agent(&self) -> &'static str {
+        "claude"
+    }
+
+    fn config_env_var(&self) -> Option<&'static str> {
+        Some("CLAUDE_CONFIG_DIR")
+    }
+
+    fn default_config_dir(&self, home: &Path) -> PathBuf {
+        home.join(".claude")
+    }
+
+    fn install(&self, dir: &Path, app_binary: &str) -> Result<()> {
+        let settings_file = Self::settings_file(dir);
+        install_hooks_file(&settings_file, claude_hook_groups(app_binary))?;
+
+        #[cfg(unix)]
+        crate::fix_user_ownership(&settings_file);
+
+        Ok(())
+    }
+
+    fn uninstall(&self, dir: &Path) -> Result<bool> {
+        remove_hooks_file(&Self::settings_file(dir), false)
+    }
+
+    fn is_installed(&self, dir: &Path) -> bool {
+        hooks_file_has_owned_hooks(&Self::settings_file(dir))
+    }
+
+    fn verify(&self, dir: &Path, app_binary: &str) -> Result<()> {
+        let settings_file = Self::settings_file(dir);
+        verify_hooks(&read_json(&settings_file)?, claude_hook_groups(app_binary))
+            .with_context(|| format!("in {}", settings_file.display()))
+    }
+}
+
+/// Codex: hooks in `hooks.json` under `~/.codex` or `$CODEX_HOME`, switched
+/// on in `config.toml`
+pub struct Codex;
+
+impl Codex {
+    fn hooks_file(dir: &Path) -> PathBuf {
+        dir.join("hooks.json")
+    }
+
+    fn config_file(dir: &Path) -> PathBuf {
+        dir.join("config.toml")
+    }
+}
+
+impl AgentIntegration for Codex {
+    fn name(&self) -> &'static str {
+        "Codex"
+    }
+
+    fn agent(&self) -> &'static str {
+        "codex"
+    }
+
+    fn config_env_var(&self) -> Option<&'static str> {
+        Some("CODEX_HOME")
+    }
+
+    fn default_config_dir(&self, home: &Path) -> PathBuf {
+        home.join(".codex")
+    }
+
+    fn install(&self, dir: &Path, app_binary: &str) -> Result<()> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+        let config_file = Self::config_file(dir);
+        enable_codex_hooks_feature(&config_file)?;
+        println!("  Updated {}", config_file.display());
+
+        install_hooks_file(&Self::hooks_file(dir), codex_hook_groups(app_binary))?;
+
+        #[cfg(unix)]
+        crate::fix_user_ownership(dir);
+
+        Ok(())
+    }
+
+    fn uninstall(&self, dir: &Path) -> Result<bool> {
+        remove_hooks_file(&Self::hooks_file(dir), true)
+    }
+
+    fn is_installed(&self, dir: &Path) -> bool {
+        hooks_file_has_owned_hooks(&Self::hooks_file(dir))
+    }
+
+    fn verify(&self, dir: &Path, app_binary: &str) -> Result<()> {
+        let config_file = Self::config_file(dir);
+        let config = fs::read_to_string(&config_file).unwrap_or_default();
+        if set_codex_hooks_feature(&config) != config {
+            anyhow::bail!("hooks feature not enabled in {}", config_file.display());
+        }
+        let hooks_file = Self::hooks_file(dir);
+        verify_hooks(&read_json(&hooks_file)?, codex_hook_groups(app_binary))
+            .with_context(|| format!("in {}", hooks_file.display()))
+    }
+}
+
+/// Gemini CLI: hooks in `~/.gemini/settings.json`
+pub struct GeminiCli;
+
+impl GeminiCli {
+    fn settings_file(dir: &Path) -> PathBuf {
+        dir.join("settings.json")
+    }
+}
+
+impl AgentIntegration for GeminiCli {
+    fn name(&self) -> &'static str {
+        "Gemini CLI"
+    }
+
+    fn agent(&self) -> &'static str {
+        "gemini"
+    }
+
+    fn default_config_dir(&self, home: &Path) -> PathBuf {
+        home.join(".gemini")
+    }
+
+    fn install(&self, dir: &Path, app_binary: &str) -> Result<()> {
+        let settings_file = Self::settings_file(dir);
+        install_hooks_file(&settings_file, gemini_hook_groups(app_binary))?;
+
+        #[cfg(unix)]
+        crate::fix_user_ownership(&settings_file);
+
+        Ok(())
+    }
+
+    fn uninstall(&self, dir: &Path) -> Result<bool> {
+        remove_hooks_file(&Self::settings_file(dir), false)
+    }
+
+    fn is_installed(&self, dir: &Path) -> bool {
+        hooks_file_has_owned_hooks(&Self::settings_file(dir))
+    }
+
+    fn verify(&self, dir: &Path, app_binary: &str) -> Result<()> {
+        let settings_file = Self::settings_file(dir);
+        verify_hooks(&read_json(&settings_file)?, gemini_hook_groups(app_binary))
+            .with_context(|| format!("in {}", settings_file.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_BINARY_PATH: &str = crate::APP_BINARY_PATH;
+
+    #[test]
+    fn set_codex_hooks_feature_adds_current_flag() {
+        let updated = set_codex_hooks_feature("model = \"gpt-5.5\"\n");
+
+        assert_eq!(updated, "model = \"gpt-5.5\"\n\n[features]\nhooks = true\n");
+    }
+
+    #[test]
+    fn set_codex_hooks_feature_replaces_deprecated_flag() {
+        let config = "\
+model = \"gpt-5.5\"
+
+[features]
+unified_exec = true
+codex_hooks = true
+
+[plugins.github]
+enabled = true
+";
+
+        let updated = set_codex_hooks_feature(config);
+
+        assert!(!updated.contains("codex_hooks"));
+        assert!(updated.contains("[features]\nunified_exec = true\nhooks = true"));
+        assert!(updated.contains("[plugins.github]\nenabled = true"));
+    }
+
+    #[test]
+    fn set_codex_hooks_feature_updates_existing_hooks_flag() {
+        let config = "\
+[features]
+hooks = false
+";
+
+        let updated = set_codex_hooks_feature(config);
+
+        assert_eq!(updated, "[features]\nhooks = true\n");
+    }
+
+    fn claude_settings_with_foreign_hooks() -> serde_json::Value {
+        json!({
+            "model": "opus",
+            "hooks": {
+                "PostToolUse": [
+                    {
+                        "matcher": "Edit|Write",
+                        "hooks": [{ "type": "command", "command": "prettier --write" }]
+                    }
+                ],
+                "Stop": [
+                    { "hooks": [{ "type": "command", "command": "notify-send done" }] }
+                ]
+            }
+        })
+    }
+
+    #[test]
+    fn merge_claude_hooks_keeps_foreign_hooks() {
+        let mut settings = claude_settings_with_foreign_hooks();
+
+        merge_owned_hook_groups(&mut settings, claude_hook_groups(APP_BINARY_PATH)).unwrap();
+
+        assert_eq!(settings["model"], "opus");
+        let post_tool = settings["hooks"]["PostToolUse"].as_array().unwrap();
+        assert_eq!(post_tool.len(), 2);
+        assert_eq!(post_tool[0]["hooks"][0]["command"], "prettier --write");
+        let stop = settings["hooks"]["Stop"].as_array().unwrap();
+        assert_eq!(stop.len(), 2);
+        assert_eq!(stop[0]["hooks"][0]["command"], "notify-send done");
+        assert_eq!(
+            stop[1]["hooks"][0]["command"],
+            hook_command(APP_BINARY_PATH, "Stop")
+        );
+        assert_eq!(settings["hooks"]["PreCompact"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn merge_claude_hooks_is_idempotent() {
+        let mut once = claude_settings_with_foreign_hooks();
+        let groups = || claude_hook_groups(APP_BINARY_PATH);
+        merge_owned_hook_groups(&mut once, groups()).unwrap();
+        let mut twice = once.clone();
+        merge_owned_hook_groups(&mut twice, groups()).unwrap();
+
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn remove_owned_hooks_leaves_foreign_hooks() {
+        let mut settings = claude_settings_with_foreign_hooks();
+        merge_owned_hook_groups(&mut settings, claude_hook_groups(APP_BINARY_PATH)).unwrap();
+
+        assert!(remove_owned_hooks(&mut settings));
+        assert_eq!(settings, claude_settings_with_foreign_hooks());
+        assert!(!remove_owned_hooks(&mut settings));
+    }
+
+    #[test]
+    fn merge_replaces_legacy_wrapper_scripts() {
+        let mut settings = json!({
+            "hooks": {
+                "PreToolUse": [
+                    { "hooks": [{ "type": "command", "command": "/Users/me/.claude/hooks/prevent-sleep.sh" }] }
+                ]
+            }
+        });
+
+        merge_owned_hook_groups(&mut settings, claude_hook_groups(APP_BINARY_PATH)).unwrap();
+
+        let pre_tool = settings["hooks"]["PreToolUse"].as_array().unwrap();
+        assert_eq!(pre_tool.len(), 1);
+        assert_eq!(
+            pre_tool[0]["hooks"][0]["command"],
+            hook_command(APP_BINARY_PATH, "PreToolUse")
+        );
+    }
+
+    #[test]
+    fn codex_tool_hooks_match_every_tool() {
+        let groups = codex_hook_groups(APP_BINARY_PATH);
+
+        for (event, group) in groups {
+            let expected = matches!(event, "PreToolUse" | "PostToolUse");
+            assert_eq!(group.get("matcher").is_some(), expected, "{}", event);
+        }
+    }
+
+    #[test]
+    fn remove_owned_hooks_drops_empty_hooks_key() {
+        let mut settings = json!({ "model": "opus" });
+        merge_owned_hook_groups(&mut settings, claude_hook_groups(APP_BINARY_PATH)).unwrap();
+
+        assert!(remove_owned_hooks(&mut settings));
+        assert_eq!(settings, json!({ "model": "opus" }));
+    }
+
+    fn temp_home(name: &str) -> PathBuf {
+        let home = std::env::temp_dir().join(format!("asp-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&home);
+        fs::create_dir_all(&home).unwrap();
+        home
+    }
+
+    #[test]
+    fn gemini_hooks_use_asp_event_names() {
+        let groups = gemini_hook_groups(APP_BINARY_PATH);
+
+        let (event, group) = &groups[1];
+        assert_eq!(*event, "BeforeTool");
+        assert_eq!(
+            group["hooks"][0]["command"],
+            hook_command(APP_BINARY_PATH, "PreToolUse")
+        );
+        assert_eq!(group["hooks"][0]["timeout"], 5_000);
+    }
+
+    fn no_env(_: &str) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[test]
+    fn integrations_install_verify_and_uninstall() {
+        let home = temp_home("integrations");
+        fs::create_dir_all(home.join(".gemini")).unwrap();
+        fs::write(
+            home.join(".gemini/settings.json"),
+            r#"{"theme": "Dracula"}"#,
+        )
+        .unwrap();
+
+        for integration in all() {
+            let dir = integration.default_config_dir(&home);
+            assert!(!integration.is_installed(&dir), "{}", integration.name());
+            integration.install(&dir, APP_BINARY_PATH).unwrap();
+            assert!(integration.is_installed(&dir), "{}", integration.name());
+            integration.verify(&dir, APP_BINARY_PATH).unwrap();
+            assert!(integration.verify(&dir, "/opt/asp").is_err());
+            assert!(integration.uninstall(&dir).unwrap());
+            assert!(!integration.is_installed(&dir), "{}", integration.name());
+        }
+
+        assert_eq!(
+            read_json(&home.join(".gemini/settings.json")).unwrap(),
+            json!({"theme": "Dracula"})
+        );
+        assert!(!home.join(".codex/hooks.json").exists());
+        let _ = fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn wanted_follows_detected_agents() {
+        let home = temp_home("wanted");
+        let dirs = ConfigDirs {
+            home: &home,
+            overrides: Vec::new(),
+            env: &no_env,
+        };
+        let names = || {
+            dirs.wanted()
+                .iter()
+                .map(|(integration, _)| integration.name())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(), vec!["Claude Code", "Codex"]);
+        fs::create_dir_all(home.join(".gemini")).unwrap();
+        assert_eq!(names(), vec!["Gemini CLI"]);
+        fs::create_dir_all(home.join(".claude")).unwrap();
+        assert_eq!(names(), vec!["Claude Code", "Gemini CLI"]);
+        let _ = fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn config_dirs_follow_flags_then_env() {
+        let home = PathBuf::from("/home/me");
+        let env = |var: &str| match var {
+            "CLAUDE_CONFIG_DIR" => vec!["~/work/.claude".to_string(), "/srv/claude".to_string()],
+            "CODEX_HOME" => vec!["/srv/codex".to_string(), "/srv/codex".to_string()],
+            _ => Vec::new(),
+        };
+        let mut dirs = ConfigDirs {
+            home: &home,
+            overrides: Vec::new(),
+            env: &env,
+        };
+
+        assert_eq!(
+            dirs.dirs(&ClaudeCode),
+            vec![
+                PathBuf::from("/home/me/work/.claude"),
+                PathBuf::from("/srv/claude")
+            ]
+        );
+        assert_eq!(dirs.dirs(&Codex), vec![PathBuf::from("/srv/codex")]);
+        assert_eq!(dirs.dirs(&GeminiCli), vec![home.join(".gemini")]);
+
+        dirs.overrides = vec![
+            ("claude", PathBuf::from("/a")),
+            ("claude", PathBuf::from("/b")),
+        ];
+        assert_eq!(
+            dirs.dirs(&ClaudeCode),
+            vec![PathBuf::from("/a"), PathBuf::from("/b")]
+        );
+        assert_eq!(dirs.dirs(&Codex), vec![PathBuf::from("/srv/codex")]);
+        let wanted = dirs
+            .wanted()
+            .into_iter()
+            .map(|(_, dir)| dir)
+            .collect::<Vec<_>>();
+        assert_eq!(wanted, vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+        assert_eq!(
+            dirs.all_dirs(&ClaudeCode),
+            vec![
+                PathBuf::from("/a"),
+                PathBuf::from("/b"),
+                PathBuf::from("/home/me/work/.claude"),
+                PathBuf::from("/srv/claude"),
+                home.join(".claude")
+            ]
+        );
+        assert_eq!(
+            dirs.all_dirs(&Codex),
+            vec![PathBuf::from("/srv/codex"), home.join(".codex")]
+        );
+    }
+}
@@ -7,6 +7,8 @@ use std::time::SystemTime;
 static LOG_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
 
 /// Initialize logging to ~/Library/Logs/AgentsSleepPreventer/asp.log
+/// (~/.local/state/AgentsSleepPreventer/asp.log on Linux)
+#[cfg(target_os = "macos")]
 pub fn init() {
     init_with_startup_message(true);
 }
@@ -16,10 +18,29 @@ pub fn init_quiet() {
     init_with_startup_message(false);
 }
 
-fn init_with_startup_message(write_startup: bool) {
-    let log_dir = dirs::home_dir()
+#[cfg(target_os = "macos")]
+pub fn log_dir() -> PathBuf {
+    if let Some(sandbox) = crate::registry::sandbox() {
+        return sandbox.join("logs");
+    }
+    dirs::home_dir()
         .unwrap_or_else(|| PathBuf::from("/tmp"))
-        .join("Library/Logs/AgentsSleepPreventer");
+        .join("Library/Logs/AgentsSleepPreventer")
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn log_dir() -> PathBuf {
+    if let Some(sandbox) = crate::registry::sandbox() {
+        return sandbox.join("logs");
+    }
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join("AgentsSleepPreventer")
+}
+
+fn init_with_startup_message(write_startup: bool) {
+    let log_dir = log_dir();
 
     if fs::create_dir_all(&log_dir).is_ok() {
         let log_path = log_dir.join("asp.log");
@@ -1,67 +1,141 @@
+mod audit;
+#[cfg(target_os = "macos")]
 mod authorization;
+mod bench;
+mod cap;
+mod control;
+mod detection;
+#[cfg(target_os = "macos")]
 mod dictation;
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+mod helper;
+mod history;
+mod holds;
+mod hooks;
+mod integrations;
 mod logging;
+#[cfg(target_os = "macos")]
 mod native_dialogs;
+#[cfg(target_os = "macos")]
 mod objc_utils;
+mod policy;
+#[cfg(target_os = "macos")]
 mod popover;
+mod power;
+mod process_table;
+mod registry;
+mod sessions;
 mod settings;
+mod thermal;
 
 use anyhow::{Context, Result};
+use cap::CapState;
 use clap::{Parser, Subcommand};
-use core_foundation::base::{kCFAllocatorDefault, TCFType};
-use core_foundation::boolean::CFBoolean;
-use core_foundation::runloop::{
-    kCFRunLoopDefaultMode, CFRunLoopAddSource, CFRunLoopGetCurrent, CFRunLoopRun,
-};
-use core_foundation::string::CFString;
-use dictation::{run_onboarding_if_needed, DictationManager};
-use global_hotkey::{
-    hotkey::{Code, HotKey, Modifiers},
-    GlobalHotKeyEvent, GlobalHotKeyManager,
-};
-use io_kit_sys::types::*;
-use io_kit_sys::*;
-use mach2::port::MACH_PORT_NULL;
-use objc::{class, msg_send, sel, sel_impl};
+use detection::{AgentKind, AgentRules};
+use history::{EndReason, LidLog};
+use hooks::{HookEvent, HookPayload};
+use policy::{PolicyDecision, PolicyInput, PolicyState};
+use power::lease::Lease;
+#[cfg(target_os = "macos")]
+use power::snapshot::PowerSnapshot;
+use process_table::{CpuBaseline, CpuSampler, ProcessInfo, ProcessTable, Snapshot};
+use registry::JsonState;
 use serde_json::json;
-use std::collections::{HashMap, HashSet};
+use sessions::{SessionRecord, SessionState};
+use std::collections::HashSet;
 use std::fs;
-use std::io::Write;
-use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::ptr;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
 use sysinfo::System;
+use thermal::ThermalMonitor;
+
+#[cfg(target_os = "macos")]
+use macos_imports::*;
+
+/// Menu bar, dictation and IOKit dependencies, which only exist on macOS.
+#[cfg(target_os = "macos")]
+mod macos_imports {
+    pub use core_foundation::base::{kCFAllocatorDefault, TCFType};
+    pub use core_foundation::boolean::CFBoolean;
+    pub use core_foundation::runloop::{
+        kCFRunLoopDefaultMode, CFRunLoopAddSource, CFRunLoopGetCurrent, CFRunLoopRun,
+    };
+    pub use core_foundation::string::CFString;
+    pub use global_hotkey::{
+        hotkey::{Code, HotKey, Modifiers},
+        GlobalHotKeyEvent, GlobalHotKeyManager,
+    };
+    pub use io_kit_sys::types::*;
+    pub use io_kit_sys::*;
+    pub use mach2::port::MACH_PORT_NULL;
+    pub use objc::{class, msg_send, sel, sel_impl};
+    pub use std::io::Write;
+    pub use std::os::unix::io::AsRawFd;
+    pub use std::ptr;
+    pub use std::sync::atomic::AtomicUsize;
+    pub use tao::event::{Event, StartCause};
+    pub use tao::event_loop::{ControlFlow, EventLoopBuilder};
+    pub use tao::platform::macos::{ActivationPolicy, EventLoopExtMacOS};
+    pub use tray_icon::{
+        menu::{Menu, MenuEvent, MenuItem},
+        MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent,
+    };
+
+    pub use crate::dictation::{run_onboarding_if_needed, DictationManager};
 
-#[link(name = "IOKit", kind = "framework")]
-extern "C" {}
-use tao::event::{Event, StartCause};
-use tao::event_loop::{ControlFlow, EventLoopBuilder};
-use tao::platform::macos::{ActivationPolicy, EventLoopExtMacOS};
-use tray_icon::{
-    menu::{Menu, MenuEvent, MenuItem},
-    MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent,
-};
+    #[link(name = "IOKit", kind = "framework")]
+    extern "C" {}
+}
 
+#[cfg(target_os = "macos")]
 static LID_JUST_CLOSED: AtomicBool = AtomicBool::new(false);
+#[cfg(target_os = "macos")]
 static LID_WAS_CLOSED: AtomicBool = AtomicBool::new(false);
+#[cfg(target_os = "macos")]
 static CURRENT_PID_INDEX: AtomicUsize = AtomicUsize::new(0);
+#[cfg(target_os = "macos")]
 static CURRENT_INACTIVE_INDEX: AtomicUsize = AtomicUsize::new(0);
+#[cfg(target_os = "macos")]
 static MANUAL_SLEEP_PREVENTION: AtomicBool = AtomicBool::new(true);
-
-const PIDS_DIR: &str = "/tmp/agents_working_pids";
-const LEGACY_PIDS_DIR: &str = "/tmp/claude_working_pids";
+static CPU_SAMPLER: Mutex<CpuSampler> = Mutex::new(CpuSampler::new());
+static POWER_BACKEND: Mutex<Option<Box<dyn power::PowerBackend>>> = Mutex::new(None);
+/// Set by long-running commands, which are the only ones that can hold a
+/// process-scoped power backend.
+static RESIDENT_PROCESS: AtomicBool = AtomicBool::new(false);
+/// Control socket served by this process, when it is the resident one
+static CONTROL_SERVER: OnceLock<Arc<control::Server>> = OnceLock::new();
+/// Child of `asp run` that signals are forwarded to
+static FORWARD_SIGNALS_TO: AtomicI32 = AtomicI32::new(0);
+/// Set when `asp watch` is asked to stop
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Policy timers, next to the session records so `asp reset` clears them too
+const POLICY_STATE_FILE: &str = "policy.json";
+const THERMAL_STATE_FILE: &str = "thermal.json";
+const CAP_STATE_FILE: &str = "cap.json";
+const LEASE_FILE: &str = "lease.json";
+/// The last decision in the audit log, so repeats aren't written again
+const AUDIT_STATE_FILE: &str = "audit.json";
+/// When the lid was closed lately, for the lid time in the history
+const LID_LOG_FILE: &str = "lid.json";
+/// When sleep was last synced, so hooks that change nothing can skip it
+const SYNC_STAMP_FILE: &str = "sync.json";
+/// The resident process's last CPU sample, for one-shot commands to diff against
+const CPU_BASELINE_FILE: &str = "cpu.json";
+#[cfg(target_os = "macos")]
+const WATCHDOG_LAUNCH_AGENT_LABEL: &str = "com.charlontank.agents-sleep-preventer.watchdog";
 const IDLE_TIMEOUT_SECS: u64 = 30;
 const IDLE_CPU_THRESHOLD: f32 = 0.5;
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
 const APP_BINARY_PATH: &str = "/Applications/AgentsSleepPreventer.app/Contents/MacOS/asp";
-const OWNED_HOOK_MARKERS: [&str; 4] = [
-    "AgentsSleepPreventer.app/Contents/MacOS/asp",
-    "/usr/local/bin/asp",
-    "/usr/local/bin/agents-sleep-preventer",
-    "claude-sleep-preventer",
-];
+/// Where `asp install` puts the CLI on Linux, relative to the user's home
+#[cfg(target_os = "linux")]
+const LINUX_BINARY_PATH: &str = ".local/bin/asp";
+#[cfg(target_os = "linux")]
+const SYSTEMD_UNIT_NAME: &str = "agents-sleep-preventer.service";
 
 #[derive(Parser)]
 #[command(name = "asp")]
@@ -78,11 +152,80 @@ enum Commands {
     Start,
     /// Unregister current agent process and re-enable sleep if no others
     Stop,
+    /// Handle a coding agent hook event (payload is read from stdin)
+    Hook {
+        /// Event name, e.g. PreToolUse; defaults to the payload's hook_event_name
+        event: Option<String>,
+    },
+    /// Keep the machine awake while a command runs
+    Run {
+        /// Command and its arguments, after `--`
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Keep the machine awake until an already running process exits
+    Watch {
+        #[arg(long)]
+        pid: u32,
+    },
+    /// Keep the machine awake for a while, whether or not agents are working
+    #[command(args_conflicts_with_subcommands = true)]
+    Hold {
+        #[command(subcommand)]
+        action: Option<HoldAction>,
+        /// How long, e.g. 90m, 2h or 1h30m
+        #[arg(long = "for")]
+        duration: Option<String>,
+        /// Shown in `asp hold list` and the menu bar
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// End a hold started with `asp hold`
+    Release { id: u32 },
+    /// List past stretches of time the machine was kept awake
+    History {
+        /// Only this project, by directory name or path
+        #[arg(long)]
+        project: Option<String>,
+        /// Only the last stretch of time, e.g. 7d or 12h
+        #[arg(long)]
+        since: Option<String>,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Total agent time per project and per day
+    Stats {
+        /// Only the last stretch of time, e.g. 7d or 12h
+        #[arg(long)]
+        since: Option<String>,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Time `asp hook` calls from a simulated agent session and report p50/p99
+    BenchHook {
+        /// Number of tool hooks to time, after the one that registers the session
+        #[arg(short = 'n', long, default_value = "200")]
+        iterations: usize,
+    },
+    /// Explain why sleep is disabled or allowed right now
+    Why {
+        #[arg(long)]
+        json: bool,
+    },
     /// Show current status
-    Status,
+    Status {
+        #[arg(long)]
+        json: bool,
+    },
     /// List active/inactive instances as JSON
-    List,
+    List {
+        /// Print the list again whenever sessions, holds or the sleep state
+        /// change (needs a running daemon or menu bar app)
+        #[arg(long)]
+        watch: bool,
+    },
     /// Focus an agent instance by PID
+    #[cfg(target_os = "macos")]
     Focus { pid: u32 },
     /// Clean up stale PIDs (interrupted sessions)
     Cleanup,
@@ -91,12 +234,40 @@ enum Commands {
         #[arg(short, long, default_value = "1")]
         interval: u64,
     },
+    /// Re-enable sleep when the process that disabled it stops renewing its lease
+    #[cfg(target_os = "macos")]
+    Watchdog {
+        #[arg(short, long, default_value = "15")]
+        interval: u64,
+    },
+    /// Run the privileged pmset helper (as root, started by launchd)
+    #[cfg(target_os = "macos")]
+    #[command(hide = true)]
+    Helper {
+        /// User allowed to send requests, besides root
+        #[arg(long = "allow-uid")]
+        allow_uid: Vec<u32>,
+    },
     /// Run background agent (dictation + permissions, no UI)
+    #[cfg(target_os = "macos")]
     Agent,
     /// Run native menu bar app
+    #[cfg(target_os = "macos")]
     Menubar,
     /// Force reset: clear all PIDs and re-enable sleep
     Reset,
+    /// Keep the Mac awake longer than the safety cap allows
+    Extend {
+        /// Minutes to add from now
+        #[arg(short, long, default_value = "60")]
+        minutes: u64,
+    },
+    /// Manage the power settings saved at install time
+    #[cfg(target_os = "macos")]
+    Power {
+        #[command(subcommand)]
+        action: PowerAction,
+    },
     /// Check thermal state
     Thermal,
     /// Install hooks and configure supported coding agents
@@ -104,6 +275,8 @@ enum Commands {
         /// Skip confirmation prompt
         #[arg(short, long)]
         yes: bool,
+        #[command(flatten)]
+        dirs: AgentDirArgs,
     },
     /// Uninstall hooks and restore defaults
     Uninstall {
@@ -116,34 +289,88 @@ enum Commands {
         /// Keep app data and logs
         #[arg(long)]
         keep_data: bool,
+        #[command(flatten)]
+        dirs: AgentDirArgs,
     },
     /// Open the settings window
+    #[cfg(target_os = "macos")]
     Settings,
     /// Debug: list process names
     Debug,
 }
 
+#[derive(Subcommand)]
+enum HoldAction {
+    /// List active holds
+    List,
+}
+
+#[cfg(target_os = "macos")]
+#[derive(Subcommand)]
+enum PowerAction {
+    /// Put back the pmset settings saved by `asp install`
+    Restore,
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    match cli.command.unwrap_or(Commands::Menubar) {
+    #[cfg(target_os = "macos")]
+    let default_command = Commands::Menubar;
+    #[cfg(not(target_os = "macos"))]
+    let default_command = Commands::Status { json: false };
+
+    match cli.command.unwrap_or(default_command) {
         Commands::Start => cmd_start()?,
         Commands::Stop => cmd_stop()?,
-        Commands::Status => cmd_status()?,
-        Commands::List => cmd_list()?,
+        Commands::Hook { event } => cmd_hook(event)?,
+        Commands::Run { command } => cmd_run(command)?,
+        Commands::Watch { pid } => cmd_watch(pid)?,
+        Commands::Hold {
+            action: Some(HoldAction::List),
+            ..
+        } => cmd_hold_list()?,
+        Commands::Hold {
+            action: None,
+            duration,
+            reason,
+        } => cmd_hold(duration, reason)?,
+        Commands::Release { id } => cmd_release(id)?,
+        Commands::History {
+            project,
+            since,
+            json,
+        } => cmd_history(project, since, json)?,
+        Commands::Stats { since, json } => cmd_stats(since, json)?,
+        Commands::BenchHook { iterations } => cmd_bench_hook(iterations)?,
+        Commands::Why { json } => cmd_why(json)?,
+        Commands::Status { json } => cmd_status(json)?,
+        Commands::List { watch } => cmd_list(watch)?,
+        #[cfg(target_os = "macos")]
         Commands::Focus { pid } => cmd_focus(pid)?,
         Commands::Cleanup => cmd_cleanup()?,
         Commands::Daemon { interval } => cmd_daemon(interval)?,
+        #[cfg(target_os = "macos")]
+        Commands::Watchdog { interval } => cmd_watchdog(interval)?,
+        #[cfg(target_os = "macos")]
+        Commands::Helper { allow_uid } => helper::run(&allow_uid)?,
+        #[cfg(target_os = "macos")]
         Commands::Agent => cmd_agent()?,
+        #[cfg(target_os = "macos")]
         Commands::Menubar => cmd_menubar()?,
         Commands::Reset => cmd_reset()?,
+        Commands::Extend { minutes } => cmd_extend(minutes)?,
+        #[cfg(target_os = "macos")]
+        Commands::Power { action } => cmd_power(action)?,
         Commands::Thermal => cmd_thermal()?,
-        Commands::Install { yes } => cmd_install(yes)?,
+        Commands::Install { yes, dirs } => cmd_install(yes, &dirs)?,
         Commands::Uninstall {
             keep_model,
             keep_hooks,
             keep_data,
-        } => cmd_uninstall(keep_model, keep_hooks, keep_data)?,
+            dirs,
+        } => cmd_uninstall(keep_model, keep_hooks, keep_data, &dirs)?,
+        #[cfg(target_os = "macos")]
         Commands::Settings => cmd_settings()?,
         Commands::Debug => cmd_debug()?,
     }
@@ -151,293 +378,640 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum AgentKind {
-    Claude,
-    Codex,
+/// Snapshot with CPU usage. The resident process diffs against its own
+/// previous sample and saves it; one-shot commands diff against that one
+/// rather than wait for a second snapshot. `None` without a recent sample.
+fn sample_processes() -> Option<Snapshot> {
+    let path = registry::dir().join(CPU_BASELINE_FILE);
+    if !RESIDENT_PROCESS.load(Ordering::SeqCst) {
+        return CpuSampler::sample_since(&CpuBaseline::load(&path), &process_table::native());
+    }
+    let mut sampler = CPU_SAMPLER.lock().unwrap_or_else(|e| e.into_inner());
+    let snapshot = sampler.sample(&process_table::native());
+    if let (Some(baseline), Ok(())) = (sampler.baseline(), ensure_pids_dir()) {
+        baseline.save(&path);
+    }
+    Some(snapshot)
 }
 
-#[derive(Debug, Clone)]
-struct ProcessInfo {
-    pid: u32,
-    ppid: u32,
-    comm: String,
-    args: String,
+/// Whether a session that stopped sending hooks is still doing work: its
+/// process tree burns CPU, or a tool it started is still running in a
+/// process spawned after the `PreToolUse` (a build, a container, a test run).
+fn session_tree_is_busy(processes: &Snapshot, record: &SessionRecord) -> bool {
+    if processes.tree_cpu(record.pid) >= IDLE_CPU_THRESHOLD {
+        return true;
+    }
+    // Start times are whole seconds, so allow one second of slack.
+    record.oldest_open_tool().is_some_and(|tool_started| {
+        processes
+            .tree(record.pid)
+            .iter()
+            .any(|p| p.pid != record.pid && p.start_time + 1 >= tool_started)
+    })
 }
 
-fn load_process_table() -> Vec<ProcessInfo> {
-    Command::new("ps")
-        .args(["-eo", "pid=,ppid=,comm=,args="])
-        .output()
-        .ok()
-        .and_then(|o| String::from_utf8(o.stdout).ok())
-        .map(|s| s.lines().filter_map(parse_process_line).collect())
-        .unwrap_or_default()
+fn agent_kind_label(kind: Option<&AgentKind>) -> &str {
+    kind.map_or("unknown", AgentKind::as_str)
 }
 
-fn parse_process_line(line: &str) -> Option<ProcessInfo> {
-    let mut parts = line.split_whitespace();
-    let pid = parts.next()?.parse().ok()?;
-    let ppid = parts.next()?.parse().ok()?;
-    let comm = parts.next()?.to_string();
-    let args = parts.collect::<Vec<_>>().join(" ");
-    Some(ProcessInfo {
-        pid,
-        ppid,
-        comm,
-        args,
-    })
+/// The coding agent the hook process `client_pid` descends from. Without
+/// one, the hook's parent stands in for it.
+fn find_agent_ancestor(client_pid: u32) -> Option<(u32, Option<AgentKind>)> {
+    let table = process_table::native();
+    let rules = AgentRules::load(&AgentRules::path());
+    let mut current_pid = client_pid;
+    let mut client_parent =
+        (client_pid == std::process::id()).then(std::os::unix::process::parent_id);
+
+    for _ in 0..20 {
+        let Some(process) = table.process(current_pid) else {
+            break;
+        };
+
+        if current_pid == client_pid {
+            client_parent = Some(process.ppid);
+        } else if let Some(rule) = rules.classify(&process) {
+            return Some((current_pid, Some(rule.agent.clone())));
+        }
+
+        if process.ppid == 0 || process.ppid == current_pid {
+            break;
+        }
+        current_pid = process.ppid;
+    }
+
+    client_parent.map(|pid| (pid, None))
 }
 
-fn executable_basename(token: &str) -> &str {
-    token.rsplit('/').next().unwrap_or(token)
+fn ensure_pids_dir() -> Result<()> {
+    let dir = registry::dir();
+    registry::ensure(dir).context("Failed to create the session registry")?;
+    registry::migrate_legacy(dir);
+    Ok(())
 }
 
-fn executable_name_is(token: &str, expected: &str) -> bool {
-    let basename = executable_basename(token);
-    basename == expected || basename == format!("{}.exe", expected)
+fn get_pid_file(pid: u32) -> PathBuf {
+    registry::dir().join(pid.to_string())
 }
 
-fn process_tokens(process: &ProcessInfo) -> Vec<&str> {
-    process.args.split_whitespace().collect()
+fn load_sessions() -> Vec<(PathBuf, SessionRecord)> {
+    sessions::load_records(registry::dir())
 }
 
-fn codex_command_index(tokens: &[&str]) -> Option<usize> {
-    tokens
-        .iter()
-        .position(|token| executable_name_is(token, "codex"))
+/// Sessions whose state currently keeps the Mac awake.
+fn load_active_sessions() -> Vec<(PathBuf, SessionRecord)> {
+    load_sessions()
+        .into_iter()
+        .filter(|(_, record)| record.state.prevents_sleep())
+        .collect()
+}
+
+/// Holds that haven't run out yet.
+fn load_active_holds() -> Vec<(PathBuf, holds::Hold)> {
+    let now = sessions::now_secs();
+    holds::load_holds(registry::dir())
+        .into_iter()
+        .filter(|(_, hold)| !hold.is_expired(now))
+        .collect()
 }
 
-fn is_codex_app_server(tokens: &[&str]) -> bool {
-    codex_command_index(tokens)
-        .and_then(|idx| tokens.get(idx + 1))
-        .map(|arg| *arg == "app-server")
-        .unwrap_or(false)
+/// When the last live hold expires. A hold is an explicit request to stay
+/// awake, so the total safety cap doesn't cut it short while it lasts.
+fn held_until() -> Option<u64> {
+    load_active_holds()
+        .into_iter()
+        .map(|(_, hold)| hold.expires_at)
+        .max()
 }
 
-fn is_codex_wrapper_process(process: &ProcessInfo) -> bool {
-    let tokens = process_tokens(process);
-    tokens
-        .first()
-        .map(|arg0| executable_name_is(arg0, "node"))
-        .unwrap_or(false)
-        && tokens
-            .get(1)
-            .map(|arg1| executable_name_is(arg1, "codex"))
-            .unwrap_or(false)
-        && !is_codex_app_server(&tokens)
+fn count_active_pids() -> usize {
+    load_active_sessions().len()
 }
 
-fn is_codex_native_process(process: &ProcessInfo) -> bool {
-    let tokens = process_tokens(process);
-    let arg0 = tokens.first().copied().unwrap_or(&process.comm);
-    (executable_name_is(arg0, "codex") || executable_name_is(&process.comm, "codex"))
-        && !is_codex_app_server(&tokens)
+/// Run `f` on the backend chosen in settings, switching backends (and
+/// releasing the previous one's hold) when the setting changed.
+fn with_power_backend<T>(f: impl FnOnce(&mut dyn power::PowerBackend) -> T) -> T {
+    let kind = power::supported(settings::AppSettings::load().sleep_prevention.backend);
+    let mut slot = POWER_BACKEND.lock().unwrap_or_else(|e| e.into_inner());
+    power::select(&mut slot, kind, power::backend);
+    f(slot.as_mut().expect("power backend selected").as_mut())
 }
 
-fn classify_agent_process(process: &ProcessInfo) -> Option<AgentKind> {
-    let tokens = process_tokens(process);
-    let arg0 = tokens.first().copied().unwrap_or(&process.comm);
+fn set_sleep_disabled(disabled: bool) -> Result<()> {
+    with_power_backend(|backend| power::reconcile(backend, disabled)).map(|_| ())
+}
 
-    if executable_name_is(arg0, "claude") || executable_name_is(&process.comm, "claude") {
-        return Some(AgentKind::Claude);
+/// Whether this process may change the sleep state. A hold that dies with
+/// the process is left to the resident agent, which syncs within a second.
+/// A sandboxed process never does.
+fn can_drive_power_backend() -> bool {
+    if registry::sandbox().is_some() {
+        return false;
     }
+    RESIDENT_PROCESS.load(Ordering::SeqCst)
+        || !with_power_backend(|backend| backend.is_process_scoped())
+}
 
-    if is_codex_native_process(process) || is_codex_wrapper_process(process) {
-        return Some(AgentKind::Codex);
+fn sleep_prevention_enabled_from_settings() -> bool {
+    settings::AppSettings::load().sleep_prevention.enabled
+}
+
+fn sync_sleep_state(source: &str, manual_enabled: bool) -> Result<()> {
+    if !can_drive_power_backend() {
+        return Ok(());
     }
 
-    None
+    let capped = enforce_safety_cap(source);
+    let records = load_sessions();
+    let active = records
+        .iter()
+        .filter(|(_, record)| record.state.prevents_sleep())
+        .count();
+    let held = !load_active_holds().is_empty();
+    let sleep_disabled = is_sleep_disabled();
+    let thermal_warning = check_thermal_warning();
+    let policy = apply_policy(manual_enabled && (active > 0 || held) && !thermal_warning);
+    let should_prevent = policy.as_ref().is_some_and(|p| p.allow_prevention);
+    let paused_by = if !manual_enabled {
+        EndReason::Disabled
+    } else if thermal_warning {
+        EndReason::Thermal
+    } else {
+        EndReason::Policy
+    };
+    track_awake_stretches(&records, should_prevent, paused_by);
+    let action = audit::Action::new(should_prevent, sleep_disabled);
+    let result = match action {
+        audit::Action::DisableSleep => set_sleep_disabled(true),
+        audit::Action::EnableSleep => enable_sleep_and_trigger_if_lid_closed(),
+        audit::Action::KeepDisabled | audit::Action::KeepEnabled => Ok(()),
+    };
+    let inputs = audit_inputs(&records, manual_enabled, policy.as_ref(), capped.as_deref());
+    let mut event = audit::Event::decision(source, sessions::now_secs(), action, inputs);
+    if let Err(e) = &result {
+        event.detail = Some(format!("Failed: {:#}", e));
+    }
+    record_audit(&event);
+    result?;
+
+    match action {
+        audit::Action::DisableSleep => logging::log(&format!(
+            "[{}] Sleep disabled (active sessions: {}; {}{})",
+            source,
+            active,
+            describe_session_states(&records),
+            if held { "; held" } else { "" }
+        )),
+        audit::Action::EnableSleep => match (capped, policy) {
+            (Some(reason), _) => logging::log(&format!(
+                "[{}] Sleep re-enabled by safety cap: {}",
+                source, reason
+            )),
+            (None, Some(policy)) => logging::log(&format!(
+                "[{}] Sleep re-enabled by policy: {}",
+                source,
+                policy.summary()
+            )),
+            (None, None) => logging::log(&format!("[{}] Sleep re-enabled", source)),
+        },
+        audit::Action::KeepDisabled | audit::Action::KeepEnabled => {}
+    }
+    track_prevention(should_prevent);
+    update_lease(should_prevent);
+    hooks::SyncStamp {
+        synced_at: sessions::now_secs(),
+    }
+    .save(&registry::dir().join(SYNC_STAMP_FILE));
+
+    Ok(())
 }
 
-fn find_agent_ancestor() -> Option<u32> {
-    let processes = load_process_table();
-    let by_pid: HashMap<u32, ProcessInfo> = processes
-        .into_iter()
-        .map(|process| (process.pid, process))
-        .collect();
-    let this_pid = std::process::id();
-    let mut current_pid = this_pid;
+/// What a decision was based on, for the audit log. The thermal state is
+/// the one `check_thermal_warning` just saved.
+fn audit_inputs(
+    records: &[(PathBuf, SessionRecord)],
+    manual_enabled: bool,
+    policy: Option<&PolicyDecision>,
+    capped: Option<&str>,
+) -> audit::Inputs {
+    let thermal = ThermalMonitor::load(&registry::dir().join(THERMAL_STATE_FILE));
+    audit::Inputs {
+        active_sessions: records
+            .iter()
+            .filter(|(_, record)| record.state.prevents_sleep())
+            .map(|(_, record)| audit::SessionInput::new(record))
+            .collect(),
+        holds: load_active_holds()
+            .into_iter()
+            .map(|(_, hold)| hold)
+            .collect(),
+        manual_enabled,
+        thermal_level: thermal.level.label().to_string(),
+        thermal_paused: thermal.paused,
+        policy: policy.map(|policy| audit::PolicyInput {
+            allow_prevention: policy.allow_prevention,
+            summary: policy.summary(),
+        }),
+        capped: capped.map(str::to_string),
+        lid_closed: is_lid_closed(),
+    }
+}
+
+fn record_audit(event: &audit::Event) {
+    if ensure_pids_dir().is_err() {
+        return;
+    }
+    let last = registry::dir().join(AUDIT_STATE_FILE);
+    if let Err(e) = audit::record(&audit::path(), &last, event) {
+        logging::log(&format!("[audit] Failed to record event: {}", e));
+    }
+}
 
-    for _ in 0..20 {
-        let Some(process) = by_pid.get(&current_pid) else {
-            break;
-        };
+/// Record something that happened outside a decision, with the state it
+/// happened in.
+fn record_audit_event(source: &str, kind: audit::EventKind, detail: String) {
+    let inputs = audit_inputs(
+        &load_sessions(),
+        sleep_prevention_enabled_from_settings(),
+        None,
+        None,
+    );
+    record_audit(&audit::Event::new(
+        source,
+        sessions::now_secs(),
+        kind,
+        detail,
+        inputs,
+    ));
+}
+
+fn lid_log_path() -> PathBuf {
+    registry::dir().join(LID_LOG_FILE)
+}
+
+/// Close the stretch of time `record` has been keeping the machine awake
+/// for, if one is open, and add it to the history. The caller saves
+/// `record`.
+fn end_awake_stretch(record: &mut SessionRecord, reason: EndReason) {
+    let now = sessions::now_secs();
+    let Some(entry) = history::finish(record, reason, now, &LidLog::load(&lid_log_path())) else {
+        return;
+    };
+    if let Err(e) = history::append(&history::path(), &entry) {
+        logging::log(&format!("[history] Failed to record session: {}", e));
+    }
+}
 
-        if current_pid != this_pid && classify_agent_process(process).is_some() {
-            return Some(current_pid);
+/// Open a history stretch for every busy session while sleep is being
+/// prevented, and close them with `paused_by` once it isn't.
+fn track_awake_stretches(
+    records: &[(PathBuf, SessionRecord)],
+    should_prevent: bool,
+    paused_by: EndReason,
+) {
+    let now = sessions::now_secs();
+    let mut stretches_open = false;
+    for (path, record) in records {
+        let awake = should_prevent && record.state.prevents_sleep();
+        stretches_open |= awake;
+        if awake == record.awake_since.is_some() {
+            continue;
+        }
+        // A hook may have rewritten the record since it was loaded: start
+        // from the file as it is now and change nothing but `awake_since`.
+        let Some(mut current) = sessions::read_record(path) else {
+            continue;
+        };
+        let awake = should_prevent && current.state.prevents_sleep();
+        if awake == current.awake_since.is_some() {
+            continue;
         }
+        if awake {
+            current.awake_since = Some(now);
+        } else if current.state.prevents_sleep() {
+            end_awake_stretch(&mut current, paused_by);
+        } else {
+            end_awake_stretch(&mut current, EndReason::Stop);
+        }
+        let _ = sessions::write_record(path, &current);
+    }
 
-        if process.ppid == 0 || process.ppid == current_pid {
-            break;
+    let path = lid_log_path();
+    let mut lid = LidLog::load(&path);
+    let lid_closed = stretches_open && is_lid_closed();
+    if lid.track(lid_closed, now) && ensure_pids_dir().is_ok() {
+        lid.save(&path);
+    }
+}
+
+fn cap_state_path() -> PathBuf {
+    registry::dir().join(CAP_STATE_FILE)
+}
+
+/// Expire every session that has prevented sleep for longer than the
+/// safety cap allows, or all of them once sleep prevention as a whole has.
+/// Returns the reason when anything expired, so the caller's sync re-enables
+/// sleep with it.
+fn enforce_safety_cap(source: &str) -> Option<String> {
+    let cap = settings::AppSettings::load().sleep_prevention.safety_cap;
+    let now = sessions::now_secs();
+    let total = cap.total_exceeded(&CapState::load(&cap_state_path()), held_until(), now);
+    let mut expired = None;
+
+    for (path, mut record) in load_active_sessions() {
+        let Some(reason) = total.clone().or_else(|| cap.session_exceeded(&record, now)) else {
+            continue;
+        };
+        cap::expire(&mut record, &reason, now);
+        end_awake_stretch(&mut record, EndReason::Cap);
+        if sessions::write_record(&path, &record).is_ok() {
+            let detail = format!(
+                "Session {} ({}) expired: {}",
+                record.pid,
+                record.location(),
+                reason
+            );
+            logging::log(&format!("[{}] {}", source, detail));
+            record_audit_event(source, audit::EventKind::Cap, detail);
+            expired = Some(reason);
         }
-        current_pid = process.ppid;
     }
 
-    Some(std::os::unix::process::parent_id())
+    total.or(expired)
 }
 
-fn ensure_pids_dir() -> Result<()> {
-    fs::create_dir_all(PIDS_DIR).context("Failed to create PIDs directory")?;
-    Ok(())
+fn lease_path() -> PathBuf {
+    registry::dir().join(LEASE_FILE)
 }
 
-fn get_pid_file(pid: u32) -> PathBuf {
-    PathBuf::from(PIDS_DIR).join(pid.to_string())
+/// Renew the lease on a persistent sleep hold while it's wanted and drop it
+/// once sleep is allowed again. Holds that die with the process need none.
+fn update_lease(preventing: bool) {
+    if with_power_backend(|backend| backend.is_process_scoped()) {
+        return;
+    }
+    let path = lease_path();
+    if !preventing {
+        let _ = fs::remove_file(&path);
+        return;
+    }
+    let hold_until = load_active_holds()
+        .iter()
+        .map(|(_, hold)| hold.expires_at)
+        .max();
+    let renewal = Lease::new(
+        std::process::id(),
+        RESIDENT_PROCESS.load(Ordering::SeqCst),
+        sessions::now_secs(),
+    )
+    .lasting_until(hold_until);
+    if let Some(current) = Lease::load(&path) {
+        if !current.yields_to(&renewal, is_process_alive(current.owner_pid)) {
+            return;
+        }
+    }
+    if ensure_pids_dir().is_ok() {
+        if let Err(e) = renewal.save(&path) {
+            logging::log(&format!("[lease] Failed to renew: {}", e));
+        }
+    }
 }
 
-fn count_active_pids() -> usize {
-    fs::read_dir(PIDS_DIR)
-        .map(|entries| entries.filter_map(|e| e.ok()).count())
-        .unwrap_or(0)
+/// Turn sleep back on when whoever disabled it stopped renewing the lease,
+/// e.g. because the menu bar app crashed.
+#[cfg(target_os = "macos")]
+fn check_lease(source: &str) {
+    let path = lease_path();
+    let Some(lease) = Lease::load(&path) else {
+        return;
+    };
+    let now = sessions::now_secs();
+    if !lease.is_expired(now, is_process_alive(lease.owner_pid)) {
+        return;
+    }
+    // The owner may have renewed in the meantime.
+    if Lease::load(&path) != Some(lease) {
+        return;
+    }
+    if !power::is_sleep_disabled() {
+        let _ = fs::remove_file(&path);
+        return;
+    }
+    // The lease stays until sleep is back on, so a failed request is tried
+    // again on the next check.
+    match helper::request(helper::Request::SetDisablesleep { enabled: false }) {
+        Ok(_) => {
+            let _ = fs::remove_file(&path);
+            logging::log(&format!(
+                "[{}] Lease held by PID {} expired {}s ago; sleep re-enabled",
+                source,
+                lease.owner_pid,
+                now.saturating_sub(lease.expires_at())
+            ));
+            if is_lid_closed() {
+                record_audit_event(
+                    source,
+                    audit::EventKind::Lid,
+                    "Lid is closed; sleeping now".to_string(),
+                );
+                force_sleep_now();
+            }
+        }
+        Err(e) => logging::log(&format!("[{}] Failed to re-enable sleep: {}", source, e)),
+    }
 }
 
-fn set_sleep_disabled(disabled: bool) -> Result<()> {
-    let value = if disabled { "1" } else { "0" };
-    let output = Command::new("sudo")
-        .args(["pmset", "-a", "disablesleep", value])
-        .output()
-        .context("Failed to run pmset")?;
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        logging::log(&format!(
-            "[pmset] Failed to set disablesleep={}: {}",
-            value,
-            stderr.trim()
-        ));
+/// A crash can leave sleep disabled with no session to justify it. Drop
+/// dead sessions and turn sleep back on if nothing is working anymore.
+fn reconcile_sleep_state(source: &str) {
+    cleanup_stale_pids();
+    if !can_drive_power_backend() || !is_sleep_disabled() || count_active_pids() > 0 {
+        return;
     }
-    Ok(())
+    logging::log(&format!(
+        "[{}] Sleep was left disabled with no working sessions; re-enabling",
+        source
+    ));
+    record_audit(&audit::Event::decision(
+        source,
+        sessions::now_secs(),
+        audit::Action::EnableSleep,
+        audit_inputs(
+            &load_sessions(),
+            sleep_prevention_enabled_from_settings(),
+            None,
+            None,
+        ),
+    ));
+    if let Err(e) = set_sleep_disabled(false) {
+        logging::log(&format!("[{}] Failed to re-enable sleep: {}", source, e));
+    }
+    let _ = fs::remove_file(lease_path());
 }
 
-fn sleep_prevention_enabled_from_settings() -> bool {
-    settings::AppSettings::load().sleep_prevention.enabled
+/// Remember when sleep prevention started, for the overall safety cap.
+fn track_prevention(preventing: bool) {
+    let path = cap_state_path();
+    let mut state = CapState::load(&path);
+    if state.track(preventing, sessions::now_secs()) && ensure_pids_dir().is_ok() {
+        state.save(&path);
+    }
 }
 
-fn sync_sleep_state(source: &str, manual_enabled: bool) -> Result<()> {
-    let active = count_active_pids();
-    let sleep_disabled = is_sleep_disabled();
-    let thermal_warning = check_thermal_warning();
-    let should_prevent = manual_enabled && active > 0 && !thermal_warning;
+fn policy_state_path() -> PathBuf {
+    registry::dir().join(POLICY_STATE_FILE)
+}
 
-    if should_prevent && !sleep_disabled {
-        set_sleep_disabled(true)?;
-        logging::log(&format!(
-            "[{}] Sleep disabled (active PIDs: {})",
-            source, active
-        ));
-    } else if !should_prevent && sleep_disabled {
-        enable_sleep_and_trigger_if_lid_closed()?;
-        logging::log(&format!("[{}] Sleep re-enabled", source));
+/// Evaluate the battery and lid rules from settings. `persist` records when
+/// time-limited rules started matching; `asp status` only looks.
+fn evaluate_policy(persist: bool) -> PolicyDecision {
+    let rules = settings::AppSettings::load().sleep_prevention.policy_rules;
+    let path = policy_state_path();
+    let mut state = PolicyState::load(&path);
+    let now = sessions::now_secs();
+    let input = PolicyInput {
+        power: state.power_source(now, power::source::read),
+        lid_closed: is_lid_closed(),
+    };
+    let decision = policy::evaluate(&rules, &input, &mut state, now);
+    if persist && ensure_pids_dir().is_ok() {
+        state.save(&path);
     }
+    decision
+}
 
-    Ok(())
+/// Consult the policy when everything else wants sleep prevented. Otherwise
+/// nothing is being prevented, so rule timers start over.
+fn apply_policy(wanted: bool) -> Option<PolicyDecision> {
+    if wanted {
+        Some(evaluate_policy(true))
+    } else {
+        let _ = fs::remove_file(policy_state_path());
+        None
+    }
 }
 
+fn describe_session_states(records: &[(PathBuf, SessionRecord)]) -> String {
+    records
+        .iter()
+        .map(|(_, record)| format!("{}={}", record.pid, record.state.label()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(target_os = "macos")]
 fn menubar_sync_sleep() {
     let manual_enabled = MANUAL_SLEEP_PREVENTION.load(Ordering::SeqCst);
     let _ = sync_sleep_state("sync", manual_enabled);
 }
 
 fn cleanup_stale_pids() {
-    let entries = match fs::read_dir(PIDS_DIR) {
-        Ok(e) => e,
-        Err(_) => return,
-    };
-
+    let records = load_sessions();
+    let total = records.len();
+    let now = sessions::now_secs();
+    let processes = sample_processes();
     let mut removed = 0;
-    let mut total = 0;
-
-    for entry in entries.filter_map(|e| e.ok()) {
-        let pid: u32 = match entry.file_name().to_string_lossy().parse() {
-            Ok(p) => p,
-            Err(_) => continue,
-        };
-
-        total += 1;
-        let path = entry.path();
+    let mut idled = 0;
+    let mut events = Vec::new();
 
-        if !is_process_alive(pid) {
+    for (path, mut record) in records {
+        if !is_session_alive(&record) {
+            end_awake_stretch(&mut record, EndReason::Exited);
             if fs::remove_file(&path).is_ok() {
                 removed += 1;
+                events.push((
+                    audit::EventKind::Reap,
+                    format!("Session {} ({}) exited", record.pid, record.location()),
+                ));
             }
             continue;
         }
 
-        let age = get_file_age(&path).unwrap_or(0);
-        if age >= IDLE_TIMEOUT_SECS {
-            let cpu = get_process_cpu(pid);
-            if cpu < IDLE_CPU_THRESHOLD {
-                if fs::remove_file(&path).is_ok() {
-                    removed += 1;
-                }
+        // A busy session that stopped sending hooks and whose process tree
+        // does no work was most likely interrupted (Ctrl+C, crash); park it
+        // as idle. Without a CPU sample that's left to the resident process.
+        if record.state.prevents_sleep()
+            && record.is_hook_driven()
+            && record.idle_secs(now) >= IDLE_TIMEOUT_SECS
+            && processes
+                .as_ref()
+                .is_some_and(|processes| !session_tree_is_busy(processes, &record))
+        {
+            record.open_tools.clear();
+            record.set_state(SessionState::Idle, now);
+            end_awake_stretch(&mut record, EndReason::IdleReap);
+            if sessions::write_record(&path, &record).is_ok() {
+                idled += 1;
+                events.push((
+                    audit::EventKind::Reap,
+                    format!(
+                        "Session {} ({}) parked as idle after {}s without hooks or work",
+                        record.pid,
+                        record.location(),
+                        record.idle_secs(now)
+                    ),
+                ));
             }
         }
     }
 
-    if removed > 0 {
+    if removed > 0 || idled > 0 {
         logging::log(&format!(
-            "[cleanup] Removed {}/{} stale PIDs",
-            removed, total
+            "[cleanup] Removed {} dead and idled {} stale of {} sessions",
+            removed, idled, total
         ));
     }
-}
-
-fn is_sleep_disabled() -> bool {
-    unsafe {
-        let service_name = b"IOPMrootDomain\0";
-        let matching = IOServiceMatching(service_name.as_ptr() as *const i8);
-        if matching.is_null() {
-            return false;
-        }
 
-        let root_domain = IOServiceGetMatchingService(kIOMasterPortDefault, matching);
-        if root_domain == MACH_PORT_NULL {
-            return false;
+    for (path, hold) in holds::load_holds(registry::dir()) {
+        if hold.is_expired(now) && fs::remove_file(&path).is_ok() {
+            logging::log(&format!("[cleanup] Hold #{} expired", hold.id));
+            let detail = match &hold.reason {
+                Some(reason) => format!("Hold #{} ({}) expired", hold.id, reason),
+                None => format!("Hold #{} expired", hold.id),
+            };
+            events.push((audit::EventKind::HoldExpired, detail));
         }
+    }
 
-        let key = CFString::new("SleepDisabled");
-        let property = IORegistryEntryCreateCFProperty(
-            root_domain,
-            key.as_concrete_TypeRef(),
-            kCFAllocatorDefault,
-            0,
-        );
-
-        IOObjectRelease(root_domain);
+    for (kind, detail) in events {
+        record_audit_event("cleanup", kind, detail);
+    }
+}
 
-        if property.is_null() {
-            return false;
-        }
+fn is_sleep_disabled() -> bool {
+    with_power_backend(|backend| backend.is_preventing_sleep())
+}
 
-        let result = CFBoolean::wrap_under_create_rule(property as _).into();
-        result
+/// Whether sleep prevention is paused because the machine is too hot.
+/// Feeds the current thermal state to the persisted monitor, which applies
+/// the thresholds from settings with hysteresis, and logs every transition.
+fn check_thermal_warning() -> bool {
+    let thresholds = settings::AppSettings::load().sleep_prevention.thermal;
+    let path = registry::dir().join(THERMAL_STATE_FILE);
+    let mut monitor = ThermalMonitor::load(&path);
+    let before = monitor;
+    let state = thermal::read();
+    let transition = monitor.update(state.level(), &thresholds, sessions::now_secs());
+    if monitor != before && ensure_pids_dir().is_ok() {
+        monitor.save(&path);
     }
+    if let Some(transition) = transition {
+        let detail = format!("{} ({})", transition, state);
+        logging::log(&format!("[thermal] {}", detail));
+        record_audit_event("thermal", audit::EventKind::Thermal, detail);
+    }
+    monitor.paused
 }
 
-fn check_thermal_warning() -> bool {
-    Command::new("pmset")
-        .args(["-g", "therm"])
-        .output()
-        .ok()
-        .and_then(|output| String::from_utf8(output.stdout).ok())
-        .map(|s| {
-            (s.contains("CPU_Scheduler_Limit") && !s.contains("No CPU"))
-                || (s.contains("thermal warning level") && !s.contains("No thermal warning"))
-        })
-        .unwrap_or(false)
+/// Whether heat has paused sleep prevention, as last recorded by
+/// `check_thermal_warning`. Reads the saved state without updating it, for
+/// callers that only report.
+fn thermal_paused() -> bool {
+    ThermalMonitor::load(&registry::dir().join(THERMAL_STATE_FILE)).paused
 }
 
 fn get_process_cwd(pid: u32) -> Option<String> {
-    Command::new("lsof")
-        .args(["-a", "-p", &pid.to_string(), "-d", "cwd", "-Fn"])
-        .output()
-        .ok()
-        .and_then(|o| String::from_utf8(o.stdout).ok())
-        .and_then(|s| {
-            s.lines()
-                .find(|l| l.starts_with('n'))
-                .map(|l| l[1..].to_string())
-        })
+    process_table::native().process(pid)?.cwd
 }
 
 fn get_git_branch(path: &str) -> Option<String> {
@@ -451,61 +1025,521 @@ fn get_git_branch(path: &str) -> Option<String> {
         .filter(|s| !s.is_empty())
 }
 
-fn format_process_location(pid: u32) -> String {
-    get_process_cwd(pid)
-        .map(|cwd| {
-            let dir_name = std::path::Path::new(&cwd)
-                .file_name()
-                .map(|n| n.to_string_lossy().to_string())
-                .unwrap_or_else(|| cwd.clone());
-            match get_git_branch(&cwd) {
-                Some(branch) => format!("{} git:({})", dir_name, branch),
-                None => dir_name,
-            }
-        })
-        .unwrap_or_else(|| "unknown".to_string())
+/// Whether the agent behind a session is still the process that registered
+/// it. A PID the OS has since handed to another program counts as dead.
+fn is_session_alive(record: &SessionRecord) -> bool {
+    if !is_process_alive(record.pid) {
+        return false;
+    }
+    match process_table::native().process(record.pid) {
+        Some(process) => record.matches_process(process.start_time, process.exe.as_deref()),
+        None => true,
+    }
 }
 
-fn cmd_start() -> Result<()> {
-    logging::init_quiet();
-    ensure_pids_dir()?;
-
-    let agent_pid = find_agent_ancestor().unwrap_or(std::process::id());
-    let pid_file = get_pid_file(agent_pid);
-
-    fs::write(&pid_file, "working").context("Failed to write PID file")?;
-
-    sync_sleep_state("hook-start", sleep_prevention_enabled_from_settings())
+/// Build a fresh record for an agent, resolving cwd and branch once so
+/// readers never have to look them up again.
+fn new_session_record(pid: u32, agent: Option<AgentKind>, payload: &HookPayload) -> SessionRecord {
+    let event = payload.hook_event_name.as_deref().unwrap_or("start");
+    let mut record = SessionRecord::new(pid, event, sessions::now_secs());
+    record.session_id = payload.session_id().map(str::to_string);
+    record.agent = agent;
+    record.cwd = payload.cwd.clone().or_else(|| get_process_cwd(pid));
+    record.git_branch = record.cwd.as_deref().and_then(get_git_branch);
+    if let Some(process) = process_table::native().process(pid) {
+        record.process_start_time = Some(process.start_time);
+        record.process_exe = process.exe;
+    }
+    record
 }
 
-fn cmd_stop() -> Result<()> {
-    logging::init_quiet();
-    let agent_pid = find_agent_ancestor().unwrap_or(std::process::id());
-    let pid_file = get_pid_file(agent_pid);
-
-    let _ = fs::remove_file(&pid_file);
+/// Find the registry record for the session behind the hook process
+/// `client_pid`. Returns `true` alongside a record that doesn't exist on
+/// disk yet.
+fn resolve_session(payload: &HookPayload, client_pid: u32) -> (PathBuf, SessionRecord, bool) {
+    let dir = registry::dir();
 
-    sync_sleep_state("hook-stop", sleep_prevention_enabled_from_settings())
-}
+    // Known session whose agent is still running.
+    if let Some(session_id) = payload.session_id() {
+        let path = sessions::record_path(dir, Some(session_id), 0);
+        if let Some(record) = sessions::read_record(&path) {
+            if is_session_alive(&record) {
+                return (path, record, false);
+            }
+        }
+    }
 
-fn get_all_agent_processes() -> Vec<ProcessInfo> {
-    let processes = load_process_table();
-    let codex_native_parents: HashSet<u32> = processes
-        .iter()
-        .filter(|process| is_codex_native_process(process))
+    // Agents mostly run hooks as direct children, so one seen before has a
+    // record under our parent's PID and the tree needn't be walked again.
+    if payload.session_id().is_none() {
+        if let Some(parent) = hook_parent(client_pid) {
+            let path = sessions::record_path(dir, None, parent);
+            if let Some(record) = sessions::read_record(&path) {
+                if !record.is_legacy() && record.pid == parent && is_session_alive(&record) {
+                    return (path, record, false);
+                }
+            }
+        }
+    }
+
+    // New or resumed session, or an agent that sent no payload: find the
+    // owning agent by walking the process tree.
+    let (agent_pid, agent) = find_agent_ancestor(client_pid).unwrap_or((client_pid, None));
+    let path = sessions::record_path(dir, payload.session_id(), agent_pid);
+
+    if payload.session_id().is_some() {
+        // Drop any PID-keyed file written by a pre-session-id hook.
+        let _ = fs::remove_file(get_pid_file(agent_pid));
+    }
+
+    match sessions::read_record(&path) {
+        Some(record)
+            if !record.is_legacy() && record.pid == agent_pid && is_session_alive(&record) =>
+        {
+            (path, record, false)
+        }
+        _ => (path, new_session_record(agent_pid, agent, payload), true),
+    }
+}
+
+/// The process that started the hook process `client_pid`.
+fn hook_parent(client_pid: u32) -> Option<u32> {
+    if client_pid == std::process::id() {
+        return Some(std::os::unix::process::parent_id());
+    }
+    process_table::native()
+        .process(client_pid)
         .map(|process| process.ppid)
-        .collect();
+}
+
+/// Where a hook left its session.
+struct HookOutcome {
+    state: SessionState,
+    /// State before the hook, `None` for a session seen for the first time
+    previous: Option<SessionState>,
+}
+
+impl HookOutcome {
+    /// Whether the hook left nothing for a sleep sync to do. Most tool
+    /// calls only move a busy session between busy states.
+    fn is_settled(&self) -> bool {
+        let stamp = hooks::SyncStamp::load(&registry::dir().join(SYNC_STAMP_FILE));
+        hooks::can_skip_sync(self.previous, self.state, &stamp, sessions::now_secs())
+    }
+}
+
+/// Advance the session's state machine for one hook event and persist it.
+fn apply_hook_event(
+    payload: &HookPayload,
+    event: &HookEvent,
+    client_pid: u32,
+) -> Result<HookOutcome> {
+    let (path, mut record, is_new) = resolve_session(payload, client_pid);
+    let previous = (!is_new).then_some(record.state);
+    hooks::apply_event(&mut record, event, payload, sessions::now_secs());
+    let state = record.state;
+    if !state.prevents_sleep() {
+        let reason = if state == SessionState::Ended {
+            EndReason::SessionEnd
+        } else {
+            EndReason::Stop
+        };
+        end_awake_stretch(&mut record, reason);
+    }
+
+    if state == SessionState::Ended || (is_new && !state.prevents_sleep()) {
+        // Nothing worth tracking: the session is gone, or we only ever saw
+        // it go quiet.
+        let _ = fs::remove_file(&path);
+    } else {
+        sessions::write_record(&path, &record).context("Failed to write PID file")?;
+    }
+
+    Ok(HookOutcome { state, previous })
+}
+
+/// Sync after a hook run without a resident process, unless it changed
+/// nothing.
+fn sync_after_hook(source: &str, outcome: &HookOutcome) -> Result<()> {
+    if outcome.is_settled() {
+        return Ok(());
+    }
+    sync_sleep_state(source, sleep_prevention_enabled_from_settings())
+}
+
+fn cmd_hook(event: Option<String>) -> Result<()> {
+    logging::init_quiet();
+
+    let payload = hooks::read_payload();
+    let Some(name) = event.or_else(|| payload.hook_event_name.clone()) else {
+        anyhow::bail!("No hook event given and none found in the stdin payload");
+    };
+    if hook_via_resident(
+        "register",
+        &control::Registration::Hook {
+            event: name.clone(),
+            payload: payload.clone(),
+            client_pid: std::process::id(),
+        },
+    ) {
+        return Ok(());
+    }
+
+    ensure_pids_dir()?;
+    let event = HookEvent::from_name(&name);
+    let outcome = apply_hook_event(&payload, &event, std::process::id())?;
+
+    sync_after_hook(&format!("hook-{}", event.name()), &outcome)
+}
+
+fn cmd_start() -> Result<()> {
+    logging::init_quiet();
+
+    // Older hook configs call `start` for every busy event; honour the
+    // payload's event name when there is one.
+    let payload = hooks::read_payload();
+    let event = payload
+        .hook_event_name
+        .as_deref()
+        .map(HookEvent::from_name)
+        .unwrap_or(HookEvent::UserPromptSubmit);
+    if hook_via_resident(
+        "register",
+        &control::Registration::Hook {
+            event: event.name().to_string(),
+            payload: payload.clone(),
+            client_pid: std::process::id(),
+        },
+    ) {
+        return Ok(());
+    }
+
+    ensure_pids_dir()?;
+    let outcome = apply_hook_event(&payload, &event, std::process::id())?;
+
+    sync_after_hook("hook-start", &outcome)
+}
+
+fn cmd_stop() -> Result<()> {
+    logging::init_quiet();
+
+    let payload = hooks::read_payload();
+    if hook_via_resident(
+        "unregister",
+        &control::Unregistration::Hook {
+            payload: payload.clone(),
+            client_pid: std::process::id(),
+        },
+    ) {
+        return Ok(());
+    }
+
+    ensure_pids_dir()?;
+    let outcome = apply_hook_event(&payload, &HookEvent::Stop, std::process::id())?;
+
+    sync_after_hook("hook-stop", &outcome)
+}
+
+/// Hand a hook to the resident process. Returns whether it took it; a
+/// resident that doesn't answer is logged and the hook falls back to
+/// writing the registry itself, so the agent's hook never fails over it.
+fn hook_via_resident(method: &str, params: &impl serde::Serialize) -> bool {
+    match call_resident(method, params) {
+        Ok(answer) => answer.is_some(),
+        Err(e) => {
+            logging::log(&format!("[hook] Resident did not answer {}: {}", method, e));
+            false
+        }
+    }
+}
+
+/// Run `asp hook` the way an agent does, once to register a session and
+/// then for `iterations` tool calls, and report how long each took end to
+/// end, process start included. It all runs in a scratch sandbox with a
+/// daemon of its own, so real sessions, history and the sleep state are
+/// left alone.
+fn cmd_bench_hook(iterations: usize) -> Result<()> {
+    if iterations == 0 {
+        anyhow::bail!("Give at least one iteration");
+    }
+    let exe = std::env::current_exe().context("Failed to find the asp binary")?;
+    let sandbox = std::env::temp_dir().join(format!("asp-bench-hook-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&sandbox);
+    let mut daemon = Command::new(&exe)
+        .arg("daemon")
+        .env(registry::SANDBOX_ENV, &sandbox)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .context("Failed to start a daemon for the benchmark")?;
+    let socket = sandbox.join("sessions").join(control::SOCKET_NAME);
+    let listening = (0..50).any(|_| {
+        let connected = std::os::unix::net::UnixStream::connect(&socket).is_ok();
+        if !connected {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        connected
+    });
+
+    let session_id = format!("asp-bench-hook-{}", std::process::id());
+    let cwd = std::env::temp_dir().display().to_string();
+    let run_hook = |event: &str, tool_use_id: Option<String>| -> Result<Duration> {
+        let payload = serde_json::to_vec(&HookPayload {
+            session_id: Some(session_id.clone()),
+            cwd: Some(cwd.clone()),
+            hook_event_name: Some(event.to_string()),
+            tool_name: tool_use_id.as_ref().map(|_| "Bash".to_string()),
+            tool_use_id,
+            ..HookPayload::default()
+        })?;
+        let started = Instant::now();
+        let mut child = Command::new(&exe)
+            .args(["hook", event])
+            .env(registry::SANDBOX_ENV, &sandbox)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .context("Failed to run asp hook")?;
+        if let Some(mut stdin) = child.stdin.take() {
+            std::io::Write::write_all(&mut stdin, &payload)?;
+        }
+        let output = child.wait_with_output()?;
+        let elapsed = started.elapsed();
+        if !output.status.success() {
+            anyhow::bail!(
+                "asp hook {} failed: {}",
+                event,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(elapsed)
+    };
+
+    let timed = if listening {
+        run_hook("UserPromptSubmit", None)
+    } else {
+        Err(anyhow::anyhow!(
+            "The benchmark daemon did not start listening"
+        ))
+    }
+    .and_then(|first| {
+        let samples = (0..iterations)
+            .map(|i| {
+                let event = if i % 2 == 0 {
+                    "PreToolUse"
+                } else {
+                    "PostToolUse"
+                };
+                run_hook(event, Some(format!("bench-{}", i / 2)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((first, bench::Latencies::new(samples)))
+    });
+    let ended = run_hook("SessionEnd", None);
+    let _ = daemon.kill();
+    let _ = daemon.wait();
+    let _ = fs::remove_dir_all(&sandbox);
+    let (first, latencies) = timed?;
+    ended?;
+
+    println!(
+        "{} tool hooks through the control socket of a sandboxed daemon:",
+        iterations
+    );
+    println!(
+        "  first: {} (registers the session)",
+        bench::format_ms(first)
+    );
+    println!("  p50:   {}", bench::format_ms(latencies.percentile(50.0)));
+    println!("  p99:   {}", bench::format_ms(latencies.percentile(99.0)));
+    println!("  max:   {}", bench::format_ms(latencies.max()));
+
+    let p99 = latencies.percentile(99.0);
+    if p99 > bench::HOOK_BUDGET {
+        anyhow::bail!(
+            "p99 of {} is over the {} budget",
+            bench::format_ms(p99),
+            bench::format_ms(bench::HOOK_BUDGET)
+        );
+    }
+    println!(
+        "p99 is within the {} budget",
+        bench::format_ms(bench::HOOK_BUDGET)
+    );
+    Ok(())
+}
+
+/// Register `pid` as a working session that lasts as long as the process,
+/// through the resident process when one is listening.
+fn register_process_session(pid: u32, command: String, source: &str) -> Result<PathBuf> {
+    let registration = control::Registration::Process {
+        pid,
+        command: command.clone(),
+    };
+    if call_resident("register", &registration)?.is_some() {
+        return Ok(sessions::record_path(registry::dir(), None, pid));
+    }
+    let path = add_process_session(pid, command, source)?;
+    sync_sleep_state(source, sleep_prevention_enabled_from_settings())?;
+    Ok(path)
+}
+
+fn add_process_session(pid: u32, command: String, source: &str) -> Result<PathBuf> {
+    ensure_pids_dir()?;
+    let payload = HookPayload {
+        hook_event_name: Some(source.to_string()),
+        ..HookPayload::default()
+    };
+    let mut record = new_session_record(pid, None, &payload);
+    record.command = Some(command);
+    let path = sessions::record_path(registry::dir(), None, pid);
+    sessions::write_record(&path, &record).context("Failed to write PID file")?;
+    Ok(path)
+}
+
+fn unregister_process_session(pid: u32, source: &str) {
+    match call_resident("unregister", &control::Unregistration::Process { pid }) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            remove_process_session(pid);
+            sync_sleep_state_logged(source);
+        }
+        Err(e) => logging::log(&format!("[{}] Failed to unregister: {}", source, e)),
+    }
+}
+
+fn remove_process_session(pid: u32) {
+    let path = sessions::record_path(registry::dir(), None, pid);
+    if let Some(mut record) = sessions::read_record(&path) {
+        end_awake_stretch(&mut record, EndReason::Exited);
+    }
+    let _ = fs::remove_file(&path);
+}
+
+/// Sync for a caller with nobody to report a failure to; it still ends up
+/// in the log and the audit log.
+fn sync_sleep_state_logged(source: &str) {
+    if let Err(e) = sync_sleep_state(source, sleep_prevention_enabled_from_settings()) {
+        logging::log(&format!("[{}] Failed to sync sleep state: {}", source, e));
+    }
+}
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = FORWARD_SIGNALS_TO.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Pass signals sent to `asp run` on to its child. Ctrl+C and Ctrl+\ from a
+/// terminal already reach the child, so those are only ignored here.
+fn forward_signals_to(pid: u32) {
+    FORWARD_SIGNALS_TO.store(pid as i32, Ordering::SeqCst);
+    let from_terminal = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    unsafe {
+        for signal in [libc::SIGTERM, libc::SIGHUP, libc::SIGUSR1, libc::SIGUSR2] {
+            libc::signal(signal, forward_signal as *const () as libc::sighandler_t);
+        }
+        for signal in [libc::SIGINT, libc::SIGQUIT] {
+            let handler = if from_terminal {
+                libc::SIG_IGN
+            } else {
+                forward_signal as *const () as libc::sighandler_t
+            };
+            libc::signal(signal, handler);
+        }
+    }
+}
+
+/// Exit code a shell would report for `status`.
+fn exit_code(status: std::process::ExitStatus) -> i32 {
+    use std::os::unix::process::ExitStatusExt;
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+fn cmd_run(command: Vec<String>) -> Result<()> {
+    logging::init_quiet();
+    let Some((program, args)) = command.split_first() else {
+        anyhow::bail!("No command given");
+    };
+
+    let mut child = Command::new(program)
+        .args(args)
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+    // Installed after the spawn so the child keeps default dispositions.
+    forward_signals_to(child.id());
+
+    let registered = match register_process_session(child.id(), command.join(" "), "run") {
+        Ok(_) => true,
+        Err(e) => {
+            logging::log(&format!("[run] Could not keep the machine awake: {}", e));
+            false
+        }
+    };
+    let status = child.wait();
+    FORWARD_SIGNALS_TO.store(0, Ordering::SeqCst);
+    if registered {
+        unregister_process_session(child.id(), "run");
+    }
+
+    let status = status.with_context(|| format!("Failed to wait for {}", program))?;
+    std::process::exit(exit_code(status));
+}
+
+fn cmd_watch(pid: u32) -> Result<()> {
+    logging::init_quiet();
+    let Some(process) = process_table::native()
+        .process(pid)
+        .filter(|_| is_process_alive(pid))
+    else {
+        anyhow::bail!("No running process with PID {} that we can watch", pid);
+    };
+    let command = if process.argv.is_empty() {
+        process.comm.clone()
+    } else {
+        process.argv.join(" ")
+    };
+
+    let path = register_process_session(pid, command.clone(), "watch")?;
+    let record = sessions::read_record(&path).context("Session record disappeared")?;
+    unsafe {
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::signal(signal, request_stop as *const () as libc::sighandler_t);
+        }
+    }
+    println!(
+        "Keeping the machine awake until PID {} exits: {}",
+        pid, command
+    );
+
+    while !STOP_REQUESTED.load(Ordering::SeqCst) && is_session_alive(&record) {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    unregister_process_session(pid, "watch");
+    Ok(())
+}
+
+fn get_all_agent_processes() -> Vec<ProcessInfo> {
+    let processes = process_table::native().snapshot();
+    let rules = AgentRules::load(&AgentRules::path());
     let mut seen_pids = HashSet::new();
-    let mut agents = processes
+    let mut agents = rules
+        .detect(processes.iter())
         .into_iter()
-        .filter(|process| match classify_agent_process(process) {
-            Some(AgentKind::Claude) => true,
-            Some(AgentKind::Codex) => {
-                !(is_codex_wrapper_process(process) && codex_native_parents.contains(&process.pid))
-            }
-            None => false,
-        })
+        .map(|(process, _)| process)
         .filter(|process| seen_pids.insert(process.pid))
+        .cloned()
         .collect::<Vec<_>>();
     agents.sort_by_key(|process| process.pid);
     agents
@@ -524,9 +1558,9 @@ fn get_all_agent_pids() -> Vec<u32> {
 
 fn get_inactive_agent_pids() -> Vec<u32> {
     let all_pids = get_all_agent_pids();
-    let active_pids: HashSet<u32> = get_instance_items()
+    let active_pids: HashSet<u32> = load_active_sessions()
         .iter()
-        .map(|(pid, _, _, _)| *pid)
+        .map(|(_, record)| record.pid)
         .collect();
     all_pids
         .into_iter()
@@ -534,11 +1568,83 @@ fn get_inactive_agent_pids() -> Vec<u32> {
         .collect()
 }
 
-fn cmd_status() -> Result<()> {
-    let sleep_disabled = is_sleep_disabled();
+/// Where each agent's hooks stand: "installed", "not-installed",
+/// "needs-repair" (with what's wrong) or "agent-not-found".
+fn hook_statuses(home: &Path) -> Vec<(&'static str, PathBuf, &'static str, Option<String>)> {
+    let app_binary = installed_binary_path(home);
+    agent_config_dirs(home, &AgentDirArgs::default())
+        .targets()
+        .into_iter()
+        .map(|(integration, dir)| {
+            let (state, detail) = if !integration.is_installed(&dir) {
+                if integration.detect(&dir) {
+                    ("not-installed", None)
+                } else {
+                    ("agent-not-found", None)
+                }
+            } else {
+                match integration.verify(&dir, &app_binary) {
+                    Ok(()) => ("installed", None),
+                    Err(e) => ("needs-repair", Some(format!("{:#}", e))),
+                }
+            };
+            (integration.name(), dir, state, detail)
+        })
+        .collect()
+}
+
+fn cmd_status(json: bool) -> Result<()> {
+    let resident = resident_status();
+    let sleep_disabled = resident_sleep_disabled(resident.as_ref());
     let active_count = count_active_pids();
-    let thermal_warning = check_thermal_warning();
+    let thermal_warning = thermal_paused();
     let agent_count = count_agent_processes();
+    let resident_pid = resident.as_ref().and_then(|status| status["pid"].as_u64());
+    let policy = evaluate_policy(false);
+    let cap = settings::AppSettings::load().sleep_prevention.safety_cap;
+    let cap_deadline = CapState::load(&cap_state_path()).deadline(&cap, held_until());
+    let hooks = resolve_user_home()
+        .map(|home| hook_statuses(&home))
+        .unwrap_or_default();
+
+    if json {
+        let now = sessions::now_secs();
+        let report = json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "working_instances": active_count,
+            "agent_processes": agent_count,
+            "sleep_disabled": sleep_disabled,
+            "resident_pid": resident_pid,
+            "thermal": thermal::read().to_string(),
+            "thermal_paused": thermal_warning,
+            "power_source": power::source::read().to_string(),
+            "policy": {
+                "allow_prevention": policy.allow_prevention,
+                "summary": policy.summary(),
+            },
+            "safety_cap_secs_left": cap_deadline.map(|deadline| deadline.saturating_sub(now)),
+            // Every agent found on this machine has working hooks.
+            "hooks_installed": hooks.iter().any(|(_, _, state, _)| *state == "installed")
+                && hooks
+                    .iter()
+                    .all(|(_, _, state, _)| matches!(*state, "installed" | "agent-not-found")),
+            "hooks": hooks
+                .iter()
+                .map(|(agent, dir, state, detail)| json!({
+                    "agent": agent,
+                    "dir": dir,
+                    "state": state,
+                    "detail": detail,
+                }))
+                .collect::<Vec<_>>(),
+            "holds": load_active_holds()
+                .into_iter()
+                .map(|(_, hold)| hold)
+                .collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
 
     println!("Agents Sleep Preventer v{}", env!("CARGO_PKG_VERSION"));
     println!("==========================================");
@@ -548,25 +1654,94 @@ fn cmd_status() -> Result<()> {
         "Sleep disabled: {}",
         if sleep_disabled { "Yes" } else { "No" }
     );
+    match resident_pid {
+        Some(pid) => println!("Resident process: PID {} (control socket)", pid),
+        None => println!("Resident process: none, hooks update the registry themselves"),
+    }
+    println!(
+        "Thermal: {}{}",
+        thermal::read(),
+        if thermal_warning {
+            ", sleep prevention paused"
+        } else {
+            ""
+        }
+    );
+    println!("Power source: {}", power::source::read());
     println!(
-        "Thermal warning: {}",
-        if thermal_warning { "YES!" } else { "No" }
+        "Policy: {} ({})",
+        if policy.allow_prevention {
+            "prevention allowed"
+        } else {
+            "sleep allowed"
+        },
+        policy.summary()
     );
+    if let Some(deadline) = cap_deadline {
+        println!(
+            "Safety cap: {} min left",
+            deadline.saturating_sub(sessions::now_secs()).div_ceil(60)
+        );
+    }
+    if !hooks.is_empty() {
+        println!("\nHooks:");
+        for (agent, dir, state, detail) in &hooks {
+            let status = match (*state, detail) {
+                ("not-installed", _) => "not installed (run asp install)".to_string(),
+                ("agent-not-found", _) => "agent not found".to_string(),
+                ("needs-repair", Some(detail)) => {
+                    format!("needs repair: {} (run asp install)", detail)
+                }
+                (state, _) => state.to_string(),
+            };
+            println!("  {} ({}): {}", agent, dir.display(), status);
+        }
+    }
+    let holds = load_active_holds();
+    if !holds.is_empty() {
+        let now = sessions::now_secs();
+        println!("\nHolds:");
+        for (_, hold) in holds {
+            println!("  {}", hold.describe(now));
+        }
+    }
+    let expired = load_sessions()
+        .into_iter()
+        .filter(|(_, record)| record.state == SessionState::Expired)
+        .collect::<Vec<_>>();
+    if !expired.is_empty() {
+        println!("\nExpired sessions (asp extend to keep them awake):");
+        for (_, record) in expired {
+            println!(
+                "  PID {}: location={}, reason={}",
+                record.pid,
+                record.location(),
+                record.expired_reason.as_deref().unwrap_or("unknown")
+            );
+        }
+    }
 
     if active_count > 0 {
         println!("\nActive PIDs:");
-        if let Ok(entries) = fs::read_dir(PIDS_DIR) {
-            for entry in entries.filter_map(|e| e.ok()) {
-                let pid: u32 = entry.file_name().to_string_lossy().parse().unwrap_or(0);
-                if pid > 0 {
-                    let age = get_file_age(&entry.path()).unwrap_or(0);
-                    let cpu = get_process_cpu(pid);
-                    let alive = is_process_alive(pid);
-                    println!(
-                        "  PID {}: age={}s, cpu={:.1}%, alive={}",
-                        pid, age, cpu, alive
-                    );
-                }
+        let now = sessions::now_secs();
+        let processes = sample_processes();
+        for (_, record) in load_active_sessions() {
+            let cpu = processes.as_ref().map_or("n/a".to_string(), |processes| {
+                format!("{:.1}%", processes.tree_cpu(record.pid))
+            });
+            let alive = is_session_alive(&record);
+            println!(
+                "  PID {}: agent={}, state={}, age={}s, cpu={}, alive={}, location={}",
+                record.pid,
+                agent_kind_label(record.agent.as_ref()),
+                record.state.label(),
+                record.idle_secs(now),
+                cpu,
+                alive,
+                record.location()
+            );
+            if let Some(command) = &record.command {
+                println!("    command: {}", command);
             }
         }
     }
@@ -574,53 +1749,105 @@ fn cmd_status() -> Result<()> {
     Ok(())
 }
 
-fn cmd_list() -> Result<()> {
-    let active = get_instance_items()
+/// Sessions, holds and the sleep state, as printed by `asp list`.
+fn list_payload() -> serde_json::Value {
+    let now = sessions::now_secs();
+    let processes = sample_processes();
+    let active = load_active_sessions()
+        .into_iter()
+        .map(|(_, record)| {
+            json!({
+                "pid": record.pid,
+                "session_id": record.session_id,
+                "state": record.state,
+                "age_secs": record.idle_secs(now),
+                "cpu": processes.as_ref().map(|processes| processes.tree_cpu(record.pid)),
+                "location": record.location(),
+                "agent": record.agent,
+                "cwd": record.cwd,
+                "git_branch": record.git_branch,
+                "command": record.command,
+                "started_at": record.started_at,
+                "last_heartbeat": record.last_heartbeat,
+            })
+        })
+        .collect::<Vec<_>>();
+    let expired = load_sessions()
         .into_iter()
-        .map(|(pid, age, cpu, location)| {
+        .filter(|(_, record)| record.state == SessionState::Expired)
+        .map(|(_, record)| {
             json!({
-                "pid": pid,
-                "age_secs": age,
-                "cpu": cpu,
-                "location": location,
+                "pid": record.pid,
+                "session_id": record.session_id,
+                "location": record.location(),
+                "agent": record.agent,
+                "reason": record.expired_reason,
+            })
+        })
+        .collect::<Vec<_>>();
+    let holds = load_active_holds()
+        .into_iter()
+        .map(|(_, hold)| {
+            json!({
+                "id": hold.id,
+                "reason": hold.reason,
+                "expires_at": hold.expires_at,
+                "remaining_secs": hold.remaining_secs(now),
             })
         })
         .collect::<Vec<_>>();
     let inactive = get_inactive_agent_pids();
     let sleep_disabled = is_sleep_disabled();
-    let payload = json!({
+    let cap = settings::AppSettings::load().sleep_prevention.safety_cap;
+    json!({
         "active": active,
         "inactive": inactive,
+        "expired": expired,
+        "holds": holds,
         "sleep_disabled": sleep_disabled,
-    });
-    println!("{}", payload);
-    Ok(())
+        "cap_deadline": CapState::load(&cap_state_path()).deadline(&cap, held_until()),
+    })
 }
 
-fn get_file_age(path: &PathBuf) -> Option<u64> {
-    fs::metadata(path)
-        .ok()?
-        .modified()
-        .ok()?
-        .elapsed()
-        .ok()
-        .map(|d| d.as_secs())
-}
+fn cmd_list(watch: bool) -> Result<()> {
+    if !watch {
+        let payload = call_resident("list", &serde_json::Value::Null)?.unwrap_or_else(list_payload);
+        println!("{}", payload);
+        return Ok(());
+    }
 
-fn get_process_cpu(pid: u32) -> f32 {
-    Command::new("ps")
-        .args(["-p", &pid.to_string(), "-o", "%cpu="])
-        .output()
-        .ok()
-        .and_then(|o| String::from_utf8(o.stdout).ok())
-        .and_then(|s| s.trim().parse().ok())
-        .unwrap_or(0.0)
+    let path = control::socket_path();
+    let Some(changes) = control::subscribe(&path)? else {
+        anyhow::bail!(
+            "Nothing is listening on {}; --watch needs asp daemon or the menu bar app",
+            path.display()
+        );
+    };
+    for _ in changes {
+        if let Some(payload) = call_resident("list", &serde_json::Value::Null)? {
+            println!("{}", payload);
+        }
+    }
+    Ok(())
 }
 
 fn is_process_alive(pid: u32) -> bool {
     unsafe { libc::kill(pid as i32, 0) == 0 }
 }
 
+#[cfg(target_os = "linux")]
+fn is_lid_closed() -> bool {
+    fs::read_dir("/proc/acpi/button/lid")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|entry| {
+            fs::read_to_string(entry.path().join("state"))
+                .is_ok_and(|state| state.contains("closed"))
+        })
+}
+
+#[cfg(target_os = "macos")]
 fn is_lid_closed() -> bool {
     unsafe {
         let service_name = b"IOPMrootDomain\0";
@@ -653,18 +1880,43 @@ fn is_lid_closed() -> bool {
     }
 }
 
+#[cfg(target_os = "macos")]
 fn force_sleep_now() {
-    let _ = Command::new("sudo").args(["pmset", "sleepnow"]).output();
+    if let Err(e) = helper::request(helper::Request::Sleepnow) {
+        logging::log(&format!("[sleep] Failed to sleep now: {}", e));
+    }
 }
 
 fn enable_sleep_and_trigger_if_lid_closed() -> Result<()> {
     set_sleep_disabled(false)?;
+    // logind re-checks the lid itself once the inhibitor is released.
+    #[cfg(target_os = "macos")]
     if is_lid_closed() {
+        record_audit_event(
+            "sleep",
+            audit::EventKind::Lid,
+            "Lid is closed; sleeping now".to_string(),
+        );
         force_sleep_now();
     }
     Ok(())
 }
 
+/// Chime when the lid closes on working agents, so it's clear the machine
+/// stays awake.
+#[cfg(target_os = "macos")]
+fn on_lid_closed() {
+    let active = count_active_pids();
+    let detail = if active > 0 {
+        play_lid_close_sound();
+        format!("Lid closed with {} working sessions", active)
+    } else {
+        "Lid closed".to_string()
+    };
+    record_audit_event("lid", audit::EventKind::Lid, detail);
+}
+
+#[cfg(target_os = "macos")]
 fn play_lid_close_sound() {
     std::thread::spawn(|| {
         let current_vol = Command::new("osascript")
@@ -689,6 +1941,7 @@ fn play_lid_close_sound() {
     });
 }
 
+#[cfg(target_os = "macos")]
 unsafe extern "C" fn clamshell_notification_callback(
     _refcon: *mut std::ffi::c_void,
     _service: io_service_t,
@@ -703,6 +1956,7 @@ unsafe extern "C" fn clamshell_notification_callback(
     }
 }
 
+#[cfg(target_os = "macos")]
 fn start_clamshell_notifications() {
     std::thread::spawn(|| unsafe {
         let notify_port = IONotificationPortCreate(kIOMasterPortDefault);
@@ -764,536 +2018,724 @@ fn start_clamshell_notifications() {
 }
 
 fn cmd_cleanup() -> Result<()> {
-    if let Ok(entries) = fs::read_dir(PIDS_DIR) {
-        for entry in entries.filter_map(|e| e.ok()) {
-            let pid: u32 = match entry.file_name().to_string_lossy().parse() {
-                Ok(p) => p,
-                Err(_) => continue,
-            };
-
-            let path = entry.path();
-
-            if !is_process_alive(pid) {
-                let _ = fs::remove_file(&path);
-                continue;
-            }
-
-            let age = get_file_age(&path).unwrap_or(0);
-            if age >= IDLE_TIMEOUT_SECS {
-                let cpu = get_process_cpu(pid);
-                if cpu < IDLE_CPU_THRESHOLD {
-                    let _ = fs::remove_file(&path);
-                }
-            }
-        }
-    }
-
-    // Fix sleep state
-    let active = count_active_pids();
-    let sleep_disabled = is_sleep_disabled();
-
-    if active > 0 && !sleep_disabled {
-        set_sleep_disabled(true)?;
-    } else if active == 0 && sleep_disabled {
-        enable_sleep_and_trigger_if_lid_closed()?;
-    }
-
-    Ok(())
+    cleanup_stale_pids();
+    // Cleanup has always gone by sessions and holds alone, not the switch.
+    sync_sleep_state("cleanup", true)
 }
 
 fn cmd_reset() -> Result<()> {
-    let _ = fs::remove_dir_all(PIDS_DIR);
-    let _ = fs::create_dir_all(PIDS_DIR);
-    enable_sleep_and_trigger_if_lid_closed()?;
+    if call_resident("reset", &serde_json::Value::Null)?.is_none() {
+        let _registry = control::registry_lock();
+        reset_registry()?;
+    }
     println!("Reset complete. Sleep re-enabled.");
     Ok(())
 }
 
-fn cmd_thermal() -> Result<()> {
-    let warning = check_thermal_warning();
-    if warning {
-        println!("THERMAL WARNING DETECTED!");
-        // Force reset if thermal warning
-        cmd_reset()?;
-    } else {
-        println!("Thermal state: OK");
+/// Forget every session, hold and piece of cap or thermal state, and let
+/// the machine sleep. The control socket and its lock stay, so a running
+/// resident keeps serving.
+fn reset_registry() -> Result<()> {
+    for (_, mut record) in load_sessions() {
+        end_awake_stretch(&mut record, EndReason::Reset);
     }
-    Ok(())
-}
-
-fn cmd_daemon(interval: u64) -> Result<()> {
-    eprintln!(
-        "Daemon started (interval: {}s, thermal check: 30s)",
-        interval
-    );
-
-    let mut thermal_counter = 0u64;
-
-    loop {
-        // Cleanup every interval
-        let _ = cmd_cleanup();
-
-        // Thermal check every 30 seconds
-        thermal_counter += interval;
-        if thermal_counter >= 30 {
-            thermal_counter = 0;
-            if check_thermal_warning() {
-                eprintln!("Thermal warning! Forcing sleep re-enable.");
-                let _ = cmd_reset();
+    let socket = control::socket_path();
+    let lock = control::lock_path(&socket);
+    if let Ok(entries) = fs::read_dir(registry::dir()) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path == socket || path == lock {
+                continue;
             }
+            let _ = if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
         }
-
-        std::thread::sleep(Duration::from_secs(interval));
     }
+    let _ = ensure_pids_dir();
+    enable_sleep_and_trigger_if_lid_closed()
 }
 
-fn create_tray_title(count: usize, manual_enabled: bool) -> String {
-    if manual_enabled && count > 0 {
-        format!("☕ {}", count)
+fn cmd_extend(minutes: u64) -> Result<()> {
+    logging::init_quiet();
+    let extension = control::Extension { minutes };
+    let extended = match call_resident("extend", &extension)? {
+        Some(answer) => answer["extended"].as_u64().unwrap_or(0) as usize,
+        None => {
+            let _registry = control::registry_lock();
+            extend_sleep_prevention(minutes, "extend")?
+        }
+    };
+    if extended == 0 {
+        println!("No working or expired sessions to extend");
     } else {
-        "😴".to_string()
+        println!(
+            "Sleep prevention extended by {} min for {} sessions",
+            minutes, extended
+        );
     }
+    Ok(())
 }
 
-fn resolve_user_home() -> Result<PathBuf> {
-    if let Ok(sudo_user) = std::env::var("SUDO_USER") {
-        let sudo_user = sudo_user.trim();
-        if !sudo_user.is_empty() && sudo_user != "root" {
-            let user_record = format!("/Users/{}", sudo_user);
-            if let Ok(output) = Command::new("dscl")
-                .args([".", "-read", &user_record, "NFSHomeDirectory"])
-                .output()
-            {
-                if output.status.success() {
-                    let stdout = String::from_utf8_lossy(&output.stdout);
-                    for line in stdout.lines() {
-                        if let Some(home) = line.trim().strip_prefix("NFSHomeDirectory:") {
-                            let home = home.trim();
-                            if !home.is_empty() {
-                                return Ok(PathBuf::from(home));
-                            }
-                        }
-                    }
-                }
-            }
-            return Ok(PathBuf::from(user_record));
+/// Push every safety cap deadline at least `minutes` from now, reviving
+/// expired sessions. Returns how many sessions were extended.
+fn extend_sleep_prevention(minutes: u64, source: &str) -> Result<usize> {
+    ensure_pids_dir()?;
+    let now = sessions::now_secs();
+    let until = cap::extension_end(minutes, now)?;
+    let mut extended = 0;
+    for (path, mut record) in load_sessions() {
+        if record.state != SessionState::Expired && !record.state.prevents_sleep() {
+            continue;
         }
+        cap::extend(&mut record, until, now);
+        sessions::write_record(&path, &record)?;
+        extended += 1;
     }
 
-    dirs::home_dir().context("Could not find home directory")
+    let path = cap_state_path();
+    let mut state = CapState::load(&path);
+    state.extended_until = Some(state.extended_until.unwrap_or(0).max(until));
+    state.save(&path);
+    logging::log(&format!(
+        "[{}] Extended {} sessions by {} min",
+        source, extended, minutes
+    ));
+
+    sync_sleep_state(source, sleep_prevention_enabled_from_settings())?;
+    Ok(extended)
 }
 
-#[cfg(unix)]
-fn fix_user_ownership(path: &Path) {
-    let Ok(sudo_user) = std::env::var("SUDO_USER") else {
-        return;
+fn cmd_hold(duration: Option<String>, reason: Option<String>) -> Result<()> {
+    logging::init_quiet();
+    let Some(duration) = duration else {
+        anyhow::bail!("Say how long to hold, e.g. `asp hold --for 2h`");
     };
-    let sudo_user = sudo_user.trim();
-    if sudo_user.is_empty() || sudo_user == "root" {
-        return;
-    }
-    let Some(path) = path.to_str() else {
-        return;
+    let secs = holds::parse_duration(&duration)?;
+    let request = control::HoldRequest::Add {
+        secs,
+        reason: reason.clone(),
     };
-    let _ = Command::new("chown").args(["-R", sudo_user, path]).status();
-}
-
-fn toml_section_name(line: &str) -> Option<&str> {
-    let code = line.split('#').next().unwrap_or("").trim();
-    if !code.starts_with('[') || !code.ends_with(']') {
-        return None;
-    }
-    Some(code.trim_matches(&['[', ']'][..]).trim())
-}
-
-fn set_toml_feature_true(content: &str, feature: &str) -> String {
-    let mut lines = content.lines().map(ToOwned::to_owned).collect::<Vec<_>>();
-    let mut features_start = None;
-    let mut features_end = lines.len();
-
-    for (idx, line) in lines.iter().enumerate() {
-        let Some(section) = toml_section_name(line) else {
-            continue;
-        };
-        if section == "features" {
-            features_start = Some(idx);
-            features_end = lines.len();
-        } else if features_start.is_some() {
-            features_end = idx;
-            break;
-        }
-    }
-
-    if let Some(start) = features_start {
-        for line in lines.iter_mut().take(features_end).skip(start + 1) {
-            let code = line.split('#').next().unwrap_or("").trim_start();
-            if let Some(rest) = code.strip_prefix(feature) {
-                if rest.trim_start().starts_with('=') {
-                    let indent = line
-                        .chars()
-                        .take_while(|ch| ch.is_whitespace())
-                        .collect::<String>();
-                    *line = format!("{}{} = true", indent, feature);
-                    return format!("{}\n", lines.join("\n"));
-                }
-            }
+    let hold = match call_resident("hold", &request)? {
+        Some(hold) => {
+            serde_json::from_value(hold).context("Invalid hold from the resident process")?
         }
-        let mut insert_at = features_end;
-        while insert_at > start + 1
-            && lines
-                .get(insert_at - 1)
-                .map(|line| line.trim().is_empty())
-                .unwrap_or(false)
-        {
-            insert_at -= 1;
+        None => {
+            let _registry = control::registry_lock();
+            let hold = add_hold(secs, reason)?;
+            sync_sleep_state("hold", sleep_prevention_enabled_from_settings())?;
+            hold
         }
-        lines.insert(insert_at, format!("{} = true", feature));
-    } else {
-        if !lines.is_empty()
-            && lines
-                .last()
-                .map(|line| !line.trim().is_empty())
-                .unwrap_or(false)
-        {
-            lines.push(String::new());
-        }
-        lines.push("[features]".to_string());
-        lines.push(format!("{} = true", feature));
-    }
-
-    format!("{}\n", lines.join("\n"))
+    };
+    println!(
+        "Hold #{} keeps the machine awake for {} (release with `asp release {}`)",
+        hold.id,
+        holds::format_duration(secs),
+        hold.id
+    );
+    Ok(())
 }
 
-fn remove_toml_feature(content: &str, feature: &str) -> String {
-    let mut changed = false;
-    let mut in_features = false;
-    let mut lines = Vec::new();
-
-    for line in content.lines() {
-        if let Some(section) = toml_section_name(line) {
-            in_features = section == "features";
-        }
-
-        if in_features {
-            let code = line.split('#').next().unwrap_or("").trim_start();
-            if let Some(rest) = code.strip_prefix(feature) {
-                if rest.trim_start().starts_with('=') {
-                    changed = true;
-                    continue;
-                }
-            }
-        }
+fn add_hold(secs: u64, reason: Option<String>) -> Result<holds::Hold> {
+    ensure_pids_dir()?;
+    let now = sessions::now_secs();
+    let hold = holds::add_hold(registry::dir(), secs, reason, now)?;
+    logging::log(&format!("[hold] Added {}", hold.describe(now)));
+    Ok(hold)
+}
 
-        lines.push(line.to_string());
+fn cmd_hold_list() -> Result<()> {
+    let now = sessions::now_secs();
+    let holds = load_active_holds();
+    if holds.is_empty() {
+        println!("No active holds");
     }
-
-    if changed {
-        format!("{}\n", lines.join("\n"))
-    } else {
-        content.to_string()
+    for (_, hold) in holds {
+        println!("{}", hold.describe(now));
     }
+    Ok(())
 }
 
-fn set_codex_hooks_feature(content: &str) -> String {
-    let without_legacy = remove_toml_feature(content, "codex_hooks");
-    set_toml_feature_true(&without_legacy, "hooks")
-}
-
-fn enable_codex_hooks_feature(config_file: &Path) -> Result<()> {
-    let content = fs::read_to_string(config_file).unwrap_or_default();
-    let updated = set_codex_hooks_feature(&content);
-    if updated != content {
-        fs::write(config_file, updated)
-            .with_context(|| format!("Failed to write {}", config_file.display()))?;
+fn cmd_release(id: u32) -> Result<()> {
+    logging::init_quiet();
+    let request = control::HoldRequest::Release { release: id };
+    if call_resident("hold", &request)?.is_none() {
+        let _registry = control::registry_lock();
+        release_hold(id)?;
+        sync_sleep_state("release", sleep_prevention_enabled_from_settings())?;
     }
+    println!("Released hold #{}", id);
     Ok(())
 }
 
-fn hook_value_contains_owned_command(value: &serde_json::Value) -> bool {
-    match value {
-        serde_json::Value::String(text) => OWNED_HOOK_MARKERS
-            .iter()
-            .any(|marker| text.contains(marker)),
-        serde_json::Value::Array(values) => values.iter().any(hook_value_contains_owned_command),
-        serde_json::Value::Object(map) => map.values().any(hook_value_contains_owned_command),
-        _ => false,
+fn release_hold(id: u32) -> Result<()> {
+    let path = holds::hold_path(registry::dir(), id);
+    if !path.exists() {
+        anyhow::bail!("No hold #{} (see `asp hold list`)", id);
     }
+    fs::remove_file(&path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    logging::log(&format!("[hold] Released #{}", id));
+    Ok(())
 }
 
-fn remove_owned_hooks_from_group(group: &mut serde_json::Value) -> bool {
-    let Some(hooks) = group
-        .get_mut("hooks")
-        .and_then(serde_json::Value::as_array_mut)
-    else {
-        return false;
+/// Start of the `--since` window, if one was given.
+fn since_cutoff(since: Option<&str>) -> Result<Option<u64>> {
+    let Some(since) = since else {
+        return Ok(None);
     };
+    let secs = holds::parse_duration(since)?;
+    Ok(Some(sessions::now_secs().saturating_sub(secs)))
+}
 
-    let before = hooks.len();
-    hooks.retain(|hook| !hook_value_contains_owned_command(hook));
-    before != hooks.len()
+fn load_history(since: Option<&str>) -> Result<Vec<history::HistoryEntry>> {
+    let cutoff = since_cutoff(since)?.unwrap_or(0);
+    Ok(history::load(&history::path())
+        .into_iter()
+        .filter(|entry| entry.ended_at >= cutoff)
+        .collect())
 }
 
-fn remove_owned_hook_groups(hooks: &mut serde_json::Value) -> bool {
-    let Some(events) = hooks.as_object_mut() else {
-        return false;
-    };
+fn cmd_history(project: Option<String>, since: Option<String>, json: bool) -> Result<()> {
+    let entries = load_history(since.as_deref())?
+        .into_iter()
+        .filter(|entry| {
+            project
+                .as_deref()
+                .is_none_or(|project| entry.matches_project(project))
+        })
+        .collect::<Vec<_>>();
 
-    let mut changed = false;
-    for groups in events.values_mut() {
-        let Some(groups) = groups.as_array_mut() else {
-            continue;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    if entries.is_empty() {
+        println!("No sessions recorded yet");
+        return Ok(());
+    }
+
+    println!(
+        "{:<16}  {:>8}  {:>8}  {:<11}  {:<8}  PROJECT",
+        "STARTED", "AWAKE", "LID", "REASON", "AGENT"
+    );
+    for entry in &entries {
+        let location = match &entry.git_branch {
+            Some(branch) => format!("{} git:({})", entry.project(), branch),
+            None => entry.project().to_string(),
         };
+        println!(
+            "{:<16}  {:>8}  {:>8}  {:<11}  {:<8}  {}",
+            history::local_datetime(entry.started_at),
+            holds::format_duration(entry.duration_secs()),
+            holds::format_duration(entry.lid_closed_secs),
+            entry.end_reason.label(),
+            agent_kind_label(entry.agent.as_ref()),
+            entry.command.as_deref().unwrap_or(&location)
+        );
+    }
+    Ok(())
+}
 
-        for group in groups.iter_mut() {
-            if remove_owned_hooks_from_group(group) {
-                changed = true;
-            }
-        }
+fn cmd_stats(since: Option<String>, json: bool) -> Result<()> {
+    let entries = load_history(since.as_deref())?;
+    let stats = history::Stats::from_entries(&entries);
 
-        let before = groups.len();
-        groups.retain(|group| {
-            group
-                .get("hooks")
-                .and_then(serde_json::Value::as_array)
-                .map(|hooks| !hooks.is_empty())
-                .unwrap_or(true)
-        });
-        changed |= before != groups.len();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    if entries.is_empty() {
+        println!("No sessions recorded yet");
+        return Ok(());
     }
 
-    changed
+    println!("{:>8}  {:>8}  {:>9}  PROJECT", "AWAKE", "LID", "STRETCHES");
+    for project in &stats.projects {
+        println!(
+            "{:>8}  {:>8}  {:>9}  {}",
+            holds::format_duration(project.awake_secs),
+            holds::format_duration(project.lid_closed_secs),
+            project.stretches,
+            project.project
+        );
+    }
+    println!("\n{:>8}  {:>8}  DAY", "AWAKE", "LID");
+    for day in &stats.days {
+        println!(
+            "{:>8}  {:>8}  {}",
+            holds::format_duration(day.awake_secs),
+            holds::format_duration(day.lid_closed_secs),
+            day.date
+        );
+    }
+    Ok(())
 }
 
-fn prune_empty_hook_events(hooks: &mut serde_json::Value) {
-    if let Some(events) = hooks.as_object_mut() {
-        events.retain(|_, groups| {
-            groups
-                .as_array()
-                .map(|groups| !groups.is_empty())
-                .unwrap_or(true)
+fn cmd_why(json: bool) -> Result<()> {
+    let sleep_disabled = resident_sleep_disabled(resident_status().as_ref());
+    let records = load_sessions();
+    let manual_enabled = sleep_prevention_enabled_from_settings();
+    let now = sessions::now_secs();
+    let cap = settings::AppSettings::load().sleep_prevention.safety_cap;
+    let capped = cap
+        .total_exceeded(&CapState::load(&cap_state_path()), held_until(), now)
+        .or_else(|| {
+            records
+                .iter()
+                .filter(|(_, record)| record.state == SessionState::Expired)
+                .find_map(|(_, record)| record.expired_reason.clone())
+        });
+    let mut inputs = audit_inputs(&records, manual_enabled, None, capped.as_deref());
+    // Like `sync_sleep_state`, the policy only has a say when everything
+    // else wants sleep prevented.
+    if manual_enabled && inputs.wanted() && !inputs.thermal_paused {
+        let policy = evaluate_policy(false);
+        inputs.policy = Some(audit::PolicyInput {
+            allow_prevention: policy.allow_prevention,
+            summary: policy.summary(),
         });
     }
-}
-
-fn command_hook_group(command: &str, matcher: Option<&str>) -> serde_json::Value {
-    let mut group = json!({
-        "hooks": [
-            {
-                "type": "command",
-                "command": command,
-                "timeout": 5
-            }
-        ]
-    });
-    if let Some(matcher) = matcher {
-        group["matcher"] = json!(matcher);
+    let mut reasons = audit::explain(sleep_disabled, &inputs, now);
+    let last = audit::load_last(&registry::dir().join(AUDIT_STATE_FILE));
+    if let Some(failure) = last.and_then(|event| event.detail) {
+        reasons.push(format!("The last change didn't go through. {}", failure));
     }
-    group
-}
+    let recent = audit::recent(&audit::path(), 5);
 
-fn append_codex_hook_group(
-    hooks: &mut serde_json::Map<String, serde_json::Value>,
-    event_name: &str,
-    group: serde_json::Value,
-) {
-    let event = hooks
-        .entry(event_name.to_string())
-        .or_insert_with(|| json!([]));
-    if !event.is_array() {
-        *event = json!([]);
+    if json {
+        let report = json!({
+            "sleep_disabled": sleep_disabled,
+            "reasons": reasons,
+            "inputs": inputs,
+            "recent_events": recent,
+        });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
     }
-    if let Some(groups) = event.as_array_mut() {
-        groups.push(group);
+
+    println!(
+        "Sleep is {}",
+        if sleep_disabled {
+            "disabled"
+        } else {
+            "allowed"
+        }
+    );
+    for reason in &reasons {
+        println!("  {}", reason);
+    }
+    if !recent.is_empty() {
+        println!("\nRecent events ({}):", audit::path().display());
+        for event in &recent {
+            let what = match (event.action, &event.detail) {
+                (Some(action), Some(detail)) => format!("{} ({})", action.label(), detail),
+                (Some(action), None) => action.label().to_string(),
+                (None, Some(detail)) => detail.clone(),
+                (None, None) => String::new(),
+            };
+            println!(
+                "  {}  {:<8}  {}",
+                history::local_datetime(event.ts),
+                event.source,
+                what
+            );
+        }
     }
+    Ok(())
 }
 
-fn install_codex_hooks(home: &Path, app_binary: &str) -> Result<()> {
-    let codex_dir = home.join(".codex");
-    fs::create_dir_all(&codex_dir)
-        .with_context(|| format!("Failed to create {}", codex_dir.display()))?;
-
-    let config_file = codex_dir.join("config.toml");
-    enable_codex_hooks_feature(&config_file)?;
-
-    let hooks_file = codex_dir.join("hooks.json");
-    let mut hooks_json = if hooks_file.exists() {
-        let content = fs::read_to_string(&hooks_file)
-            .with_context(|| format!("Failed to read {}", hooks_file.display()))?;
-        serde_json::from_str::<serde_json::Value>(&content)
-            .with_context(|| format!("Failed to parse {}", hooks_file.display()))?
+fn cmd_thermal() -> Result<()> {
+    let paused = check_thermal_warning();
+    println!("Thermal state: {}", thermal::read());
+    if paused {
+        println!("Sleep prevention paused until the machine cools down");
     } else {
-        json!({})
-    };
-
-    if !hooks_json.is_object() {
-        hooks_json = json!({});
-    }
-    if !hooks_json
-        .get("hooks")
-        .map(serde_json::Value::is_object)
-        .unwrap_or(false)
-    {
-        hooks_json["hooks"] = json!({});
+        println!("Sleep prevention: allowed");
     }
+    Ok(())
+}
 
-    if let Some(hooks) = hooks_json.get_mut("hooks") {
-        remove_owned_hook_groups(hooks);
-        prune_empty_hook_events(hooks);
+/// Ask the resident process to run `method`. `Ok(None)` when nobody is
+/// listening, or when this is the resident process.
+fn call_resident(
+    method: &str,
+    params: &impl serde::Serialize,
+) -> Result<Option<serde_json::Value>> {
+    if RESIDENT_PROCESS.load(Ordering::SeqCst) {
+        return Ok(None);
     }
+    control::call(
+        &control::socket_path(),
+        method,
+        serde_json::to_value(params)?,
+    )
+}
 
-    let start_command =
-        format!("[ -x \"{app_binary}\" ] && \"{app_binary}\" start 2>/dev/null || true");
-    let stop_command =
-        format!("[ -x \"{app_binary}\" ] && \"{app_binary}\" stop 2>/dev/null || true");
-
-    let hooks = hooks_json
-        .get_mut("hooks")
-        .and_then(serde_json::Value::as_object_mut)
-        .context("Failed to prepare Codex hooks object")?;
-    append_codex_hook_group(
-        hooks,
-        "UserPromptSubmit",
-        command_hook_group(&start_command, None),
-    );
-    append_codex_hook_group(
-        hooks,
-        "PreToolUse",
-        command_hook_group(&start_command, Some("*")),
-    );
-    append_codex_hook_group(
-        hooks,
-        "PostToolUse",
-        command_hook_group(&start_command, Some("*")),
-    );
-    append_codex_hook_group(hooks, "Stop", command_hook_group(&stop_command, None));
-
-    fs::write(&hooks_file, serde_json::to_string_pretty(&hooks_json)?)
-        .with_context(|| format!("Failed to write {}", hooks_file.display()))?;
-
-    #[cfg(unix)]
-    fix_user_ownership(&codex_dir);
+/// Status from the resident process, if one is listening.
+fn resident_status() -> Option<serde_json::Value> {
+    call_resident("status", &serde_json::Value::Null)
+        .ok()
+        .flatten()
+}
+
+/// Whether sleep is disabled, asking the resident process first since it
+/// is the one holding the power backend.
+fn resident_sleep_disabled(status: Option<&serde_json::Value>) -> bool {
+    status
+        .and_then(|status| status["sleep_disabled"].as_bool())
+        .unwrap_or_else(is_sleep_disabled)
+}
+
+/// Serve the control socket, unless another resident process already does.
+fn start_control_server(source: &str) {
+    let path = control::socket_path();
+    let started = ensure_pids_dir()
+        .and_then(|_| control::Server::start(&path, handle_control, status_payload));
+    match started {
+        Ok(Some(server)) => {
+            let _ = CONTROL_SERVER.set(server);
+            logging::log(&format!("[{}] Listening on {}", source, path.display()));
+        }
+        Ok(None) => logging::log(&format!(
+            "[{}] Another process already serves {}",
+            source,
+            path.display()
+        )),
+        Err(e) => logging::log(&format!("[{}] No control socket: {:#}", source, e)),
+    }
+}
+
+/// Run a control socket call in the resident process.
+fn handle_control(call: control::Call) -> Result<serde_json::Value> {
+    use control::{Call, Extension, HoldRequest, Registration, Unregistration};
+
+    // The client's part is done once the registry is updated; a power
+    // backend failure is the resident process's to log. Hooks that change
+    // nothing skip the sleep sync and are left to the next tick.
+    let result = match call {
+        Call::Register(Registration::Hook {
+            event,
+            payload,
+            client_pid,
+        }) => {
+            let event = HookEvent::from_name(&event);
+            let outcome = apply_hook_event(&payload, &event, client_pid)?;
+            if !outcome.is_settled() {
+                sync_sleep_state_logged(&format!("hook-{}", event.name()));
+            }
+            json!({ "state": outcome.state })
+        }
+        Call::Register(Registration::Process { pid, command }) => {
+            add_process_session(pid, command, "register")?;
+            sync_sleep_state_logged("register");
+            json!({ "pid": pid })
+        }
+        Call::Unregister(Unregistration::Hook {
+            payload,
+            client_pid,
+        }) => {
+            let outcome = apply_hook_event(&payload, &HookEvent::Stop, client_pid)?;
+            if !outcome.is_settled() {
+                sync_sleep_state_logged("hook-stop");
+            }
+            json!({ "state": outcome.state })
+        }
+        Call::Unregister(Unregistration::Process { pid }) => {
+            remove_process_session(pid);
+            sync_sleep_state_logged("unregister");
+            json!({ "pid": pid })
+        }
+        Call::Heartbeat(heartbeat) => json!({ "state": touch_session(&heartbeat)? }),
+        Call::List => list_payload(),
+        Call::Status | Call::Subscribe => status_payload(),
+        Call::Hold(HoldRequest::Add { secs, reason }) => {
+            let hold = add_hold(secs, reason)?;
+            sync_sleep_state_logged("hold");
+            serde_json::to_value(hold)?
+        }
+        Call::Hold(HoldRequest::Release { release }) => {
+            release_hold(release)?;
+            sync_sleep_state_logged("release");
+            json!({ "released": release })
+        }
+        Call::Extend(Extension { minutes }) => {
+            json!({ "extended": extend_sleep_prevention(minutes, "extend")? })
+        }
+        Call::Reset => {
+            reset_registry()?;
+            json!({ "reset": true })
+        }
+    };
+    Ok(result)
+}
+
+/// Note that a session is still around, so it isn't parked as idle.
+fn touch_session(heartbeat: &control::Heartbeat) -> Result<SessionState> {
+    if heartbeat.pid.is_none() && heartbeat.session_id.is_none() {
+        anyhow::bail!("Give a pid or a session_id");
+    }
+    let Some((path, mut record)) = load_sessions().into_iter().find(|(_, record)| {
+        heartbeat.pid.is_none_or(|pid| record.pid == pid)
+            && heartbeat
+                .session_id
+                .as_deref()
+                .is_none_or(|id| record.session_id.as_deref() == Some(id))
+    }) else {
+        anyhow::bail!("No session matches {:?}", heartbeat);
+    };
+    record.last_heartbeat = sessions::now_secs();
+    sessions::write_record(&path, &record).context("Failed to write PID file")?;
+    Ok(record.state)
+}
 
-    println!("  Updated {}", config_file.display());
-    println!("  Updated {}", hooks_file.display());
+/// What subscribers are told about. Only fields that change with the
+/// sleep state, sessions or holds, so an unchanged status isn't resent.
+fn status_payload() -> serde_json::Value {
+    let sessions = load_active_sessions()
+        .into_iter()
+        .map(|(_, record)| {
+            json!({
+                "pid": record.pid,
+                "session_id": record.session_id,
+                "agent": record.agent,
+                "state": record.state,
+                "location": record.location(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let holds = load_active_holds()
+        .into_iter()
+        .map(|(_, hold)| hold)
+        .collect::<Vec<_>>();
+    let thermal = ThermalMonitor::load(&registry::dir().join(THERMAL_STATE_FILE));
+    json!({
+        "pid": std::process::id(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "sleep_disabled": is_sleep_disabled(),
+        "manual_enabled": sleep_prevention_enabled_from_settings(),
+        "thermal_paused": thermal.paused,
+        "sessions": sessions,
+        "holds": holds,
+    })
+}
 
-    Ok(())
+/// Tell subscribers about the current status if it changed.
+/// Tell subscribers about the status the caller left under `registry`.
+/// The status is read under the lock and sent after releasing it, so a
+/// slow subscriber doesn't hold up hooks.
+fn publish_status(registry: MutexGuard<'static, ()>) {
+    let Some(server) = CONTROL_SERVER.get() else {
+        return;
+    };
+    let status = status_payload();
+    drop(registry);
+    server.publish(status);
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn cmd_daemon(interval: u64) -> Result<()> {
+    RESIDENT_PROCESS.store(true, Ordering::SeqCst);
+    eprintln!("Daemon started (interval: {}s)", interval);
+    reconcile_sleep_state("daemon");
+    start_control_server("daemon");
 
-    #[test]
-    fn set_codex_hooks_feature_adds_current_flag() {
-        let updated = set_codex_hooks_feature("model = \"gpt-5.5\"\n");
+    loop {
+        {
+            let registry = control::registry_lock();
+            #[cfg(target_os = "macos")]
+            check_lease("daemon");
+            // Cleanup and sync sleep state (including thermal pauses) every interval
+            let _ = cmd_cleanup();
+            publish_status(registry);
+        }
 
-        assert_eq!(updated, "model = \"gpt-5.5\"\n\n[features]\nhooks = true\n");
+        std::thread::sleep(Duration::from_secs(interval));
     }
+}
 
-    #[test]
-    fn set_codex_hooks_feature_replaces_deprecated_flag() {
-        let config = "\
-model = \"gpt-5.5\"
-
-[features]
-unified_exec = true
-codex_hooks = true
-
-[plugins.github]
-enabled = true
-";
+#[cfg(target_os = "macos")]
+fn cmd_watchdog(interval: u64) -> Result<()> {
+    logging::init_quiet();
+    logging::log(&format!("[watchdog] Started (interval: {}s)", interval));
 
-        let updated = set_codex_hooks_feature(config);
+    loop {
+        check_lease("watchdog");
+        std::thread::sleep(Duration::from_secs(interval));
+    }
+}
 
-        assert!(!updated.contains("codex_hooks"));
-        assert!(updated.contains("[features]\nunified_exec = true\nhooks = true"));
-        assert!(updated.contains("[plugins.github]\nenabled = true"));
+#[cfg(target_os = "macos")]
+fn create_tray_title(count: usize, manual_enabled: bool) -> String {
+    if manual_enabled && count > 0 {
+        format!("☕ {}", count)
+    } else {
+        "😴".to_string()
     }
+}
 
-    #[test]
-    fn set_codex_hooks_feature_updates_existing_hooks_flag() {
-        let config = "\
-[features]
-hooks = false
-";
+#[cfg(target_os = "linux")]
+fn resolve_user_home() -> Result<PathBuf> {
+    if let Ok(sudo_user) = std::env::var("SUDO_USER") {
+        let sudo_user = sudo_user.trim();
+        if !sudo_user.is_empty() && sudo_user != "root" {
+            let name = std::ffi::CString::new(sudo_user)?;
+            let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+            if !passwd.is_null() {
+                let dir = unsafe { std::ffi::CStr::from_ptr((*passwd).pw_dir) };
+                return Ok(PathBuf::from(dir.to_string_lossy().to_string()));
+            }
+            return Ok(PathBuf::from("/home").join(sudo_user));
+        }
+    }
 
-        let updated = set_codex_hooks_feature(config);
+    dirs::home_dir().context("Could not find home directory")
+}
 
-        assert_eq!(updated, "[features]\nhooks = true\n");
+#[cfg(target_os = "macos")]
+fn resolve_user_home() -> Result<PathBuf> {
+    if let Ok(sudo_user) = std::env::var("SUDO_USER") {
+        let sudo_user = sudo_user.trim();
+        if !sudo_user.is_empty() && sudo_user != "root" {
+            let user_record = format!("/Users/{}", sudo_user);
+            if let Ok(output) = Command::new("dscl")
+                .args([".", "-read", &user_record, "NFSHomeDirectory"])
+                .output()
+            {
+                if output.status.success() {
+                    let stdout = String::from_utf8_lossy(&output.stdout);
+                    for line in stdout.lines() {
+                        if let Some(home) = line.trim().strip_prefix("NFSHomeDirectory:") {
+                            let home = home.trim();
+                            if !home.is_empty() {
+                                return Ok(PathBuf::from(home));
+                            }
+                        }
+                    }
+                }
+            }
+            return Ok(PathBuf::from(user_record));
+        }
     }
+
+    dirs::home_dir().context("Could not find home directory")
 }
 
-fn remove_codex_hooks(home: &Path) -> Result<bool> {
-    let hooks_file = home.join(".codex/hooks.json");
-    if !hooks_file.exists() {
-        return Ok(false);
+#[cfg(unix)]
+fn fix_user_ownership(path: &Path) {
+    let Ok(sudo_user) = std::env::var("SUDO_USER") else {
+        return;
+    };
+    let sudo_user = sudo_user.trim();
+    if sudo_user.is_empty() || sudo_user == "root" {
+        return;
     }
-
-    let content = fs::read_to_string(&hooks_file)
-        .with_context(|| format!("Failed to read {}", hooks_file.display()))?;
-    let Ok(mut hooks_json) = serde_json::from_str::<serde_json::Value>(&content) else {
-        eprintln!(
-            "Warning: could not parse {}, leaving it unchanged",
-            hooks_file.display()
-        );
-        return Ok(false);
+    let Some(path) = path.to_str() else {
+        return;
     };
+    let _ = Command::new("chown").args(["-R", sudo_user, path]).status();
+}
 
-    let changed = hooks_json
-        .get_mut("hooks")
-        .map(remove_owned_hook_groups)
-        .unwrap_or(false);
-    if !changed {
-        return Ok(false);
-    }
+/// `--claude-dir` and `--codex-dir` for `asp install` and `asp uninstall`
+#[derive(clap::Args, Debug, Default)]
+struct AgentDirArgs {
+    /// Claude Code config directory (repeat for several; default
+    /// $CLAUDE_CONFIG_DIR or ~/.claude)
+    #[arg(long = "claude-dir", value_name = "DIR")]
+    claude: Vec<PathBuf>,
+    /// Codex config directory (repeat for several; default $CODEX_HOME or
+    /// ~/.codex)
+    #[arg(long = "codex-dir", value_name = "DIR")]
+    codex: Vec<PathBuf>,
+}
 
-    if let Some(hooks) = hooks_json.get_mut("hooks") {
-        prune_empty_hook_events(hooks);
+impl AgentDirArgs {
+    fn overrides(&self) -> Vec<(&'static str, PathBuf)> {
+        let claude = self.claude.iter().map(|dir| ("claude", dir.clone()));
+        let codex = self.codex.iter().map(|dir| ("codex", dir.clone()));
+        claude.chain(codex).collect()
     }
+}
 
-    if let Some(root) = hooks_json.as_object_mut() {
-        let hooks_empty = root
-            .get("hooks")
-            .and_then(serde_json::Value::as_object)
-            .map(|hooks| hooks.is_empty())
-            .unwrap_or(false);
-        if hooks_empty {
-            root.remove("hooks");
-        }
-        if root.is_empty() {
-            fs::remove_file(&hooks_file)
-                .with_context(|| format!("Failed to remove {}", hooks_file.display()))?;
-            return Ok(true);
-        }
+/// Values `var` has for the user: in this process, and on macOS in their
+/// launchd session, which is what apps started from Finder or the Dock see.
+fn user_env_values(var: &str) -> Vec<String> {
+    #[allow(unused_mut)]
+    let mut values = std::env::var(var).ok().into_iter().collect::<Vec<_>>();
+    #[cfg(target_os = "macos")]
+    values.extend(launchd_getenv(var));
+    values
+}
+
+#[cfg(target_os = "macos")]
+fn launchd_getenv(var: &str) -> Option<String> {
+    let uid = installing_uid();
+    let mut command = Command::new("launchctl");
+    if unsafe { libc::getuid() } == 0 && uid != 0 {
+        command.args(["asuser", &uid.to_string(), "launchctl"]);
     }
+    let output = command.args(["getenv", var]).output().ok()?;
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !value.is_empty()).then_some(value)
+}
 
-    fs::write(&hooks_file, serde_json::to_string_pretty(&hooks_json)?)
-        .with_context(|| format!("Failed to write {}", hooks_file.display()))?;
+fn agent_config_dirs<'a>(home: &'a Path, args: &AgentDirArgs) -> integrations::ConfigDirs<'a> {
+    integrations::ConfigDirs {
+        home,
+        overrides: args.overrides(),
+        env: &user_env_values,
+    }
+}
 
-    Ok(true)
+/// Configure hooks in every config directory `ConfigDirs::wanted` picks.
+fn install_agent_hooks(home: &Path, args: &AgentDirArgs, app_binary: &str) -> Result<()> {
+    for (integration, dir) in agent_config_dirs(home, args).wanted() {
+        println!(
+            "Configuring {} hooks in {}...",
+            integration.name(),
+            dir.display()
+        );
+        integration.install(&dir, app_binary)?;
+    }
+    Ok(())
 }
 
-fn is_codex_hooks_installed(home: &Path) -> bool {
-    let hooks_file = home.join(".codex/hooks.json");
-    fs::read_to_string(hooks_file)
-        .ok()
-        .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
-        .map(|hooks_json| hook_value_contains_owned_command(&hooks_json))
-        .unwrap_or(false)
+fn remove_agent_hooks(home: &Path, args: &AgentDirArgs) -> Result<()> {
+    for (integration, dir) in agent_config_dirs(home, args).all_targets() {
+        if integration.uninstall(&dir)? {
+            println!(
+                "Removed {} hooks from {}",
+                integration.name(),
+                dir.display()
+            );
+        }
+    }
+    Ok(())
 }
 
-fn is_claude_hooks_installed(home: &Path) -> bool {
-    home.join(".claude/hooks/prevent-sleep.sh").exists()
+/// Where hooks expect to find `asp` for `home`.
+fn installed_binary_path(home: &Path) -> String {
+    #[cfg(target_os = "linux")]
+    return home.join(LINUX_BINARY_PATH).to_string_lossy().to_string();
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = home;
+        APP_BINARY_PATH.to_string()
+    }
 }
 
+#[cfg(target_os = "macos")]
 fn is_installed() -> bool {
     let home = resolve_user_home().unwrap_or_default();
-    is_claude_hooks_installed(&home) && is_codex_hooks_installed(&home)
+    agent_config_dirs(&home, &AgentDirArgs::default())
+        .wanted()
+        .iter()
+        .all(|(integration, dir)| integration.is_installed(dir))
 }
 
+#[cfg(target_os = "macos")]
 fn run_first_time_setup() -> Result<()> {
-    let message =
-        "Agents Sleep Preventer needs to be configured to work with Claude Code and Codex.
+    let message = "Agents Sleep Preventer needs to be configured to work with your coding agents.
 
 This will:
 • Install the CLI tool
@@ -1311,7 +2753,7 @@ Administrator password required.";
     match authorization::execute_script_with_privileges(script) {
         Ok(true) => {
             native_dialogs::show_dialog(
-                "Setup complete!\n\nRestart Claude Code or Codex to activate sleep prevention.",
+                "Setup complete!\n\nRestart your coding agents to activate sleep prevention.",
                 "Agents Sleep Preventer",
             );
             relaunch_app_after_install();
@@ -1327,6 +2769,7 @@ Administrator password required.";
     Ok(())
 }
 
+#[cfg(target_os = "macos")]
 fn relaunch_app_after_install() {
     logging::log("[main] Relaunching app after install...");
     match Command::new("open")
@@ -1345,16 +2788,12 @@ fn relaunch_app_after_install() {
     }
 }
 
+#[cfg(target_os = "macos")]
 fn get_process_tty(pid: u32) -> Option<String> {
-    Command::new("ps")
-        .args(["-p", &pid.to_string(), "-o", "tty="])
-        .output()
-        .ok()
-        .and_then(|o| String::from_utf8(o.stdout).ok())
-        .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty() && s != "??")
+    process_table::native().process(pid)?.tty
 }
 
+#[cfg(target_os = "macos")]
 fn focus_terminal_by_tty(tty: &str) {
     let tty_path = if tty.starts_with("/dev/") {
         tty.to_string()
@@ -1427,12 +2866,14 @@ fn focus_terminal_by_tty(tty: &str) {
         .output();
 }
 
+#[cfg(target_os = "macos")]
 fn focus_terminal_by_pid(pid: u32) {
     if let Some(tty) = get_process_tty(pid) {
         focus_terminal_by_tty(&tty);
     }
 }
 
+#[cfg(target_os = "macos")]
 fn cmd_focus(pid: u32) -> Result<()> {
     logging::init();
     logging::log(&format!("[focus] requested pid={}", pid));
@@ -1440,22 +2881,26 @@ fn cmd_focus(pid: u32) -> Result<()> {
     Ok(())
 }
 
+#[cfg(target_os = "macos")]
 fn get_instance_items() -> Vec<(u32, u64, f32, String)> {
-    let mut items = Vec::new();
-    if let Ok(entries) = fs::read_dir(PIDS_DIR) {
-        for entry in entries.filter_map(|e| e.ok()) {
-            let pid: u32 = entry.file_name().to_string_lossy().parse().unwrap_or(0);
-            if pid > 0 {
-                let age = get_file_age(&entry.path()).unwrap_or(0);
-                let cpu = get_process_cpu(pid);
-                let location = format_process_location(pid);
-                items.push((pid, age, cpu, location));
-            }
-        }
-    }
-    items
+    let now = sessions::now_secs();
+    let processes = sample_processes();
+    load_active_sessions()
+        .into_iter()
+        .map(|(_, record)| {
+            (
+                record.pid,
+                record.idle_secs(now),
+                processes
+                    .as_ref()
+                    .map_or(0.0, |processes| processes.tree_cpu(record.pid)),
+                record.location(),
+            )
+        })
+        .collect()
 }
 
+#[cfg(target_os = "macos")]
 fn quit_app() {
     unsafe {
         let app: objc_utils::Id = msg_send![class!(NSApplication), sharedApplication];
@@ -1466,10 +2911,12 @@ fn quit_app() {
     }
 }
 
+#[cfg(target_os = "macos")]
 fn cmd_menubar() -> Result<()> {
     // Initialize logging
     logging::init();
     logging::log("[main] Starting menubar app");
+    RESIDENT_PROCESS.store(true, Ordering::SeqCst);
 
     if !is_installed() {
         run_first_time_setup()?;
@@ -1485,6 +2932,8 @@ fn cmd_menubar() -> Result<()> {
         "[main] Loaded settings: sleep_prevention={}",
         app_settings.sleep_prevention.enabled
     ));
+    reconcile_sleep_state("main");
+    start_control_server("main");
 
     let mut event_loop = EventLoopBuilder::new().build();
     event_loop.set_activation_policy(ActivationPolicy::Accessory);
@@ -1501,6 +2950,9 @@ fn cmd_menubar() -> Result<()> {
     let settings_item = MenuItem::new("Settings...", true, None);
     let settings_item_id = settings_item.id().clone();
     let _ = minimal_menu.append(&settings_item);
+    let extend_item = MenuItem::new("Keep Awake 1 More Hour", true, None);
+    let extend_item_id = extend_item.id().clone();
+    let _ = minimal_menu.append(&extend_item);
     let quit_item = MenuItem::new("Quit", true, None);
     let quit_item_id = quit_item.id().clone();
     let _ = minimal_menu.append(&quit_item);
@@ -1545,22 +2997,14 @@ fn cmd_menubar() -> Result<()> {
             tick_counter += 1;
 
             if LID_JUST_CLOSED.swap(false, Ordering::SeqCst) {
-                let active = count_active_pids();
-                if active > 0 {
-                    play_lid_close_sound();
-                }
+                on_lid_closed();
             }
 
             if tick_counter % 100 == 0 {
+                let registry = control::registry_lock();
                 cleanup_stale_pids();
                 menubar_sync_sleep();
-            }
-
-            if tick_counter % 300 == 0 {
-                if check_thermal_warning() {
-                    let _ = set_sleep_disabled(false);
-                    logging::log("[thermal] Sleep re-enabled due to thermal warning");
-                }
+                publish_status(registry);
             }
 
             let _ = tick_proxy.send_event(());
@@ -1605,12 +3049,20 @@ fn cmd_menubar() -> Result<()> {
                     // Update manual sleep prevention based on settings
                     MANUAL_SLEEP_PREVENTION
                         .store(new_settings.sleep_prevention.enabled, Ordering::SeqCst);
+                    let registry = control::registry_lock();
                     menubar_sync_sleep();
+                    publish_status(registry);
                     logging::log(&format!(
                         "[menu] Settings saved: sleep_enabled={}",
                         new_settings.sleep_prevention.enabled
                     ));
                 }
+            } else if menu_event.id == extend_item_id {
+                logging::log("[menu] Extend selected");
+                let _registry = control::registry_lock();
+                if let Err(e) = extend_sleep_prevention(60, "menu") {
+                    logging::log(&format!("[menu] Failed to extend: {}", e));
+                }
             } else if menu_event.id == quit_item_id {
                 logging::log("[menu] Quit selected");
                 dictation_manager.stop();
@@ -1647,7 +3099,15 @@ fn cmd_menubar() -> Result<()> {
                         manual_enabled: MANUAL_SLEEP_PREVENTION.load(Ordering::SeqCst),
                         instances: get_instance_items(),
                         inactive: get_inactive_agent_pids(),
-                        thermal_warning: check_thermal_warning(),
+                        expired: load_sessions()
+                            .iter()
+                            .filter(|(_, record)| record.state == SessionState::Expired)
+                            .count(),
+                        holds: load_active_holds()
+                            .iter()
+                            .map(|(_, hold)| hold.describe(sessions::now_secs()))
+                            .collect(),
+                        thermal_warning: thermal_paused(),
                         dictation_enabled: dictation_manager.is_enabled(),
                         dictation_available: dictation_manager.is_available(),
                         sleep_disabled: is_sleep_disabled(),
@@ -1696,9 +3156,11 @@ fn cmd_menubar() -> Result<()> {
     });
 }
 
+#[cfg(target_os = "macos")]
 fn cmd_agent() -> Result<()> {
     logging::init();
     logging::log("[agent] Starting background agent");
+    RESIDENT_PROCESS.store(true, Ordering::SeqCst);
 
     let _agent_lock = match acquire_agent_lock() {
         Ok(Some(lock)) => lock,
@@ -1725,6 +3187,8 @@ fn cmd_agent() -> Result<()> {
         "[agent] Loaded settings: sleep_prevention={}",
         app_settings.sleep_prevention.enabled
     ));
+    reconcile_sleep_state("agent");
+    start_control_server("agent");
 
     start_clamshell_notifications();
 
@@ -1751,22 +3215,16 @@ fn cmd_agent() -> Result<()> {
 
         // Every 1s: cleanup stale PIDs and sync sleep state
         if tick_counter % 20 == 0 {
+            let registry = control::registry_lock();
             cleanup_stale_pids();
             menubar_sync_sleep();
+            publish_status(registry);
         }
 
-        // Every 3s: thermal check + lid close
+        // Every 3s: lid close (heat is handled by the sleep sync above)
         if tick_counter % 60 == 0 {
-            if check_thermal_warning() {
-                let _ = set_sleep_disabled(false);
-                logging::log("[agent][thermal] Sleep re-enabled due to thermal warning");
-            }
-
             if LID_JUST_CLOSED.swap(false, Ordering::SeqCst) {
-                let active = count_active_pids();
-                if active > 0 {
-                    play_lid_close_sound();
-                }
+                on_lid_closed();
             }
         }
 
@@ -1778,6 +3236,7 @@ fn cmd_agent() -> Result<()> {
     }
 }
 
+#[cfg(target_os = "macos")]
 fn acquire_agent_lock() -> Result<Option<std::fs::File>> {
     let lock_dir = dirs::data_local_dir()
         .unwrap_or_else(|| PathBuf::from("/tmp"))
@@ -1815,6 +3274,7 @@ fn ask_yes_no(prompt: &str) -> bool {
     answer.is_empty() || answer == "y" || answer == "yes"
 }
 
+#[cfg(target_os = "macos")]
 fn sync_installed_cli() -> Result<bool> {
     let current_exe = std::env::current_exe().context("Could not find current executable")?;
     fs::create_dir_all("/usr/local/bin")?;
@@ -1849,145 +3309,287 @@ fn sync_installed_cli() -> Result<bool> {
     Ok(updated)
 }
 
-fn cmd_install(auto_yes: bool) -> Result<()> {
-    let home = resolve_user_home()?;
-    let hooks_dir = home.join(".claude").join("hooks");
-    let settings_file = home.join(".claude").join("settings.json");
-    let launch_agents_dir = home.join("Library/LaunchAgents");
+/// Run a command directly when we're already root, through sudo otherwise.
+#[cfg(target_os = "macos")]
+fn run_as_root(is_root: bool, program: &str, args: &[&str]) -> Result<std::process::Output> {
+    let output = if is_root {
+        Command::new(program).args(args).output()
+    } else {
+        Command::new("sudo").arg(program).args(args).output()
+    };
+    output.with_context(|| format!("Failed to run {}", program))
+}
 
-    match sync_installed_cli() {
-        Ok(true) => println!("Updated /usr/local/bin/asp"),
-        Ok(false) => {}
-        Err(e) => eprintln!("Warning: could not update /usr/local/bin/asp: {}", e),
+/// The user `asp install` is installing for, even when run through sudo or
+/// from the package postinstall script.
+#[cfg(target_os = "macos")]
+fn installing_uid() -> u32 {
+    if let Some(uid) = std::env::var("SUDO_UID")
+        .ok()
+        .and_then(|uid| uid.parse().ok())
+    {
+        return uid;
     }
+    let uid = unsafe { libc::getuid() };
+    if uid != 0 {
+        return uid;
+    }
+    // Root without sudo: the package installer. Serve whoever is at the console.
+    let mut console: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::stat(c"/dev/console".as_ptr(), &mut console) } == 0 {
+        console.st_uid
+    } else {
+        0
+    }
+}
 
-    fs::create_dir_all(&hooks_dir)?;
-
-    let prevent_script = format!(
-        "#!/bin/bash\n[ -x \"{}\" ] && \"{}\" start 2>/dev/null || true\n",
-        APP_BINARY_PATH, APP_BINARY_PATH
-    );
-    let allow_script = format!(
-        "#!/bin/bash\n[ -x \"{}\" ] && \"{}\" stop 2>/dev/null || true\n",
-        APP_BINARY_PATH, APP_BINARY_PATH
+/// Copy `asp` to a root-owned location and load it as a LaunchDaemon running
+/// `asp helper`, replacing the old pmset sudoers rule.
+#[cfg(target_os = "macos")]
+fn install_privileged_helper(is_root: bool, uid: u32) -> Result<()> {
+    let current_exe = std::env::current_exe().context("Could not find current executable")?;
+    let current_exe = current_exe.to_string_lossy();
+    let plist_path = format!(
+        "/Library/LaunchDaemons/{}.plist",
+        helper::LAUNCH_DAEMON_LABEL
     );
 
-    fs::write(hooks_dir.join("prevent-sleep.sh"), prevent_script)?;
-    fs::write(hooks_dir.join("allow-sleep.sh"), allow_script)?;
-
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        fs::set_permissions(
-            hooks_dir.join("prevent-sleep.sh"),
-            fs::Permissions::from_mode(0o755),
-        )?;
-        fs::set_permissions(
-            hooks_dir.join("allow-sleep.sh"),
-            fs::Permissions::from_mode(0o755),
-        )?;
-
-        fix_user_ownership(&hooks_dir);
-    }
-
-    println!("Setting up passwordless sudo for pmset...");
-    // Get the real user (not root) for sudoers entry
-    let real_user = std::env::var("SUDO_USER")
-        .or_else(|_| std::env::var("USER"))
-        .unwrap_or_default();
-    let sudoers_content = format!("{} ALL=(ALL) NOPASSWD: /usr/bin/pmset\n", real_user);
+    run_as_root(is_root, "mkdir", &["-p", "/Library/PrivilegedHelperTools"])?;
+    run_as_root(is_root, "cp", &[&current_exe, helper::HELPER_BINARY_PATH])?;
+    run_as_root(
+        is_root,
+        "chown",
+        &["root:wheel", helper::HELPER_BINARY_PATH],
+    )?;
+    run_as_root(is_root, "chmod", &["755", helper::HELPER_BINARY_PATH])?;
+
+    let plist = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{binary}</string>
+        <string>helper</string>
+        <string>--allow-uid</string>
+        <string>{uid}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardErrorPath</key>
+    <string>/Library/Logs/AgentsSleepPreventer-helper.log</string>
+</dict>
+</plist>
+"#,
+        label = helper::LAUNCH_DAEMON_LABEL,
+        binary = helper::HELPER_BINARY_PATH,
+        uid = uid,
+    );
 
-    // Write directly if we're root, otherwise use sudo
-    let is_root = unsafe { libc::geteuid() == 0 };
     let mut child = if is_root {
         Command::new("tee")
-            .arg("/etc/sudoers.d/agents-pmset")
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::null())
-            .spawn()?
     } else {
-        Command::new("sudo")
-            .args(["tee", "/etc/sudoers.d/agents-pmset"])
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::null())
-            .spawn()?
-    };
-
+        let mut sudo = Command::new("sudo");
+        sudo.arg("tee");
+        sudo
+    }
+    .arg(&plist_path)
+    .stdin(std::process::Stdio::piped())
+    .stdout(std::process::Stdio::null())
+    .spawn()?;
     if let Some(stdin) = child.stdin.as_mut() {
-        stdin.write_all(sudoers_content.as_bytes())?;
+        stdin.write_all(plist.as_bytes())?;
     }
     child.wait()?;
+    run_as_root(is_root, "chmod", &["644", &plist_path])?;
 
-    if is_root {
-        Command::new("chmod")
-            .args(["440", "/etc/sudoers.d/agents-pmset"])
-            .output()?;
-        let _ = Command::new("rm")
-            .args(["-f", "/etc/sudoers.d/claude-pmset"])
-            .output();
-    } else {
-        Command::new("sudo")
-            .args(["chmod", "440", "/etc/sudoers.d/agents-pmset"])
-            .output()?;
-        let _ = Command::new("sudo")
-            .args(["rm", "-f", "/etc/sudoers.d/claude-pmset"])
-            .output();
+    let service = format!("system/{}", helper::LAUNCH_DAEMON_LABEL);
+    let _ = run_as_root(is_root, "launchctl", &["bootout", &service]);
+    let loaded = run_as_root(is_root, "launchctl", &["bootstrap", "system", &plist_path])?;
+    if !loaded.status.success() {
+        eprintln!(
+            "Warning: could not load the privileged helper: {}",
+            String::from_utf8_lossy(&loaded.stderr).trim()
+        );
     }
 
-    println!("Configuring Claude Code hooks...");
+    // The helper replaces the blanket pmset sudoers rule from older versions.
+    let _ = run_as_root(
+        is_root,
+        "rm",
+        &[
+            "-f",
+            "/etc/sudoers.d/agents-pmset",
+            "/etc/sudoers.d/claude-pmset",
+        ],
+    );
+
+    Ok(())
+}
 
-    let prevent_path = hooks_dir.join("prevent-sleep.sh");
-    let allow_path = hooks_dir.join("allow-sleep.sh");
-    let hooks_json = format!(
-        r#"{{
-    "UserPromptSubmit": [{{ "hooks": [{{ "type": "command", "command": "{prevent}" }}] }}],
-    "PreToolUse": [{{ "hooks": [{{ "type": "command", "command": "{prevent}" }}] }}],
-    "PreCompact": [{{ "hooks": [{{ "type": "command", "command": "{prevent}" }}] }}],
-    "Stop": [{{ "hooks": [{{ "type": "command", "command": "{allow}" }}] }}]
-}}"#,
-        prevent = prevent_path.display(),
-        allow = allow_path.display(),
+/// Load a LaunchAgent running `asp watchdog` from the helper's copy of
+/// `asp`, so sleep comes back even if the app is gone or crashed.
+#[cfg(target_os = "macos")]
+fn install_watchdog(launch_agents_dir: &Path, uid: u32) -> Result<()> {
+    fs::create_dir_all(launch_agents_dir)?;
+    let plist = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{binary}</string>
+        <string>watchdog</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+</dict>
+</plist>
+"#,
+        label = WATCHDOG_LAUNCH_AGENT_LABEL,
+        binary = helper::HELPER_BINARY_PATH,
     );
+    let plist_path = launch_agents_dir.join(format!("{}.plist", WATCHDOG_LAUNCH_AGENT_LABEL));
+    fs::write(&plist_path, plist)
+        .with_context(|| format!("Failed to write {}", plist_path.display()))?;
+    #[cfg(unix)]
+    fix_user_ownership(&plist_path);
 
-    let hooks: serde_json::Value =
-        serde_json::from_str(&hooks_json).context("Failed to parse hooks JSON")?;
-
-    if settings_file.exists() {
-        let content = fs::read_to_string(&settings_file)
-            .with_context(|| format!("Failed to read {}", settings_file.display()))?;
-        let mut json: serde_json::Value = serde_json::from_str(&content)
-            .with_context(|| format!("Failed to parse {}", settings_file.display()))?;
-        json["hooks"] = hooks;
-        fs::write(&settings_file, serde_json::to_string_pretty(&json)?)
-            .with_context(|| format!("Failed to write {}", settings_file.display()))?;
-        println!("  Updated {}", settings_file.display());
-    } else {
-        fs::create_dir_all(settings_file.parent().unwrap())?;
-        let mut json = serde_json::json!({});
-        json["hooks"] = hooks;
-        fs::write(&settings_file, serde_json::to_string_pretty(&json)?)
-            .with_context(|| format!("Failed to write {}", settings_file.display()))?;
-        println!("  Created {}", settings_file.display());
+    let domain = format!("gui/{}", uid);
+    let service = format!("{}/{}", domain, WATCHDOG_LAUNCH_AGENT_LABEL);
+    let _ = Command::new("launchctl")
+        .args(["bootout", &service])
+        .output();
+    let loaded = Command::new("launchctl")
+        .arg("bootstrap")
+        .arg(&domain)
+        .arg(&plist_path)
+        .output()?;
+    if !loaded.status.success() {
+        eprintln!(
+            "Warning: could not load the watchdog: {}",
+            String::from_utf8_lossy(&loaded.stderr).trim()
+        );
     }
+    Ok(())
+}
 
-    #[cfg(unix)]
-    fix_user_ownership(&settings_file);
+#[cfg(target_os = "macos")]
+fn uninstall_privileged_helper(is_root: bool) {
+    let plist_path = format!(
+        "/Library/LaunchDaemons/{}.plist",
+        helper::LAUNCH_DAEMON_LABEL
+    );
+    let service = format!("system/{}", helper::LAUNCH_DAEMON_LABEL);
+    let _ = run_as_root(is_root, "launchctl", &["bootout", &service]);
+    let _ = run_as_root(
+        is_root,
+        "rm",
+        &[
+            "-f",
+            &plist_path,
+            helper::HELPER_BINARY_PATH,
+            helper::SOCKET_PATH,
+        ],
+    );
+}
 
-    println!("Configuring Codex hooks...");
-    install_codex_hooks(&home, APP_BINARY_PATH)?;
+#[cfg(target_os = "macos")]
+fn power_snapshot_path(home: &Path) -> PathBuf {
+    home.join("Library/Application Support/AgentsSleepPreventer/power-snapshot.json")
+}
 
-    if is_root {
-        Command::new("pmset").args(["-a", "sleep", "5"]).output()?;
-        Command::new("pmset")
-            .args(["-a", "disablesleep", "0"])
-            .output()?;
-    } else {
-        Command::new("sudo")
-            .args(["pmset", "-a", "sleep", "5"])
-            .output()?;
-        Command::new("sudo")
-            .args(["pmset", "-a", "disablesleep", "0"])
-            .output()?;
+/// Save the user's pmset settings, unless an earlier install already did:
+/// by now they'd include our own changes.
+#[cfg(target_os = "macos")]
+fn save_power_snapshot(home: &Path) -> Result<bool> {
+    let path = power_snapshot_path(home);
+    if PowerSnapshot::load(&path)?.is_some() {
+        return Ok(false);
+    }
+    let snapshot = power::snapshot::capture()?;
+    if snapshot.is_empty() {
+        return Ok(false);
+    }
+    snapshot.save(&path)?;
+    fix_user_ownership(&path);
+    Ok(true)
+}
+
+#[cfg(target_os = "macos")]
+fn restore_power_settings(home: &Path) -> Result<bool> {
+    let Some(snapshot) = PowerSnapshot::load(&power_snapshot_path(home))? else {
+        return Ok(false);
+    };
+    for args in snapshot.restore_args() {
+        helper::request(helper::Request::RestorePowerSettings { args })?;
+    }
+    Ok(true)
+}
+
+#[cfg(target_os = "macos")]
+fn cmd_power(action: PowerAction) -> Result<()> {
+    let home = resolve_user_home()?;
+    match action {
+        PowerAction::Restore => {
+            if restore_power_settings(&home)? {
+                println!(
+                    "Restored power settings from {}",
+                    power_snapshot_path(&home).display()
+                );
+            } else {
+                println!("No saved power settings to restore");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn cmd_install(auto_yes: bool, dirs: &AgentDirArgs) -> Result<()> {
+    let home = resolve_user_home()?;
+    let hooks_dir = home.join(".claude").join("hooks");
+    let launch_agents_dir = home.join("Library/LaunchAgents");
+
+    match sync_installed_cli() {
+        Ok(true) => println!("Updated /usr/local/bin/asp"),
+        Ok(false) => {}
+        Err(e) => eprintln!("Warning: could not update /usr/local/bin/asp: {}", e),
+    }
+
+    // Hooks now call `asp hook <event>` directly; drop the old wrapper scripts.
+    let _ = fs::remove_file(hooks_dir.join("prevent-sleep.sh"));
+    let _ = fs::remove_file(hooks_dir.join("allow-sleep.sh"));
+
+    println!("Installing privileged helper for pmset...");
+    let is_root = unsafe { libc::geteuid() == 0 };
+    let uid = installing_uid();
+    install_privileged_helper(is_root, uid)?;
+
+    println!("Installing sleep watchdog...");
+    install_watchdog(&launch_agents_dir, uid)?;
+
+    install_agent_hooks(&home, dirs, APP_BINARY_PATH)?;
+
+    match save_power_snapshot(&home) {
+        Ok(true) => println!("Saved your power settings (restore with `asp power restore`)"),
+        Ok(false) => {}
+        Err(e) => eprintln!("Warning: could not save power settings: {}", e),
+    }
+
+    if let Err(e) = helper::request_after_start(helper::Request::SetDisablesleep { enabled: false })
+    {
+        eprintln!("Warning: could not reset disablesleep: {}", e);
     }
 
     println!();
@@ -2020,7 +3622,7 @@ fn cmd_install(auto_yes: bool) -> Result<()> {
     }
 
     println!("\n✅ Installation complete!");
-    println!("\nRestart Claude Code or Codex to activate.");
+    println!("\nRestart your coding agents to activate.");
     println!("\nCommands:");
     println!("  asp status   - Show current state");
     println!("  asp cleanup  - Clean up stale PIDs");
@@ -2036,10 +3638,15 @@ fn cmd_install(auto_yes: bool) -> Result<()> {
     Ok(())
 }
 
-fn cmd_uninstall(keep_model: bool, keep_hooks: bool, keep_data: bool) -> Result<()> {
+#[cfg(target_os = "macos")]
+fn cmd_uninstall(
+    keep_model: bool,
+    keep_hooks: bool,
+    keep_data: bool,
+    dirs: &AgentDirArgs,
+) -> Result<()> {
     let home = resolve_user_home()?;
     let hooks_dir = home.join(".claude").join("hooks");
-    let settings_file = home.join(".claude").join("settings.json");
     let launch_agents_dir = home.join("Library/LaunchAgents");
 
     // Remove hook scripts (unless keeping hooks)
@@ -2047,29 +3654,17 @@ fn cmd_uninstall(keep_model: bool, keep_hooks: bool, keep_data: bool) -> Result<
         let _ = fs::remove_file(hooks_dir.join("prevent-sleep.sh"));
         let _ = fs::remove_file(hooks_dir.join("allow-sleep.sh"));
 
-        // Remove hooks from settings.json
-        if settings_file.exists() {
-            if let Ok(content) = fs::read_to_string(&settings_file) {
-                if let Ok(mut json) = serde_json::from_str::<serde_json::Value>(&content) {
-                    if json.get("hooks").is_some() {
-                        json.as_object_mut().unwrap().remove("hooks");
-                        let _ =
-                            fs::write(&settings_file, serde_json::to_string_pretty(&json).unwrap());
-                        println!("Removed hooks from settings.json");
-                    }
-                }
-            }
-        }
-        if remove_codex_hooks(&home)? {
-            println!("Removed Codex hooks");
-        }
+        // Remove our hooks from each agent's config, keeping everyone else's
+        remove_agent_hooks(&home, dirs)?;
         println!("Removed coding agent hooks");
     }
 
     // Remove LaunchAgents
+    let watchdog_plist = format!("{}.plist", WATCHDOG_LAUNCH_AGENT_LABEL);
     for label in [
         "com.charlontank.agents-sleep-preventer.plist",
         "com.charlontank.claude-sleep-preventer.plist",
+        watchdog_plist.as_str(),
     ] {
         let plist_path = launch_agents_dir.join(label);
         if plist_path.exists() {
@@ -2081,21 +3676,23 @@ fn cmd_uninstall(keep_model: bool, keep_hooks: bool, keep_data: bool) -> Result<
         }
     }
 
-    // Remove sudoers config
-    for sudoers_path in ["/etc/sudoers.d/agents-pmset", "/etc/sudoers.d/claude-pmset"] {
-        Command::new("sudo")
-            .args(["rm", "-f", sudoers_path])
-            .output()?;
-    }
+    // Reset sleep settings while the helper is still around
+    let _ = helper::request(helper::Request::SetDisablesleep { enabled: false });
 
-    // Remove PID tracking directory
-    let _ = fs::remove_dir_all(PIDS_DIR);
-    let _ = fs::remove_dir_all(LEGACY_PIDS_DIR);
+    match restore_power_settings(&home) {
+        Ok(true) => println!("Restored your power settings"),
+        Ok(false) => {}
+        Err(e) => eprintln!("Warning: could not restore power settings: {}", e),
+    }
+    let is_root = unsafe { libc::geteuid() == 0 };
+    uninstall_privileged_helper(is_root);
+    println!("Removed privileged helper");
 
-    // Reset sleep settings
-    Command::new("sudo")
-        .args(["pmset", "-a", "disablesleep", "0"])
-        .output()?;
+    // Remove the session registry, and the shared one older versions used
+    let _ = fs::remove_dir_all(registry::dir_in(&home.join("Library/Application Support")));
+    for legacy in registry::LEGACY_DIRS {
+        let _ = fs::remove_dir_all(legacy);
+    }
 
     // Remove app data and preferences (unless keeping data)
     if !keep_data {
@@ -2134,15 +3731,19 @@ fn cmd_uninstall(keep_model: bool, keep_hooks: bool, keep_data: bool) -> Result<
     }
 
     // Remove the app from /Applications
-    Command::new("sudo")
-        .args([
-            "rm",
-            "-rf",
-            "/Applications/AgentsSleepPreventer.app",
-            "/Applications/ClaudeSleepPreventer.app",
-        ])
-        .output()?;
-    println!("Removed app from /Applications");
+    for app in [
+        "/Applications/AgentsSleepPreventer.app",
+        "/Applications/ClaudeSleepPreventer.app",
+    ] {
+        match fs::remove_dir_all(app) {
+            Ok(()) => println!("Removed {}", app),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!(
+                "Warning: could not remove {} ({}); move it to the Trash",
+                app, e
+            ),
+        }
+    }
 
     let _ = fs::remove_file("/usr/local/bin/asp");
     let _ = fs::remove_file("/usr/local/bin/agents-sleep-preventer");
@@ -2153,6 +3754,123 @@ fn cmd_uninstall(keep_model: bool, keep_hooks: bool, keep_data: bool) -> Result<
     Ok(())
 }
 
+#[cfg(target_os = "linux")]
+fn cmd_install(auto_yes: bool, dirs: &AgentDirArgs) -> Result<()> {
+    let home = resolve_user_home()?;
+    let binary = home.join(LINUX_BINARY_PATH);
+    let binary_path = binary.to_string_lossy().to_string();
+
+    let current_exe = std::env::current_exe().context("Could not find current executable")?;
+    if current_exe != binary {
+        if let Some(parent) = binary.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(&current_exe, &binary).with_context(|| {
+            format!(
+                "Failed to copy {} to {}",
+                current_exe.display(),
+                binary_path
+            )
+        })?;
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755))?;
+        println!("Installed {}", binary_path);
+    }
+
+    install_agent_hooks(&home, dirs, &binary_path)?;
+
+    println!();
+    if auto_yes || ask_yes_no("Run the daemon at login (systemd user service)?") {
+        let unit_dir = home.join(".config/systemd/user");
+        fs::create_dir_all(&unit_dir)?;
+        let unit = format!(
+            "[Unit]\n\
+             Description=Agents Sleep Preventer\n\
+             \n\
+             [Service]\n\
+             ExecStart={} daemon\n\
+             Restart=on-failure\n\
+             \n\
+             [Install]\n\
+             WantedBy=default.target\n",
+            binary_path
+        );
+        fs::write(unit_dir.join(SYSTEMD_UNIT_NAME), unit)?;
+
+        let _ = Command::new("systemctl")
+            .args(["--user", "daemon-reload"])
+            .output();
+        let enabled = Command::new("systemctl")
+            .args(["--user", "enable", "--now", SYSTEMD_UNIT_NAME])
+            .output()
+            .map(|o| o.status.success())
+            .unwrap_or(false);
+        if enabled {
+            println!("  Enabled {}", SYSTEMD_UNIT_NAME);
+        } else {
+            println!(
+                "  Created {}; enable it with: systemctl --user enable --now {}",
+                SYSTEMD_UNIT_NAME, SYSTEMD_UNIT_NAME
+            );
+        }
+    }
+
+    println!("\n✅ Installation complete!");
+    println!("\nRestart your coding agents to activate.");
+    println!("\nCommands:");
+    println!("  asp status   - Show current state");
+    println!("  asp cleanup  - Clean up stale PIDs");
+    println!("  asp reset    - Release the sleep inhibitor");
+    println!("  asp daemon   - Run background daemon");
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn cmd_uninstall(
+    keep_model: bool,
+    keep_hooks: bool,
+    keep_data: bool,
+    dirs: &AgentDirArgs,
+) -> Result<()> {
+    let _ = keep_model;
+    let home = resolve_user_home()?;
+
+    if !keep_hooks {
+        remove_agent_hooks(&home, dirs)?;
+        println!("Removed coding agent hooks");
+    }
+
+    let unit_path = home.join(".config/systemd/user").join(SYSTEMD_UNIT_NAME);
+    if unit_path.exists() {
+        let _ = Command::new("systemctl")
+            .args(["--user", "disable", "--now", SYSTEMD_UNIT_NAME])
+            .output();
+        let _ = fs::remove_file(&unit_path);
+        let _ = Command::new("systemctl")
+            .args(["--user", "daemon-reload"])
+            .output();
+        println!("Removed {}", SYSTEMD_UNIT_NAME);
+    }
+
+    let _ = fs::remove_dir_all(registry::dir());
+    for legacy in registry::LEGACY_DIRS {
+        let _ = fs::remove_dir_all(legacy);
+    }
+
+    if !keep_data {
+        let _ = fs::remove_dir_all(logging::log_dir());
+        println!("Removed logs");
+    }
+
+    let _ = fs::remove_file(home.join(LINUX_BINARY_PATH));
+
+    println!("Uninstalled successfully");
+
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
 fn cmd_settings() -> Result<()> {
     logging::init();
     logging::log("[settings] Opening settings window");
@@ -2175,40 +3893,146 @@ fn cmd_settings() -> Result<()> {
 }
 
 fn cmd_debug() -> Result<()> {
+    let rules = AgentRules::load(&AgentRules::path());
+    let names = rules
+        .agents()
+        .map(|agent| agent.as_str().to_lowercase())
+        .collect::<HashSet<_>>();
+    let mentions_agent = |text: &str| names.iter().any(|name| text.contains(name.as_str()));
+
+    println!("Agent rules: {}", AgentRules::path().display());
     println!("sysinfo processes:");
     let sys = System::new_all();
     for (pid, proc) in sys.processes() {
         let name = proc.name().to_string_lossy();
         let lower = name.to_lowercase();
-        if lower.contains("claude") || lower.contains("codex") {
+        if mentions_agent(&lower) {
             println!("  PID {}: name={:?}", pid.as_u32(), name);
         }
     }
 
-    println!("\nps command:");
-    let output = Command::new("ps")
-        .args(["-eo", "pid=,ppid=,comm=,args="])
-        .output()?;
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    for line in stdout.lines() {
-        let lower = line.to_lowercase();
-        if lower.contains("claude") || lower.contains("codex") {
-            println!("  {}", line.trim());
+    println!("\nProcess table:");
+    for process in process_table::native().snapshot().iter() {
+        let args = process.argv.join(" ");
+        let lower = format!("{} {}", process.comm, args).to_lowercase();
+        if mentions_agent(&lower) {
+            println!(
+                "  PID {}: ppid={}, comm={}, args={}",
+                process.pid, process.ppid, process.comm, args
+            );
         }
     }
 
     println!("\nDetected agent PIDs:");
-    for process in get_all_agent_processes() {
-        let kind = match classify_agent_process(&process) {
-            Some(AgentKind::Claude) => "claude",
-            Some(AgentKind::Codex) => "codex",
-            None => "unknown",
-        };
+    let processes = process_table::native().snapshot();
+    for (process, rule) in rules.detect(processes.iter()) {
         println!(
-            "  PID {}: kind={}, ppid={}, comm={}, args={}",
-            process.pid, kind, process.ppid, process.comm, process.args
+            "  PID {}: kind={}, rule={}, ppid={}, comm={}, args={}",
+            process.pid,
+            rule.agent,
+            rule.name,
+            process.ppid,
+            process.comm,
+            process.argv.join(" ")
         );
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, ppid: u32, cpu: f32, start_time: u64) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            ppid,
+            cpu,
+            start_time,
+            comm: "proc".to_string(),
+            argv: vec!["proc".to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn agent(argv: &[&str]) -> ProcessInfo {
+        ProcessInfo {
+            comm: argv[0].rsplit('/').next().unwrap().to_string(),
+            argv: argv.iter().map(|arg| arg.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn classifies_agents_from_argv() {
+        let rules = AgentRules::new(detection::builtin_rules());
+        let cases = [
+            (agent(&["claude"]), Some("claude")),
+            (
+                agent(&["/Users/me/.local/bin/claude", "--resume"]),
+                Some("claude"),
+            ),
+            (agent(&["node", "/opt/homebrew/bin/codex"]), Some("codex")),
+            (agent(&["codex", "app-server"]), None),
+            (agent(&["vim", "claude.md"]), None),
+        ];
+
+        for (process, expected) in cases {
+            assert_eq!(
+                rules.classify(&process).map(|rule| rule.agent.as_str()),
+                expected,
+                "{:?}",
+                process.argv
+            );
+        }
+    }
+
+    #[test]
+    fn descendant_cpu_keeps_session_busy() {
+        // agent 10 -> shell 11 -> cargo 12 compiling, agent itself idle
+        let processes = Snapshot::new(vec![
+            process(10, 1, 0.0, 400),
+            process(11, 10, 0.0, 700),
+            process(12, 11, 95.0, 700),
+            process(13, 1, 80.0, 700),
+        ]);
+        let record = SessionRecord::new(10, "UserPromptSubmit", 0);
+
+        assert!(session_tree_is_busy(&processes, &record));
+
+        let quiet = Snapshot::new(vec![process(10, 1, 0.1, 400), process(13, 1, 80.0, 700)]);
+        assert!(!session_tree_is_busy(&quiet, &record));
+    }
+
+    #[test]
+    fn open_tool_with_live_child_keeps_session_busy() {
+        // An MCP server (11) predates the tool; `docker build` (12) waits
+        // on the VM without using CPU itself.
+        let mcp_server = process(11, 10, 0.0, 410);
+        let docker = process(12, 10, 0.0, 800);
+        let mut record = SessionRecord::new(10, "UserPromptSubmit", 0);
+        hooks::apply_event(
+            &mut record,
+            &HookEvent::PreToolUse,
+            &HookPayload::default(),
+            800,
+        );
+
+        let running = Snapshot::new(vec![process(10, 1, 0.0, 400), mcp_server.clone(), docker]);
+        assert!(session_tree_is_busy(&running, &record));
+
+        // The tool's process is gone (interrupted): only the MCP server is left.
+        let interrupted = Snapshot::new(vec![process(10, 1, 0.0, 400), mcp_server]);
+        assert!(!session_tree_is_busy(&interrupted, &record));
+    }
+
+    #[test]
+    fn run_exit_code_matches_shell() {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+
+        assert_eq!(exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(exit_code(ExitStatus::from_raw(libc::SIGTERM)), 143);
+    }
+}
@@ -0,0 +1,276 @@
+//! Rules deciding whether sleep may be prevented right now
+//!
+//! Active sessions are necessary but not sufficient: a laptop on battery at
+//! 8% with the lid closed should go to sleep even if agents are still busy.
+//! Rules come from settings and are evaluated in order; every rule that
+//! matches adds a reason, and any blocking rule lets the machine sleep.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::power::source::PowerSource;
+use crate::registry::JsonState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyAction {
+    /// Let the machine sleep as long as the rule matches
+    AllowSleep,
+    /// Keep preventing sleep for at most this many minutes of the rule matching
+    MaxMinutes(u64),
+}
+
+/// A rule matches when every condition it sets holds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyRule {
+    #[serde(default)]
+    pub on_battery: bool,
+    /// Battery charge below this percent (only known on machines with a battery)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery_below: Option<u8>,
+    #[serde(default)]
+    pub lid_closed: bool,
+    pub action: PolicyAction,
+}
+
+impl PolicyRule {
+    pub fn matches(&self, input: &PolicyInput) -> bool {
+        (!self.on_battery || input.power.on_battery)
+            && (!self.lid_closed || input.lid_closed)
+            && self
+                .battery_below
+                .is_none_or(|below| input.power.percent.is_some_and(|percent| percent < below))
+    }
+
+    /// e.g. "lid closed on battery: max 30 min"
+    pub fn describe(&self) -> String {
+        let mut conditions = Vec::new();
+        if self.lid_closed {
+            conditions.push("lid closed".to_string());
+        }
+        if self.on_battery {
+            conditions.push("on battery".to_string());
+        }
+        if let Some(below) = self.battery_below {
+            conditions.push(format!("below {}%", below));
+        }
+        let when = if conditions.is_empty() {
+            "always".to_string()
+        } else {
+            conditions.join(" ")
+        };
+        let action = match self.action {
+            PolicyAction::AllowSleep => "allow sleep".to_string(),
+            PolicyAction::MaxMinutes(minutes) => format!("max {} min", minutes),
+        };
+        format!("{}: {}", when, action)
+    }
+}
+
+pub fn default_rules() -> Vec<PolicyRule> {
+    vec![
+        PolicyRule {
+            on_battery: true,
+            battery_below: Some(20),
+            lid_closed: false,
+            action: PolicyAction::AllowSleep,
+        },
+        PolicyRule {
+            on_battery: true,
+            battery_below: None,
+            lid_closed: true,
+            action: PolicyAction::MaxMinutes(30),
+        },
+    ]
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PolicyInput {
+    pub power: PowerSource,
+    pub lid_closed: bool,
+}
+
+/// How long a power source reading is reused. Reading it spawns `pmset`
+/// on macOS, and hooks sync far more often than the power source changes.
+const POWER_SOURCE_TTL_SECS: u64 = 30;
+
+/// When each time-limited rule started matching, keyed by its description,
+/// and the last power source read.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyState {
+    #[serde(default)]
+    pub matched_since: BTreeMap<String, u64>,
+    /// When the power source was read, and what it was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power: Option<(u64, PowerSource)>,
+}
+
+impl JsonState for PolicyState {}
+
+impl PolicyState {
+    /// The power source, read with `read` unless the last reading is recent.
+    pub fn power_source(&mut self, now: u64, read: impl FnOnce() -> PowerSource) -> PowerSource {
+        match self.power {
+            Some((read_at, power)) if read_at <= now && now - read_at < POWER_SOURCE_TTL_SECS => {
+                power
+            }
+            _ => {
+                let power = read();
+                self.power = Some((now, power));
+                power
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyDecision {
+    pub allow_prevention: bool,
+    /// One line per matching rule
+    pub reasons: Vec<String>,
+}
+
+impl PolicyDecision {
+    pub fn summary(&self) -> String {
+        if self.reasons.is_empty() {
+            "no rule applies".to_string()
+        } else {
+            self.reasons.join("; ")
+        }
+    }
+}
+
+pub fn evaluate(
+    rules: &[PolicyRule],
+    input: &PolicyInput,
+    state: &mut PolicyState,
+    now: u64,
+) -> PolicyDecision {
+    let mut allow_prevention = true;
+    let mut reasons = Vec::new();
+    let mut matched_since = BTreeMap::new();
+
+    for rule in rules.iter().filter(|rule| rule.matches(input)) {
+        let description = rule.describe();
+        match rule.action {
+            PolicyAction::AllowSleep => {
+                allow_prevention = false;
+                reasons.push(description);
+            }
+            PolicyAction::MaxMinutes(minutes) => {
+                let since = state
+                    .matched_since
+                    .get(&description)
+                    .copied()
+                    .unwrap_or(now);
+                let limit = since + minutes * 60;
+                if now >= limit {
+                    allow_prevention = false;
+                    reasons.push(format!("{} (limit reached)", description));
+                } else {
+                    reasons.push(format!(
+                        "{} ({} min left)",
+                        description,
+                        (limit - now).div_ceil(60)
+                    ));
+                }
+                matched_since.insert(description, since);
+            }
+        }
+    }
+
+    state.matched_since = matched_since;
+    PolicyDecision {
+        allow_prevention,
+        reasons,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(on_battery: bool, percent: u8, lid_closed: bool) -> PolicyInput {
+        PolicyInput {
+            power: PowerSource {
+                on_battery,
+                percent: Some(percent),
+            },
+            lid_closed,
+        }
+    }
+
+    #[test]
+    fn low_battery_allows_sleep() {
+        let mut state = PolicyState::default();
+
+        let decision = evaluate(&default_rules(), &input(true, 8, false), &mut state, 0);
+
+        assert!(!decision.allow_prevention);
+        assert_eq!(decision.reasons, vec!["on battery below 20%: allow sleep"]);
+
+        let charging = evaluate(&default_rules(), &input(false, 8, true), &mut state, 0);
+        assert!(charging.allow_prevention);
+        assert!(charging.reasons.is_empty());
+    }
+
+    #[test]
+    fn lid_closed_on_battery_is_time_limited() {
+        let rules = default_rules();
+        let mut state = PolicyState::default();
+
+        let first = evaluate(&rules, &input(true, 80, true), &mut state, 1_000);
+        assert!(first.allow_prevention);
+        assert_eq!(
+            first.reasons,
+            vec!["lid closed on battery: max 30 min (30 min left)"]
+        );
+
+        let later = evaluate(&rules, &input(true, 70, true), &mut state, 1_000 + 29 * 60);
+        assert!(later.allow_prevention);
+
+        let expired = evaluate(&rules, &input(true, 60, true), &mut state, 1_000 + 30 * 60);
+        assert!(!expired.allow_prevention);
+        assert_eq!(
+            expired.summary(),
+            "lid closed on battery: max 30 min (limit reached)"
+        );
+
+        // Opening the lid resets the clock.
+        evaluate(&rules, &input(true, 60, false), &mut state, 1_000 + 31 * 60);
+        assert!(state.matched_since.is_empty());
+    }
+
+    #[test]
+    fn rules_deserialize_from_settings() {
+        let rules: Vec<PolicyRule> = serde_json::from_str(
+            r#"[{"on_battery": true, "battery_below": 10, "action": "allow_sleep"},
+                {"lid_closed": true, "action": {"max_minutes": 5}}]"#,
+        )
+        .unwrap();
+
+        assert_eq!(rules[0].describe(), "on battery below 10%: allow sleep");
+        assert_eq!(rules[1].action, PolicyAction::MaxMinutes(5));
+        assert!(!rules[1].matches(&input(false, 50, false)));
+        assert!(rules[1].matches(&input(false, 50, true)));
+    }
+
+    #[test]
+    fn power_source_is_reused_until_it_goes_stale() {
+        let mut state = PolicyState::default();
+        let battery = PowerSource {
+            on_battery: true,
+            percent: Some(40),
+        };
+
+        assert_eq!(state.power_source(1_000, || battery), battery);
+        assert_eq!(
+            state.power_source(1_000 + POWER_SOURCE_TTL_SECS - 1, PowerSource::default),
+            battery
+        );
+        assert_eq!(
+            state.power_source(1_000 + POWER_SOURCE_TTL_SECS, PowerSource::default),
+            PowerSource::default()
+        );
+    }
+}
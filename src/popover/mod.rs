@@ -19,6 +19,10 @@ pub struct PopoverState {
     pub manual_enabled: bool,
     pub instances: Vec<(u32, u64, f32, String)>,
     pub inactive: Vec<u32>,
+    /// Sessions stopped by the safety cap
+    pub expired: usize,
+    /// Manual holds with their remaining time
+    pub holds: Vec<String>,
     pub thermal_warning: bool,
     pub dictation_enabled: bool,
     pub dictation_available: bool,
@@ -180,6 +184,29 @@ impl PopoverWindow {
             y -= 30.0;
         }
 
+        // Manual holds
+        if !state.holds.is_empty() {
+            let holds_header = ui::create_label("Holds", 20.0, y, 240.0, 18.0, false);
+            let _: () = msg_send![content_view, addSubview: holds_header];
+            y -= 22.0;
+
+            for hold in &state.holds {
+                let text = format!("  ⏳ {}", hold);
+                let label = ui::create_label(&text, 20.0, y, 240.0, 18.0, false);
+                let _: () = msg_send![content_view, addSubview: label];
+                y -= 20.0;
+            }
+            y -= 10.0;
+        }
+
+        // Sessions stopped by the safety cap
+        if state.expired > 0 {
+            let expired_text = format!("⏱ {} expired (right-click to keep awake)", state.expired);
+            let expired_label = ui::create_label(&expired_text, 20.0, y, 240.0, 18.0, false);
+            let _: () = msg_send![content_view, addSubview: expired_label];
+            y -= 22.0;
+        }
+
         // Dictation status
         let dictation_text = if !state.dictation_available {
             "🎤 Dictation: Unavailable"
@@ -0,0 +1,94 @@
+//! IOPMAssertion backend
+
+use anyhow::{bail, Result};
+use core_foundation::base::TCFType;
+use core_foundation::string::{CFString, CFStringRef};
+
+use super::PowerBackend;
+use crate::settings::PowerBackendKind;
+
+type IOPMAssertionID = u32;
+
+const K_IOPM_ASSERTION_LEVEL_ON: u32 = 255;
+const ASSERTION_NAME: &str = "Agents Sleep Preventer: coding agents are working";
+/// `PreventSystemSleep` only counts on AC power; the idle variant also
+/// works on battery, so hold both.
+const ASSERTION_TYPES: [&str; 2] = ["PreventSystemSleep", "PreventUserIdleSystemSleep"];
+
+#[link(name = "IOKit", kind = "framework")]
+extern "C" {
+    fn IOPMAssertionCreateWithName(
+        assertion_type: CFStringRef,
+        level: u32,
+        name: CFStringRef,
+        assertion_id: *mut IOPMAssertionID,
+    ) -> i32;
+    fn IOPMAssertionRelease(assertion_id: IOPMAssertionID) -> i32;
+}
+
+/// Power assertions owned by this process. They need no privileges and the
+/// kernel drops them if we crash, but they don't prevent clamshell sleep.
+pub struct AssertionBackend {
+    held: Vec<IOPMAssertionID>,
+}
+
+impl AssertionBackend {
+    pub fn new() -> Self {
+        Self { held: Vec::new() }
+    }
+}
+
+impl PowerBackend for AssertionBackend {
+    fn kind(&self) -> PowerBackendKind {
+        PowerBackendKind::Assertion
+    }
+
+    /// Only our own hold: another process's assertion isn't ours to release.
+    fn is_preventing_sleep(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    fn prevent_sleep(&mut self) -> Result<()> {
+        if !self.held.is_empty() {
+            return Ok(());
+        }
+        let name = CFString::new(ASSERTION_NAME);
+        for assertion_type in ASSERTION_TYPES {
+            let assertion_type = CFString::new(assertion_type);
+            let mut id: IOPMAssertionID = 0;
+            let result = unsafe {
+                IOPMAssertionCreateWithName(
+                    assertion_type.as_concrete_TypeRef(),
+                    K_IOPM_ASSERTION_LEVEL_ON,
+                    name.as_concrete_TypeRef(),
+                    &mut id,
+                )
+            };
+            if result != 0 {
+                self.allow_sleep()?;
+                bail!("IOPMAssertionCreateWithName failed: {:#x}", result);
+            }
+            self.held.push(id);
+        }
+        Ok(())
+    }
+
+    fn allow_sleep(&mut self) -> Result<()> {
+        for id in self.held.drain(..) {
+            unsafe {
+                IOPMAssertionRelease(id);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_process_scoped(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for AssertionBackend {
+    fn drop(&mut self) {
+        let _ = self.allow_sleep();
+    }
+}
@@ -0,0 +1,130 @@
+//! Lease on the persistent `disablesleep` flag
+//!
+//! `pmset disablesleep 1` stays set if the process that set it dies. Whoever
+//! keeps sleep disabled renews a timestamped lease; `asp watchdog` (or the
+//! daemon) turns sleep back on once the lease runs out. A resident process
+//! renews every few seconds, so its lease is short and also ends when the
+//! process is gone. Hooks only renew when an event fires, so theirs lasts
+//! long enough to cover a slow tool call.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Lease length for `asp agent`, `asp menubar` and `asp daemon`
+pub const RESIDENT_TTL_SECS: u64 = 60;
+/// Lease length for a hook process that exits right after renewing
+pub const HOOK_TTL_SECS: u64 = 15 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    pub owner_pid: u32,
+    /// Whether the owner keeps running and renewing
+    pub resident: bool,
+    pub renewed_at: u64,
+    pub ttl_secs: u64,
+}
+
+impl Lease {
+    pub fn new(owner_pid: u32, resident: bool, now: u64) -> Self {
+        Self {
+            owner_pid,
+            resident,
+            renewed_at: now,
+            ttl_secs: if resident {
+                RESIDENT_TTL_SECS
+            } else {
+                HOOK_TTL_SECS
+            },
+        }
+    }
+
+    /// Stretch the lease to cover a hold ending at `until`.
+    pub fn lasting_until(mut self, until: Option<u64>) -> Self {
+        if let Some(until) = until {
+            self.ttl_secs = self.ttl_secs.max(until.saturating_sub(self.renewed_at));
+        }
+        self
+    }
+
+    pub fn expires_at(&self) -> u64 {
+        self.renewed_at + self.ttl_secs
+    }
+
+    /// Whether nobody is looking after the flag anymore. A resident owner
+    /// that died gives up its lease right away.
+    pub fn is_expired(&self, now: u64, owner_alive: bool) -> bool {
+        now >= self.expires_at() || (self.resident && !owner_alive)
+    }
+
+    /// Whether `renewal` may take over from this lease. A hook must not
+    /// replace a live resident's short lease with its own long one.
+    pub fn yields_to(&self, renewal: &Lease, owner_alive: bool) -> bool {
+        renewal.resident
+            || !self.resident
+            || renewal.owner_pid == self.owner_pid
+            || self.is_expired(renewal.renewed_at, owner_alive)
+    }
+
+    pub fn load(path: &Path) -> Option<Self> {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+    }
+
+    /// Written atomically so the watchdog never reads half a lease.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        crate::registry::write_atomic(path, serde_json::to_string(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resident_lease_ends_with_its_owner() {
+        let lease = Lease::new(100, true, 1_000);
+
+        assert!(!lease.is_expired(1_000 + RESIDENT_TTL_SECS - 1, true));
+        assert!(lease.is_expired(1_000 + RESIDENT_TTL_SECS, true));
+        assert!(lease.is_expired(1_001, false));
+    }
+
+    #[test]
+    fn hook_lease_outlives_the_hook() {
+        let lease = Lease::new(200, false, 1_000);
+
+        assert!(!lease.is_expired(1_000 + HOOK_TTL_SECS - 1, false));
+        assert!(lease.is_expired(1_000 + HOOK_TTL_SECS, false));
+
+        let held = lease.lasting_until(Some(1_000 + 7_200));
+        assert_eq!(held.expires_at(), 8_200);
+        assert_eq!(lease.lasting_until(Some(1_060)), lease);
+    }
+
+    #[test]
+    fn hook_does_not_take_over_live_resident_lease() {
+        let resident = Lease::new(100, true, 1_000);
+        let hook = Lease::new(200, false, 1_010);
+
+        assert!(!resident.yields_to(&hook, true));
+        assert!(resident.yields_to(&hook, false));
+        assert!(resident.yields_to(&Lease::new(100, true, 1_010), true));
+        assert!(hook.yields_to(&Lease::new(300, false, 1_020), false));
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let path = std::env::temp_dir().join(format!("asp-lease-{}.json", std::process::id()));
+        let lease = Lease::new(7, true, 42);
+
+        lease.save(&path).unwrap();
+        let loaded = Lease::load(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded, Some(lease));
+        assert_eq!(Lease::load(&path), None);
+    }
+}
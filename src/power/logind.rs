@@ -0,0 +1,80 @@
+//! systemd-logind inhibitor backend
+
+use anyhow::{Context, Result};
+use zbus::blocking::Connection;
+use zbus::zvariant::OwnedFd;
+
+use super::PowerBackend;
+use crate::settings::PowerBackendKind;
+
+const LOGIN1_DESTINATION: &str = "org.freedesktop.login1";
+const LOGIN1_PATH: &str = "/org/freedesktop/login1";
+const LOGIN1_MANAGER: &str = "org.freedesktop.login1.Manager";
+/// Idle and suspend, plus the lid switch so a closed laptop stays awake.
+const INHIBIT_WHAT: &str = "sleep:idle:handle-lid-switch";
+const INHIBIT_WHO: &str = "Agents Sleep Preventer";
+const INHIBIT_WHY: &str = "Coding agents are working";
+
+/// A `block` inhibitor lock. logind keeps it for as long as we hold the file
+/// descriptor, so it is released when the daemon exits or crashes.
+pub struct LogindBackend {
+    connection: Option<Connection>,
+    lock: Option<OwnedFd>,
+}
+
+impl LogindBackend {
+    pub fn new() -> Self {
+        Self {
+            connection: None,
+            lock: None,
+        }
+    }
+
+    fn connection(&mut self) -> Result<&Connection> {
+        if self.connection.is_none() {
+            self.connection =
+                Some(Connection::system().context("Failed to connect to the system bus")?);
+        }
+        Ok(self.connection.as_ref().expect("connection just opened"))
+    }
+}
+
+impl PowerBackend for LogindBackend {
+    fn kind(&self) -> PowerBackendKind {
+        PowerBackendKind::Logind
+    }
+
+    /// Only our own lock: another process's inhibitor isn't ours to release.
+    fn is_preventing_sleep(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    fn prevent_sleep(&mut self) -> Result<()> {
+        if self.lock.is_some() {
+            return Ok(());
+        }
+        let lock: OwnedFd = self
+            .connection()?
+            .call_method(
+                Some(LOGIN1_DESTINATION),
+                LOGIN1_PATH,
+                Some(LOGIN1_MANAGER),
+                "Inhibit",
+                &(INHIBIT_WHAT, INHIBIT_WHO, INHIBIT_WHY, "block"),
+            )
+            .context("logind refused the inhibitor lock")?
+            .body()
+            .deserialize()?;
+        self.lock = Some(lock);
+        Ok(())
+    }
+
+    fn allow_sleep(&mut self) -> Result<()> {
+        self.lock = None;
+        Ok(())
+    }
+
+    fn is_process_scoped(&self) -> bool {
+        true
+    }
+}
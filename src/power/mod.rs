@@ -0,0 +1,190 @@
+//! Ways of keeping the machine awake
+//!
+//! `pmset` flips the global `disablesleep` flag: it survives a closed lid but
+//! needs the root helper and outlives a crash. An IOPMAssertion needs no privileges and
+//! goes away with the process holding it, but doesn't stop clamshell sleep.
+//! On Linux a systemd-logind inhibitor lock blocks both, for as long as the
+//! daemon holds its file descriptor.
+
+#[cfg(target_os = "macos")]
+mod assertion;
+pub mod lease;
+#[cfg(target_os = "linux")]
+mod logind;
+#[cfg(target_os = "macos")]
+mod pmset;
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+pub mod snapshot;
+pub mod source;
+
+#[cfg(target_os = "macos")]
+pub use assertion::AssertionBackend;
+#[cfg(target_os = "linux")]
+pub use logind::LogindBackend;
+#[cfg(target_os = "macos")]
+pub use pmset::{is_sleep_disabled, PmsetBackend};
+
+use anyhow::Result;
+
+use crate::settings::PowerBackendKind;
+
+pub trait PowerBackend: Send {
+    fn kind(&self) -> PowerBackendKind;
+
+    /// Whether sleep is currently being prevented by this backend.
+    fn is_preventing_sleep(&self) -> bool;
+
+    fn prevent_sleep(&mut self) -> Result<()>;
+
+    fn allow_sleep(&mut self) -> Result<()>;
+
+    /// Whether the hold is released when this process exits, so only a
+    /// resident process (`asp agent`, `asp menubar`, `asp daemon`) should
+    /// drive it.
+    fn is_process_scoped(&self) -> bool;
+}
+
+/// The backend `kind` maps to on this platform, for settings copied
+/// between machines.
+pub fn supported(kind: PowerBackendKind) -> PowerBackendKind {
+    if cfg!(target_os = "macos") {
+        match kind {
+            PowerBackendKind::Logind => PowerBackendKind::Pmset,
+            kind => kind,
+        }
+    } else {
+        PowerBackendKind::Logind
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn backend(kind: PowerBackendKind) -> Box<dyn PowerBackend> {
+    match supported(kind) {
+        PowerBackendKind::Assertion => Box::new(AssertionBackend::new()),
+        _ => Box::new(PmsetBackend),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn backend(_kind: PowerBackendKind) -> Box<dyn PowerBackend> {
+    Box::new(LogindBackend::new())
+}
+
+/// Make sure `slot` holds a backend of `kind`, releasing the hold of the
+/// backend it replaces.
+pub fn select(
+    slot: &mut Option<Box<dyn PowerBackend>>,
+    kind: PowerBackendKind,
+    make: impl FnOnce(PowerBackendKind) -> Box<dyn PowerBackend>,
+) {
+    if slot.as_ref().map(|backend| backend.kind()) == Some(kind) {
+        return;
+    }
+    if let Some(mut previous) = slot.take() {
+        if previous.is_preventing_sleep() {
+            let _ = previous.allow_sleep();
+        }
+    }
+    *slot = Some(make(kind));
+}
+
+/// Move `backend` to the wanted state. Returns the new state when it changed.
+pub fn reconcile(backend: &mut dyn PowerBackend, prevent: bool) -> Result<Option<bool>> {
+    if backend.is_preventing_sleep() == prevent {
+        return Ok(None);
+    }
+    if prevent {
+        backend.prevent_sleep()?;
+    } else {
+        backend.allow_sleep()?;
+    }
+    Ok(Some(prevent))
+}
+
+/// In-memory backend that records every transition.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockBackend {
+    pub kind: PowerBackendKind,
+    pub preventing: bool,
+    pub transitions: Vec<bool>,
+}
+
+#[cfg(test)]
+impl PowerBackend for MockBackend {
+    fn kind(&self) -> PowerBackendKind {
+        self.kind
+    }
+
+    fn is_preventing_sleep(&self) -> bool {
+        self.preventing
+    }
+
+    fn prevent_sleep(&mut self) -> Result<()> {
+        self.preventing = true;
+        self.transitions.push(true);
+        Ok(())
+    }
+
+    fn allow_sleep(&mut self) -> Result<()> {
+        self.preventing = false;
+        self.transitions.push(false);
+        Ok(())
+    }
+
+    fn is_process_scoped(&self) -> bool {
+        self.kind != PowerBackendKind::Pmset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock(kind: PowerBackendKind) -> Box<dyn PowerBackend> {
+        Box::new(MockBackend {
+            kind,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn reconcile_only_acts_on_changes() {
+        let mut backend = MockBackend::default();
+
+        assert_eq!(reconcile(&mut backend, true).unwrap(), Some(true));
+        assert_eq!(reconcile(&mut backend, true).unwrap(), None);
+        assert_eq!(reconcile(&mut backend, false).unwrap(), Some(false));
+        assert_eq!(reconcile(&mut backend, false).unwrap(), None);
+
+        assert_eq!(backend.transitions, vec![true, false]);
+    }
+
+    #[test]
+    fn select_keeps_matching_backend_and_swaps_others() {
+        let mut slot = Some(mock(PowerBackendKind::Pmset));
+        reconcile(slot.as_mut().unwrap().as_mut(), true).unwrap();
+
+        select(&mut slot, PowerBackendKind::Pmset, |_| unreachable!());
+        assert!(slot.as_ref().unwrap().is_preventing_sleep());
+
+        select(&mut slot, PowerBackendKind::Assertion, mock);
+        let backend = slot.as_ref().unwrap();
+        assert_eq!(backend.kind(), PowerBackendKind::Assertion);
+        assert!(!backend.is_preventing_sleep());
+        assert!(backend.is_process_scoped());
+    }
+
+    #[test]
+    fn supported_maps_foreign_backends() {
+        if cfg!(target_os = "macos") {
+            assert_eq!(supported(PowerBackendKind::Logind), PowerBackendKind::Pmset);
+            assert_eq!(
+                supported(PowerBackendKind::Assertion),
+                PowerBackendKind::Assertion
+            );
+        } else {
+            assert_eq!(supported(PowerBackendKind::Pmset), PowerBackendKind::Logind);
+        }
+    }
+}
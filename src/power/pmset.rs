@@ -0,0 +1,77 @@
+//! `pmset disablesleep` backend
+
+use anyhow::{Context, Result};
+use core_foundation::base::{kCFAllocatorDefault, TCFType};
+use core_foundation::boolean::CFBoolean;
+use core_foundation::string::CFString;
+use io_kit_sys::*;
+use mach2::port::MACH_PORT_NULL;
+
+use super::PowerBackend;
+use crate::helper::{self, Request};
+use crate::settings::PowerBackendKind;
+
+/// Global `disablesleep` flag set through the root helper. Keeps the Mac
+/// awake with the lid closed.
+pub struct PmsetBackend;
+
+impl PowerBackend for PmsetBackend {
+    fn kind(&self) -> PowerBackendKind {
+        PowerBackendKind::Pmset
+    }
+
+    fn is_preventing_sleep(&self) -> bool {
+        is_sleep_disabled()
+    }
+
+    fn prevent_sleep(&mut self) -> Result<()> {
+        set_disablesleep(true)
+    }
+
+    fn allow_sleep(&mut self) -> Result<()> {
+        set_disablesleep(false)
+    }
+
+    fn is_process_scoped(&self) -> bool {
+        false
+    }
+}
+
+fn set_disablesleep(disabled: bool) -> Result<()> {
+    helper::request(Request::SetDisablesleep { enabled: disabled })
+        .with_context(|| format!("Failed to set disablesleep={}", disabled as u8))?;
+    Ok(())
+}
+
+/// `SleepDisabled` from IOPMrootDomain, i.e. whether `disablesleep` is on.
+pub fn is_sleep_disabled() -> bool {
+    unsafe {
+        let service_name = b"IOPMrootDomain\0";
+        let matching = IOServiceMatching(service_name.as_ptr() as *const i8);
+        if matching.is_null() {
+            return false;
+        }
+
+        let root_domain = IOServiceGetMatchingService(kIOMasterPortDefault, matching);
+        if root_domain == MACH_PORT_NULL {
+            return false;
+        }
+
+        let key = CFString::new("SleepDisabled");
+        let property = IORegistryEntryCreateCFProperty(
+            root_domain,
+            key.as_concrete_TypeRef(),
+            kCFAllocatorDefault,
+            0,
+        );
+
+        IOObjectRelease(root_domain);
+
+        if property.is_null() {
+            return false;
+        }
+
+        let result = CFBoolean::wrap_under_create_rule(property as _).into();
+        result
+    }
+}
@@ -0,0 +1,273 @@
+//! The user's own pmset settings, saved before `asp install` touches them
+//!
+//! `pmset -g custom` lists settings per power source. The sleep related ones
+//! are saved at install time so `asp power restore` and `asp uninstall` can
+//! put back exactly what was there.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Settings worth restoring. Others in `pmset -g custom` are read-only or
+/// not ours to touch.
+const RESTORED_KEYS: [&str; 13] = [
+    "sleep",
+    "displaysleep",
+    "disksleep",
+    "standby",
+    "standbydelayhigh",
+    "standbydelaylow",
+    "highstandbythreshold",
+    "powernap",
+    "hibernatemode",
+    "lowpowermode",
+    "ttyskeepawake",
+    "tcpkeepalive",
+    "womp",
+];
+
+/// Power source headings in `pmset -g custom` and the matching pmset flag.
+const SOURCES: [(&str, &str); 3] = [
+    ("Battery Power", "-b"),
+    ("AC Power", "-c"),
+    ("UPS Power", "-u"),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PowerSnapshot {
+    /// Power source heading (e.g. "AC Power") to setting name and value
+    pub sources: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl PowerSnapshot {
+    /// Parse `pmset -g custom`. Values annotated by pmset, like
+    /// `sleep 1 (sleep prevented by powerd)`, keep only the value itself.
+    pub fn parse(output: &str) -> Self {
+        let mut sources: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+        let mut current: Option<String> = None;
+        for line in output.lines() {
+            if !line.starts_with(char::is_whitespace) {
+                current = line
+                    .trim()
+                    .strip_suffix(':')
+                    .filter(|source| SOURCES.iter().any(|(name, _)| name == source))
+                    .map(str::to_string);
+                continue;
+            }
+            let Some(source) = &current else {
+                continue;
+            };
+            let mut fields = line.split_whitespace();
+            let (Some(key), Some(value)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            if RESTORED_KEYS.contains(&key) {
+                sources
+                    .entry(source.clone())
+                    .or_default()
+                    .insert(key.to_string(), value.to_string());
+            }
+        }
+        Self { sources }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.values().all(|settings| settings.is_empty())
+    }
+
+    /// pmset arguments that put every saved setting back, one call per
+    /// power source.
+    pub fn restore_args(&self) -> Vec<Vec<String>> {
+        SOURCES
+            .iter()
+            .filter_map(|(name, flag)| {
+                let settings = self.sources.get(*name).filter(|s| !s.is_empty())?;
+                let mut args = vec![flag.to_string()];
+                for (key, value) in settings {
+                    args.push(key.clone());
+                    args.push(value.clone());
+                }
+                Some(args)
+            })
+            .collect()
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                Ok(Some(serde_json::from_str(&content).with_context(|| {
+                    format!("Invalid power snapshot {}", path.display())
+                })?))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Check pmset arguments from [`PowerSnapshot::restore_args`] before the
+/// helper runs them as root: a power source flag followed by pairs of a
+/// restorable setting and a number.
+pub fn check_restore_args(args: &[String]) -> Result<()> {
+    let Some((flag, pairs)) = args.split_first() else {
+        bail!("No power source given");
+    };
+    if !SOURCES.iter().any(|(_, source)| source == flag) {
+        bail!("Unknown power source {}", flag);
+    }
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        bail!("Settings must come as name and value pairs");
+    }
+    for pair in pairs.chunks(2) {
+        if !RESTORED_KEYS.contains(&pair[0].as_str()) {
+            bail!("{} is not a restorable setting", pair[0]);
+        }
+        if pair[1].is_empty() || !pair[1].bytes().all(|b| b.is_ascii_digit()) {
+            bail!("Invalid value {:?} for {}", pair[1], pair[0]);
+        }
+    }
+    Ok(())
+}
+
+/// Read the current settings from `pmset -g custom`.
+#[cfg(target_os = "macos")]
+pub fn capture() -> Result<PowerSnapshot> {
+    let output = std::process::Command::new("/usr/bin/pmset")
+        .args(["-g", "custom"])
+        .output()
+        .context("Failed to run pmset -g custom")?;
+    if !output.status.success() {
+        bail!("pmset -g custom failed");
+    }
+    Ok(PowerSnapshot::parse(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACBOOK: &str = "\
+Battery Power:
+ Sleep On Power Button 1
+ lowpowermode         0
+ standby              1
+ ttyskeepawake        1
+ hibernatemode        3
+ powernap             0
+ hibernatefile        /var/vm/sleepimage
+ displaysleep         2
+ womp                 0
+ networkoversleep     0
+ sleep                1 (sleep prevented by sharingd, powerd)
+ lidwake              1
+ tcpkeepalive         1
+ disksleep            10
+AC Power:
+ Sleep On Power Button 1
+ lowpowermode         0
+ standby              1
+ ttyskeepawake        1
+ hibernatemode        3
+ powernap             1
+ hibernatefile        /var/vm/sleepimage
+ displaysleep         10
+ womp                 1
+ networkoversleep     0
+ sleep                0
+ lidwake              1
+ tcpkeepalive         1
+ disksleep            10
+";
+
+    const DESKTOP: &str = "\
+AC Power:
+ sleep                30
+ displaysleep         15
+ powernap             1
+ disksleep            10
+ womp                 1
+";
+
+    #[test]
+    fn parses_each_power_source() {
+        let snapshot = PowerSnapshot::parse(MACBOOK);
+
+        let battery = &snapshot.sources["Battery Power"];
+        assert_eq!(battery["sleep"], "1");
+        assert_eq!(battery["displaysleep"], "2");
+        assert_eq!(battery["disksleep"], "10");
+        assert!(!battery.contains_key("hibernatefile"));
+        assert!(!battery.contains_key("lidwake"));
+        assert!(!battery.contains_key("Sleep"));
+
+        let ac = &snapshot.sources["AC Power"];
+        assert_eq!(ac["sleep"], "0");
+        assert_eq!(ac["powernap"], "1");
+        assert_eq!(ac.len(), 10);
+    }
+
+    #[test]
+    fn restores_only_saved_sources() {
+        let snapshot = PowerSnapshot::parse(DESKTOP);
+
+        assert_eq!(
+            snapshot.restore_args(),
+            vec![vec![
+                "-c",
+                "disksleep",
+                "10",
+                "displaysleep",
+                "15",
+                "powernap",
+                "1",
+                "sleep",
+                "30",
+                "womp",
+                "1"
+            ]]
+        );
+        assert_eq!(PowerSnapshot::parse(MACBOOK).restore_args().len(), 2);
+        assert!(PowerSnapshot::parse("").is_empty());
+    }
+
+    #[test]
+    fn helper_accepts_only_restore_args() {
+        let args = |list: &[&str]| list.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+        for restore in PowerSnapshot::parse(MACBOOK).restore_args() {
+            check_restore_args(&restore).unwrap();
+        }
+        assert!(check_restore_args(&[]).is_err());
+        assert!(check_restore_args(&args(&["-c"])).is_err());
+        assert!(check_restore_args(&args(&["-a", "sleep", "5"])).is_err());
+        assert!(check_restore_args(&args(&["-c", "disablesleep", "1"])).is_err());
+        assert!(check_restore_args(&args(&["-c", "sleep", "-1"])).is_err());
+        assert!(check_restore_args(&args(&["-c", "sleep"])).is_err());
+        assert!(check_restore_args(&args(&["-c", "sleep", "0", "womp", "1"])).is_ok());
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let path = std::env::temp_dir().join(format!("asp-power-{}.json", std::process::id()));
+        let snapshot = PowerSnapshot::parse(MACBOOK);
+
+        snapshot.save(&path).unwrap();
+        let loaded = PowerSnapshot::load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded, Some(snapshot));
+        assert_eq!(PowerSnapshot::load(&path).unwrap(), None);
+    }
+}
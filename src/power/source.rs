@@ -0,0 +1,120 @@
+//! Where power comes from: AC or battery, and how much charge is left
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PowerSource {
+    pub on_battery: bool,
+    /// Charge of the internal battery, `None` on machines without one
+    pub percent: Option<u8>,
+}
+
+impl fmt::Display for PowerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = if self.on_battery { "Battery" } else { "AC" };
+        match self.percent {
+            Some(percent) => write!(f, "{} ({}%)", source, percent),
+            None => write!(f, "{}", source),
+        }
+    }
+}
+
+/// Parse `pmset -g batt`:
+///
+/// ```text
+/// Now drawing from 'Battery Power'
+///  -InternalBattery-0 (id=4653155)    85%; discharging; 4:12 remaining present: true
+/// ```
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+pub fn parse_pmset_batt(output: &str) -> PowerSource {
+    let on_battery = output
+        .lines()
+        .find_map(|line| line.strip_prefix("Now drawing from '"))
+        .is_some_and(|source| source.starts_with("Battery Power"));
+    let percent = output
+        .lines()
+        .filter(|line| line.contains("InternalBattery"))
+        .find_map(|line| {
+            let end = line.find('%')?;
+            let start = line[..end]
+                .rfind(|c: char| !c.is_ascii_digit())
+                .map_or(0, |i| i + 1);
+            line[start..end].parse().ok()
+        });
+    PowerSource {
+        on_battery,
+        percent,
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn read() -> PowerSource {
+    std::process::Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()
+        .ok()
+        .map(|output| parse_pmset_batt(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or_default()
+}
+
+/// Read `/sys/class/power_supply`: on battery when no mains supply is online.
+#[cfg(target_os = "linux")]
+pub fn read() -> PowerSource {
+    use std::fs;
+
+    let read = |path: &std::path::Path, name: &str| {
+        fs::read_to_string(path.join(name))
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+    let mut mains_online = false;
+    let mut has_mains = false;
+    let mut percent = None;
+    for entry in fs::read_dir("/sys/class/power_supply")
+        .into_iter()
+        .flatten()
+        .flatten()
+    {
+        let path = entry.path();
+        match read(&path, "type").as_str() {
+            "Mains" => {
+                has_mains = true;
+                mains_online |= read(&path, "online") == "1";
+            }
+            "Battery" if percent.is_none() => percent = read(&path, "capacity").parse().ok(),
+            _ => {}
+        }
+    }
+    PowerSource {
+        on_battery: has_mains && !mains_online && percent.is_some(),
+        percent,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pmset_batt() {
+        let battery = parse_pmset_batt(
+            "Now drawing from 'Battery Power'\n -InternalBattery-0 (id=4653155)\t8%; discharging; 0:21 remaining present: true\n",
+        );
+        assert_eq!(
+            battery,
+            PowerSource {
+                on_battery: true,
+                percent: Some(8)
+            }
+        );
+
+        let charging = parse_pmset_batt(
+            "Now drawing from 'AC Power'\n -InternalBattery-0 (id=4653155)\t100%; charged; 0:00 remaining present: true\n",
+        );
+        assert_eq!(charging.to_string(), "AC (100%)");
+
+        let desktop = parse_pmset_batt("Now drawing from 'AC Power'\n");
+        assert_eq!(desktop, PowerSource::default());
+    }
+}
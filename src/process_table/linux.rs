@@ -0,0 +1,173 @@
+//! `/proc` backed process table
+
+use std::fs;
+use std::path::Path;
+
+use super::{ProcessInfo, ProcessTable};
+
+pub struct ProcfsTable {
+    boot_time: u64,
+    ticks_per_sec: u64,
+}
+
+impl ProcfsTable {
+    pub fn new() -> Self {
+        let boot_time = fs::read_to_string("/proc/stat")
+            .ok()
+            .and_then(|stat| parse_boot_time(&stat))
+            .unwrap_or(0);
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        Self {
+            boot_time,
+            ticks_per_sec: if ticks > 0 { ticks as u64 } else { 100 },
+        }
+    }
+}
+
+impl ProcessTable for ProcfsTable {
+    fn pids(&self) -> Vec<u32> {
+        fs::read_dir("/proc")
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn process(&self, pid: u32) -> Option<ProcessInfo> {
+        let dir = Path::new("/proc").join(pid.to_string());
+        let stat = parse_stat(&fs::read_to_string(dir.join("stat")).ok()?)?;
+        let argv = fs::read(dir.join("cmdline"))
+            .map(|cmdline| parse_cmdline(&cmdline))
+            .unwrap_or_default();
+        let cwd = fs::read_link(dir.join("cwd"))
+            .ok()
+            .map(|path| path.to_string_lossy().to_string());
+        // An executable replaced on disk (an upgrade) reads back as "<path> (deleted)".
+        let exe = fs::read_link(dir.join("exe")).ok().map(|path| {
+            let path = path.to_string_lossy();
+            path.strip_suffix(" (deleted)").unwrap_or(&path).to_string()
+        });
+
+        Some(ProcessInfo {
+            pid,
+            ppid: stat.ppid,
+            comm: stat.comm,
+            argv,
+            exe,
+            cwd,
+            tty: tty_name(stat.tty_nr),
+            start_time: self.boot_time + stat.start_ticks / self.ticks_per_sec,
+            cpu_time_ns: (stat.utime + stat.stime) * 1_000_000_000 / self.ticks_per_sec,
+            cpu: 0.0,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Stat {
+    comm: String,
+    ppid: u32,
+    tty_nr: u32,
+    utime: u64,
+    stime: u64,
+    start_ticks: u64,
+}
+
+/// Parse `/proc/<pid>/stat`. `comm` may contain spaces and parentheses, so
+/// fields are counted from the last `)`.
+fn parse_stat(content: &str) -> Option<Stat> {
+    let open = content.find('(')?;
+    let close = content.rfind(')')?;
+    let comm = content.get(open + 1..close)?.to_string();
+    // Fields after comm start at field 3 (state).
+    let fields: Vec<&str> = content.get(close + 1..)?.split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3).copied();
+    Some(Stat {
+        comm,
+        ppid: field(4)?.parse().ok()?,
+        tty_nr: field(7)?.parse::<i64>().ok()? as u32,
+        utime: field(14)?.parse().ok()?,
+        stime: field(15)?.parse().ok()?,
+        start_ticks: field(22)?.parse().ok()?,
+    })
+}
+
+fn parse_boot_time(stat: &str) -> Option<u64> {
+    stat.lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|secs| secs.trim().parse().ok())
+}
+
+fn parse_cmdline(cmdline: &[u8]) -> Vec<String> {
+    cmdline
+        .split(|byte| *byte == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).to_string())
+        .collect()
+}
+
+/// Name of a controlling terminal from its encoded device number.
+fn tty_name(tty_nr: u32) -> Option<String> {
+    let major = (tty_nr >> 8) & 0xfff;
+    let minor = (tty_nr & 0xff) | ((tty_nr >> 12) & 0xfff00);
+    match major {
+        0 => None,
+        136..=143 => Some(format!("pts/{}", (major - 136) * 256 + minor)),
+        4 if minor < 64 => Some(format!("tty{}", minor)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stat_with_awkward_comm() {
+        let stat = parse_stat(
+            "4242 (tmux: server (1)) S 1 4242 4242 34817 4242 4194560 1 0 0 0 150 25 0 0 20 0 1 0 98765 0 0",
+        )
+        .unwrap();
+
+        assert_eq!(
+            stat,
+            Stat {
+                comm: "tmux: server (1)".to_string(),
+                ppid: 1,
+                tty_nr: 34817,
+                utime: 150,
+                stime: 25,
+                start_ticks: 98765,
+            }
+        );
+        assert_eq!(tty_name(stat.tty_nr), Some("pts/1".to_string()));
+        assert_eq!(tty_name(0), None);
+    }
+
+    #[test]
+    fn parses_cmdline_and_boot_time() {
+        assert_eq!(
+            parse_cmdline(b"node\0/usr/bin/codex\0--yolo\0"),
+            vec!["node", "/usr/bin/codex", "--yolo"]
+        );
+        assert_eq!(
+            parse_boot_time("cpu  1 2 3\nbtime 1700000000\nprocesses 9\n"),
+            Some(1_700_000_000)
+        );
+    }
+
+    #[test]
+    fn reads_own_process() {
+        let table = ProcfsTable::new();
+        let me = table.process(std::process::id()).unwrap();
+
+        assert_eq!(me.ppid, std::os::unix::process::parent_id());
+        assert!(!me.argv.is_empty());
+        assert!(me.exe.is_some());
+        assert!(me.start_time > 0);
+        assert!(table.pids().contains(&std::process::id()));
+    }
+}
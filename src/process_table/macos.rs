@@ -0,0 +1,238 @@
+//! libproc and sysctl backed process table
+
+use mach2::mach_time::{mach_timebase_info, mach_timebase_info as MachTimebaseInfo};
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+
+use super::{ProcessInfo, ProcessTable};
+
+/// Device number `proc_bsdinfo` reports for processes without a terminal.
+const NODEV: u32 = u32::MAX;
+
+pub struct LibprocTable {
+    /// Mach absolute time units to nanoseconds
+    timebase: (u64, u64),
+    argmax: usize,
+}
+
+impl LibprocTable {
+    pub fn new() -> Self {
+        let mut info = MachTimebaseInfo { numer: 0, denom: 0 };
+        let timebase = if unsafe { mach_timebase_info(&mut info) } == 0 && info.denom != 0 {
+            (info.numer as u64, info.denom as u64)
+        } else {
+            (1, 1)
+        };
+        Self {
+            timebase,
+            argmax: read_argmax().unwrap_or(256 * 1024),
+        }
+    }
+
+    fn to_nanos(&self, mach_time: u64) -> u64 {
+        (mach_time as u128 * self.timebase.0 as u128 / self.timebase.1 as u128) as u64
+    }
+
+    fn argv(&self, pid: u32) -> Vec<String> {
+        let mut mib = [libc::CTL_KERN, libc::KERN_PROCARGS2, pid as c_int];
+        let mut buffer = vec![0u8; self.argmax];
+        let mut size = buffer.len();
+        let result = unsafe {
+            libc::sysctl(
+                mib.as_mut_ptr(),
+                mib.len() as u32,
+                buffer.as_mut_ptr() as *mut c_void,
+                &mut size,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if result != 0 {
+            return Vec::new();
+        }
+        parse_procargs(&buffer[..size.min(buffer.len())])
+    }
+}
+
+impl ProcessTable for LibprocTable {
+    fn pids(&self) -> Vec<u32> {
+        let count = unsafe { libc::proc_listallpids(ptr::null_mut(), 0) };
+        if count <= 0 {
+            return Vec::new();
+        }
+        // Leave room for processes spawned between the two calls.
+        let mut pids = vec![0 as c_int; count as usize + 64];
+        let bytes = (pids.len() * mem::size_of::<c_int>()) as c_int;
+        let found = unsafe { libc::proc_listallpids(pids.as_mut_ptr() as *mut c_void, bytes) };
+        pids.truncate(found.max(0) as usize);
+        pids.into_iter()
+            .filter(|pid| *pid > 0)
+            .map(|pid| pid as u32)
+            .collect()
+    }
+
+    fn process(&self, pid: u32) -> Option<ProcessInfo> {
+        let mut info: libc::proc_taskallinfo = unsafe { mem::zeroed() };
+        if pid_info(pid, libc::PROC_PIDTASKALLINFO, &mut info) {
+            let bsd = &info.pbsd;
+            let task = &info.ptinfo;
+            return Some(ProcessInfo {
+                pid,
+                ppid: bsd.pbi_ppid,
+                comm: c_chars_to_string(&bsd.pbi_comm),
+                argv: self.argv(pid),
+                exe: exe(pid),
+                cwd: cwd(pid),
+                tty: tty_name(bsd.e_tdev),
+                start_time: bsd.pbi_start_tvsec,
+                cpu_time_ns: self.to_nanos(task.pti_total_user + task.pti_total_system),
+                cpu: 0.0,
+            });
+        }
+
+        // Task info is denied for other users' processes; BSD info still
+        // gives the parent and start time, just no CPU time.
+        let mut bsd: libc::proc_bsdinfo = unsafe { mem::zeroed() };
+        if !pid_info(pid, libc::PROC_PIDTBSDINFO, &mut bsd) {
+            return None;
+        }
+        Some(ProcessInfo {
+            pid,
+            ppid: bsd.pbi_ppid,
+            comm: c_chars_to_string(&bsd.pbi_comm),
+            argv: self.argv(pid),
+            exe: exe(pid),
+            cwd: None,
+            tty: tty_name(bsd.e_tdev),
+            start_time: bsd.pbi_start_tvsec,
+            cpu_time_ns: 0,
+            cpu: 0.0,
+        })
+    }
+}
+
+fn pid_info<T>(pid: u32, flavor: c_int, info: &mut T) -> bool {
+    let size = mem::size_of::<T>() as c_int;
+    let written =
+        unsafe { libc::proc_pidinfo(pid as c_int, flavor, 0, info as *mut T as *mut c_void, size) };
+    written == size
+}
+
+fn exe(pid: u32) -> Option<String> {
+    let mut buffer = vec![0u8; libc::PROC_PIDPATHINFO_MAXSIZE as usize];
+    let len = unsafe {
+        libc::proc_pidpath(
+            pid as c_int,
+            buffer.as_mut_ptr() as *mut c_void,
+            buffer.len() as u32,
+        )
+    };
+    if len <= 0 {
+        return None;
+    }
+    buffer.truncate(len as usize);
+    Some(String::from_utf8_lossy(&buffer).to_string())
+}
+
+fn cwd(pid: u32) -> Option<String> {
+    let mut info: libc::proc_vnodepathinfo = unsafe { mem::zeroed() };
+    if !pid_info(pid, libc::PROC_PIDVNODEPATHINFO, &mut info) {
+        return None;
+    }
+    let path: &[c_char] = info.pvi_cdir.vip_path.as_flattened();
+    Some(c_chars_to_string(path)).filter(|path| !path.is_empty())
+}
+
+fn tty_name(dev: u32) -> Option<String> {
+    if dev == NODEV {
+        return None;
+    }
+    let name = unsafe { libc::devname(dev as libc::dev_t, libc::S_IFCHR) };
+    if name.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(name) }
+        .to_string_lossy()
+        .to_string();
+    Some(name).filter(|name| !name.is_empty() && name != "??")
+}
+
+fn read_argmax() -> Option<usize> {
+    let mut mib = [libc::CTL_KERN, libc::KERN_ARGMAX];
+    let mut argmax: c_int = 0;
+    let mut size = mem::size_of::<c_int>();
+    let result = unsafe {
+        libc::sysctl(
+            mib.as_mut_ptr(),
+            mib.len() as u32,
+            &mut argmax as *mut c_int as *mut c_void,
+            &mut size,
+            ptr::null_mut(),
+            0,
+        )
+    };
+    (result == 0 && argmax > 0).then_some(argmax as usize)
+}
+
+fn c_chars_to_string(chars: &[c_char]) -> String {
+    let bytes: Vec<u8> = chars
+        .iter()
+        .take_while(|ch| **ch != 0)
+        .map(|ch| *ch as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Parse a `KERN_PROCARGS2` buffer: `argc`, the executable path, NUL
+/// padding, then `argc` NUL-terminated arguments (followed by the environment).
+fn parse_procargs(buffer: &[u8]) -> Vec<String> {
+    let Some(argc) = buffer.get(..4) else {
+        return Vec::new();
+    };
+    let argc = i32::from_ne_bytes([argc[0], argc[1], argc[2], argc[3]]).max(0) as usize;
+    let rest = &buffer[4..];
+    let Some(exec_end) = rest.iter().position(|byte| *byte == 0) else {
+        return Vec::new();
+    };
+    let Some(args_start) = rest[exec_end..].iter().position(|byte| *byte != 0) else {
+        return Vec::new();
+    };
+    rest[exec_end + args_start..]
+        .split(|byte| *byte == 0)
+        .take(argc)
+        .map(|arg| String::from_utf8_lossy(arg).to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_procargs_buffer() {
+        let mut buffer = 2i32.to_ne_bytes().to_vec();
+        buffer.extend_from_slice(
+            b"/opt/homebrew/bin/node\0\0\0\0node\0/opt/homebrew/bin/codex\0HOME=/Users/me\0",
+        );
+
+        assert_eq!(
+            parse_procargs(&buffer),
+            vec!["node", "/opt/homebrew/bin/codex"]
+        );
+        assert!(parse_procargs(&[1, 0]).is_empty());
+    }
+
+    #[test]
+    fn reads_own_process() {
+        let table = LibprocTable::new();
+        let me = table.process(std::process::id()).unwrap();
+
+        assert_eq!(me.ppid, std::os::unix::process::parent_id());
+        assert!(!me.argv.is_empty());
+        assert!(me.exe.is_some());
+        assert!(me.cwd.is_some());
+        assert!(table.pids().contains(&std::process::id()));
+    }
+}
@@ -0,0 +1,327 @@
+//! Native process table
+//!
+//! Reads pid, parent, argv, cwd, tty, start time and CPU time straight from
+//! the kernel (libproc/sysctl on macOS, `/proc` on Linux) so hot paths don't
+//! spawn `ps` or `lsof`. CPU usage is a delta between two snapshots, taken by
+//! [`CpuSampler`]. One-shot commands diff against the baseline the resident
+//! process last saved instead of waiting for a second snapshot.
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "macos")]
+mod macos;
+
+#[cfg(target_os = "linux")]
+pub use linux::ProcfsTable as NativeTable;
+#[cfg(target_os = "macos")]
+pub use macos::LibprocTable as NativeTable;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long to wait between snapshots when there's no earlier one to diff against.
+const FIRST_SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+/// Oldest saved baseline a one-shot command will diff against.
+const BASELINE_MAX_AGE: Duration = Duration::from_secs(10);
+
+/// Cumulative CPU nanoseconds keyed by (pid, start time).
+type CpuTimes = HashMap<(u32, u64), u64>;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    /// Short kernel process name
+    pub comm: String,
+    pub argv: Vec<String>,
+    /// Path of the running executable
+    pub exe: Option<String>,
+    /// `None` when the process belongs to another user
+    pub cwd: Option<String>,
+    /// Controlling terminal, e.g. `ttys003`
+    pub tty: Option<String>,
+    /// Unix seconds when the process started
+    pub start_time: u64,
+    /// Cumulative user + system CPU time in nanoseconds
+    pub cpu_time_ns: u64,
+    /// Percent of one core since the previous sample; 0 in unsampled snapshots
+    pub cpu: f32,
+}
+
+pub trait ProcessTable {
+    fn pids(&self) -> Vec<u32>;
+
+    fn process(&self, pid: u32) -> Option<ProcessInfo>;
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot::new(
+            self.pids()
+                .into_iter()
+                .filter_map(|pid| self.process(pid))
+                .collect(),
+        )
+    }
+}
+
+pub fn native() -> NativeTable {
+    NativeTable::new()
+}
+
+/// Every process visible at one point in time, sorted by PID.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    processes: Vec<ProcessInfo>,
+}
+
+impl Snapshot {
+    pub fn new(mut processes: Vec<ProcessInfo>) -> Self {
+        processes.sort_by_key(|process| process.pid);
+        Self { processes }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ProcessInfo> {
+        self.processes.iter()
+    }
+
+    pub fn get(&self, pid: u32) -> Option<&ProcessInfo> {
+        self.processes
+            .binary_search_by_key(&pid, |process| process.pid)
+            .ok()
+            .map(|idx| &self.processes[idx])
+    }
+
+    /// The process and everything it spawned, directly or through children.
+    pub fn tree(&self, root: u32) -> Vec<&ProcessInfo> {
+        let mut tree: Vec<&ProcessInfo> = self.get(root).into_iter().collect();
+        let mut parents = vec![root];
+        while let Some(parent) = parents.pop() {
+            for child in self
+                .processes
+                .iter()
+                .filter(|p| p.ppid == parent && p.pid != root)
+            {
+                if !tree.iter().any(|p| p.pid == child.pid) {
+                    tree.push(child);
+                    parents.push(child.pid);
+                }
+            }
+        }
+        tree
+    }
+
+    /// CPU percent of a process and all of its descendants.
+    pub fn tree_cpu(&self, root: u32) -> f32 {
+        self.tree(root).iter().map(|process| process.cpu).sum()
+    }
+}
+
+/// Cumulative CPU times of every process at one moment, the baseline the
+/// next sample is diffed against.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CpuBaseline {
+    /// Unix milliseconds
+    taken_at_ms: u64,
+    /// (pid, start time, CPU nanoseconds)
+    times: Vec<(u32, u64, u64)>,
+}
+
+impl crate::registry::JsonState for CpuBaseline {}
+
+impl CpuBaseline {
+    fn new(taken_at_ms: u64, snapshot: &Snapshot) -> Self {
+        Self {
+            taken_at_ms,
+            times: snapshot
+                .iter()
+                .map(|p| (p.pid, p.start_time, p.cpu_time_ns))
+                .collect(),
+        }
+    }
+
+    /// Whether a sample taken at `now_ms` can still be diffed against this.
+    fn is_fresh(&self, now_ms: u64) -> bool {
+        self.taken_at_ms < now_ms
+            && now_ms - self.taken_at_ms <= BASELINE_MAX_AGE.as_millis() as u64
+    }
+}
+
+/// Turns cumulative CPU times into percentages by diffing against the
+/// previous sample. Keeps the last sample so a resident process pays for
+/// the wait only once.
+#[derive(Debug, Default)]
+pub struct CpuSampler {
+    previous: Option<CpuBaseline>,
+}
+
+impl CpuSampler {
+    pub const fn new() -> Self {
+        Self { previous: None }
+    }
+
+    /// Sample CPU use, waiting for a second snapshot the first time.
+    pub fn sample(&mut self, table: &dyn ProcessTable) -> Snapshot {
+        if self.previous.is_none() {
+            self.previous = Some(CpuBaseline::new(now_ms(), &table.snapshot()));
+            std::thread::sleep(FIRST_SAMPLE_INTERVAL);
+        }
+        let taken_at_ms = now_ms();
+        let mut snapshot = table.snapshot();
+        if let Some(previous) = &self.previous {
+            apply_cpu_deltas(previous, taken_at_ms, &mut snapshot.processes);
+        }
+        self.previous = Some(CpuBaseline::new(taken_at_ms, &snapshot));
+        snapshot
+    }
+
+    /// Sample CPU use against a baseline saved by another process, without
+    /// waiting. `None` when the baseline is too old to mean much, or was
+    /// never saved.
+    pub fn sample_since(baseline: &CpuBaseline, table: &dyn ProcessTable) -> Option<Snapshot> {
+        let taken_at_ms = now_ms();
+        if !baseline.is_fresh(taken_at_ms) {
+            return None;
+        }
+        let mut snapshot = table.snapshot();
+        apply_cpu_deltas(baseline, taken_at_ms, &mut snapshot.processes);
+        Some(snapshot)
+    }
+
+    /// The last sample, for one-shot commands to diff against.
+    pub fn baseline(&self) -> Option<&CpuBaseline> {
+        self.previous.as_ref()
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Fill in `cpu` from CPU time spent since `previous`. Processes are matched
+/// on PID and start time, so a recycled PID starts from zero.
+fn apply_cpu_deltas(previous: &CpuBaseline, taken_at_ms: u64, processes: &mut [ProcessInfo]) {
+    let elapsed_ns = taken_at_ms.saturating_sub(previous.taken_at_ms) as f64 * 1_000_000.0;
+    if elapsed_ns <= 0.0 {
+        return;
+    }
+    let previous: CpuTimes = previous
+        .times
+        .iter()
+        .map(|&(pid, start_time, cpu_time_ns)| ((pid, start_time), cpu_time_ns))
+        .collect();
+    for process in processes {
+        let Some(before) = previous.get(&(process.pid, process.start_time)) else {
+            continue;
+        };
+        let spent = process.cpu_time_ns.saturating_sub(*before) as f64;
+        process.cpu = (spent / elapsed_ns * 100.0) as f32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn process(pid: u32, ppid: u32) -> ProcessInfo {
+        ProcessInfo {
+            pid,
+            ppid,
+            start_time: 1_000,
+            ..Default::default()
+        }
+    }
+
+    /// Table whose processes burn a fixed amount of CPU per snapshot.
+    struct FakeTable {
+        snapshots: Cell<u64>,
+    }
+
+    impl ProcessTable for FakeTable {
+        fn pids(&self) -> Vec<u32> {
+            self.snapshots.set(self.snapshots.get() + 1);
+            vec![1, 2]
+        }
+
+        fn process(&self, pid: u32) -> Option<ProcessInfo> {
+            Some(ProcessInfo {
+                cpu_time_ns: self.snapshots.get() * 100_000_000,
+                ..process(pid, 0)
+            })
+        }
+    }
+
+    #[test]
+    fn tree_follows_descendants_only() {
+        let snapshot = Snapshot::new(vec![
+            process(12, 11),
+            process(10, 1),
+            process(11, 10),
+            process(13, 1),
+        ]);
+
+        let tree: Vec<u32> = snapshot.tree(10).iter().map(|p| p.pid).collect();
+
+        assert_eq!(tree, vec![10, 11, 12]);
+        assert_eq!(snapshot.get(13).map(|p| p.ppid), Some(1));
+        assert!(snapshot.get(99).is_none());
+    }
+
+    #[test]
+    fn cpu_deltas_skip_recycled_pids() {
+        let previous = CpuBaseline {
+            taken_at_ms: 1_000,
+            times: vec![(1, 1_000, 0), (2, 500, 0)],
+        };
+        let mut processes = vec![
+            ProcessInfo {
+                cpu_time_ns: 500_000_000,
+                ..process(1, 0)
+            },
+            ProcessInfo {
+                cpu_time_ns: 500_000_000,
+                ..process(2, 0)
+            },
+        ];
+
+        apply_cpu_deltas(&previous, 2_000, &mut processes);
+
+        assert_eq!(processes[0].cpu, 50.0);
+        assert_eq!(processes[1].cpu, 0.0);
+    }
+
+    #[test]
+    fn sampler_reports_cpu_on_first_call() {
+        let table = FakeTable {
+            snapshots: Cell::new(0),
+        };
+        let mut sampler = CpuSampler::new();
+
+        let snapshot = sampler.sample(&table);
+
+        assert!(snapshot.get(1).unwrap().cpu > 0.0);
+        assert_eq!(snapshot.tree_cpu(1), snapshot.get(1).unwrap().cpu);
+    }
+
+    #[test]
+    fn one_shot_samples_need_a_fresh_baseline() {
+        let table = FakeTable {
+            snapshots: Cell::new(0),
+        };
+        let now = now_ms();
+        let baseline = |taken_at_ms| CpuBaseline {
+            taken_at_ms,
+            times: vec![(1, 1_000, 0)],
+        };
+
+        let snapshot = CpuSampler::sample_since(&baseline(now - 500), &table).unwrap();
+
+        assert!(snapshot.get(1).unwrap().cpu > 0.0);
+        assert!(CpuSampler::sample_since(&CpuBaseline::default(), &table).is_none());
+        let stale = baseline(now - BASELINE_MAX_AGE.as_millis() as u64 - 1_000);
+        assert!(CpuSampler::sample_since(&stale, &table).is_none());
+    }
+}
@@ -0,0 +1,310 @@
+//! Where session records, holds and sleep state live
+//!
+//! The registry used to be `/tmp/agents_working_pids`, which any local user
+//! could fill with records or plant symlinks in. It now lives in a directory
+//! only its owner can enter, and files in it are written under a temporary
+//! name and renamed into place, so a reader never sees half a record.
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// Points a process at a scratch directory: the registry, logs and history
+/// go under it and the sleep state is left alone. `asp bench-hook` runs the
+/// hooks it times this way.
+pub const SANDBOX_ENV: &str = "ASP_SANDBOX_DIR";
+
+pub fn sandbox() -> Option<&'static Path> {
+    static SANDBOX: OnceLock<Option<PathBuf>> = OnceLock::new();
+    SANDBOX
+        .get_or_init(|| {
+            std::env::var_os(SANDBOX_ENV)
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+        })
+        .as_deref()
+}
+
+/// Shared directories older versions kept records in
+pub const LEGACY_DIRS: [&str; 2] = ["/tmp/agents_working_pids", "/tmp/claude_working_pids"];
+
+/// The registry under `data_dir`, a user's local data directory.
+pub fn dir_in(data_dir: &Path) -> PathBuf {
+    data_dir.join("AgentsSleepPreventer").join("sessions")
+}
+
+/// The registry under the account's home directory from the password
+/// database, not `$HOME` or the XDG variables: hooks started by an agent and
+/// a daemon started by launchd or systemd get different environments but
+/// must find the same records.
+fn default_dir() -> PathBuf {
+    if let Some(sandbox) = sandbox() {
+        return sandbox.join("sessions");
+    }
+    let home = account_home()
+        .or_else(dirs::home_dir)
+        .unwrap_or_else(|| PathBuf::from("/tmp"));
+    #[cfg(target_os = "macos")]
+    return dir_in(&home.join("Library/Application Support"));
+    #[cfg(not(target_os = "macos"))]
+    dir_in(&home.join(".local/share"))
+}
+
+fn account_home() -> Option<PathBuf> {
+    use std::ffi::CStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let passwd = unsafe { libc::getpwuid(libc::geteuid()) };
+    if passwd.is_null() || unsafe { (*passwd).pw_dir.is_null() } {
+        return None;
+    }
+    let home = unsafe { CStr::from_ptr((*passwd).pw_dir) };
+    (!home.to_bytes().is_empty())
+        .then(|| PathBuf::from(std::ffi::OsStr::from_bytes(home.to_bytes())))
+}
+
+/// The registry directory. Records left in the old shared directories are
+/// moved in the first time it's asked for, so readers see them too.
+pub fn dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = default_dir();
+        let has_legacy = LEGACY_DIRS
+            .iter()
+            .any(|legacy| fs::symlink_metadata(legacy).is_ok());
+        if has_legacy && ensure(&dir).is_ok() {
+            migrate_legacy(&dir);
+        }
+        dir
+    })
+}
+
+/// Create `dir` private to the current user. Refuses a symlink or a
+/// directory someone else owns.
+pub fn ensure(dir: &Path) -> Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let metadata = fs::symlink_metadata(dir)
+        .with_context(|| format!("Failed to inspect {}", dir.display()))?;
+    if !metadata.is_dir() {
+        anyhow::bail!("{} is not a directory", dir.display());
+    }
+    if metadata.uid() != unsafe { libc::geteuid() } {
+        anyhow::bail!("{} belongs to another user", dir.display());
+    }
+    if metadata.mode() & 0o777 != 0o700 {
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+            .with_context(|| format!("Failed to restrict {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Move records left in the old shared directories into `dir`. A sandbox
+/// leaves them for the real registry.
+pub fn migrate_legacy(dir: &Path) -> usize {
+    if sandbox().is_some() {
+        return 0;
+    }
+    let moved = LEGACY_DIRS
+        .iter()
+        .map(|legacy| migrate(Path::new(legacy), dir))
+        .sum();
+    if moved > 0 {
+        crate::logging::log(&format!(
+            "[registry] Moved {} records to {}",
+            moved,
+            dir.display()
+        ));
+    }
+    moved
+}
+
+/// Move the files we own out of `legacy` into `dir`, then remove `legacy`
+/// if that emptied it. Symlinks and other users' files are left alone.
+/// Returns how many files moved.
+pub fn migrate(legacy: &Path, dir: &Path) -> usize {
+    if !fs::symlink_metadata(legacy).is_ok_and(|metadata| metadata.is_dir()) {
+        return 0;
+    }
+
+    let uid = unsafe { libc::geteuid() };
+    let mut moved = 0;
+    for entry in fs::read_dir(legacy).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let owned_file = fs::symlink_metadata(&path)
+            .is_ok_and(|metadata| metadata.is_file() && metadata.uid() == uid);
+        if !owned_file {
+            continue;
+        }
+        let target = dir.join(entry.file_name());
+        if !target.exists() {
+            let copied = fs::read(&path)
+                .ok()
+                .is_some_and(|content| write_atomic(&target, content).is_ok());
+            if !copied {
+                continue;
+            }
+            moved += 1;
+        }
+        let _ = fs::remove_file(&path);
+    }
+    let _ = fs::remove_dir(legacy);
+    moved
+}
+
+/// Whether `name` is one of the temporary files `write_atomic` leaves while
+/// writing.
+pub fn is_temporary(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".tmp")
+}
+
+/// Write `path` through a temporary file next to it, so readers see either
+/// the old contents or the new ones.
+pub fn write_atomic(path: &Path, content: impl AsRef<[u8]>) -> Result<()> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("Invalid path {}", path.display()))?
+        .to_string_lossy();
+    // Unique per call: threads of one process may write the same file.
+    static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp, content).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        anyhow::Error::new(e).context(format!("Failed to write {}", path.display()))
+    })
+}
+
+/// Small state kept between checks in a JSON file in the registry, so
+/// short-lived hook processes agree with the resident process.
+pub trait JsonState: Serialize + DeserializeOwned + Default {
+    /// The saved state, or the default when there's none or it can't be read.
+    fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Best effort: losing the state only means starting over from the
+    /// default on the next check.
+    fn save(&self, path: &Path) {
+        if let Ok(content) = serde_json::to_string(self) {
+            let _ = write_atomic(path, content);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("asp-registry-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn ensure_makes_a_private_directory() {
+        let root = temp_dir("ensure");
+        let dir = root.join("sessions");
+
+        ensure(&dir).unwrap();
+        assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        ensure(&dir).unwrap();
+        assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+
+        let link = root.join("link");
+        symlink(&dir, &link).unwrap();
+        assert!(ensure(&link).is_err());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn migrate_moves_owned_files_but_not_symlinks() {
+        let root = temp_dir("migrate");
+        let legacy = root.join("legacy");
+        let dir = root.join("sessions");
+        fs::create_dir_all(&legacy).unwrap();
+        ensure(&dir).unwrap();
+        fs::write(legacy.join("42"), "working").unwrap();
+        fs::write(legacy.join("hold-1.json"), "{}").unwrap();
+        fs::write(root.join("secret"), "x").unwrap();
+        symlink(root.join("secret"), legacy.join("43")).unwrap();
+
+        assert_eq!(migrate(&legacy, &dir), 2);
+
+        assert_eq!(fs::read_to_string(dir.join("42")).unwrap(), "working");
+        assert!(dir.join("hold-1.json").exists());
+        assert!(!dir.join("43").exists());
+        assert!(legacy.join("43").exists());
+        assert_eq!(migrate(&root.join("missing"), &dir), 0);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn write_atomic_replaces_and_cleans_up() {
+        let dir = temp_dir("write");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session-abc");
+
+        write_atomic(&path, "one").unwrap();
+        write_atomic(&path, "two").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two");
+        let names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["session-abc"]);
+        assert!(is_temporary(&format!(
+            ".session-abc.{}.0.tmp",
+            std::process::id()
+        )));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn write_atomic_from_several_threads() {
+        let dir = temp_dir("threads");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("status.json");
+
+        let writers = (0..8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        write_atomic(&path, i.to_string()).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert!(fs::read_to_string(&path).unwrap().parse::<u32>().unwrap() < 8);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
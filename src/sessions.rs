@@ -0,0 +1,436 @@
+//! Session records stored in the PID registry
+//!
+//! Each working agent session gets one JSON file in the registry directory,
+//! named after the agent's `session_id` when the hook payload carries one and
+//! after the agent PID otherwise. Older releases wrote the literal string
+//! `working` into PID-named files; those are still read back as minimal
+//! records so an upgrade doesn't drop running sessions.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::detection::AgentKind;
+
+/// Bump when the on-disk shape changes incompatibly.
+pub const SESSION_RECORD_VERSION: u32 = 1;
+
+/// A tool call that got `PreToolUse` but no `PostToolUse` yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpenTool {
+    /// `tool_use_id` from the payload, or the tool name when there is none
+    pub id: String,
+    pub started_at: u64,
+}
+
+/// Where a session is in its lifecycle, driven by hook events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    #[default]
+    Working,
+    RunningTool,
+    Compacting,
+    WaitingForPermission,
+    Idle,
+    /// Stopped preventing sleep after hitting the safety cap
+    Expired,
+    Ended,
+}
+
+impl SessionState {
+    /// Whether a session in this state should keep the Mac awake.
+    pub fn prevents_sleep(self) -> bool {
+        matches!(
+            self,
+            SessionState::Working | SessionState::RunningTool | SessionState::Compacting
+        )
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SessionState::Working => "working",
+            SessionState::RunningTool => "running_tool",
+            SessionState::Compacting => "compacting",
+            SessionState::WaitingForPermission => "waiting_for_permission",
+            SessionState::Idle => "idle",
+            SessionState::Expired => "expired",
+            SessionState::Ended => "ended",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub version: u32,
+    pub pid: u32,
+    /// Agent-provided session id; `None` for PID-keyed sessions
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub agent: Option<AgentKind>,
+    #[serde(default)]
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub git_branch: Option<String>,
+    /// Unix seconds when the session was first registered
+    pub started_at: u64,
+    /// Unix seconds of the most recent hook for this session
+    pub last_heartbeat: u64,
+    /// Hook event that created the record
+    #[serde(default)]
+    pub created_by: String,
+    /// Unix seconds when the agent process itself started
+    #[serde(default)]
+    pub process_start_time: Option<u64>,
+    /// Executable of the agent process
+    #[serde(default)]
+    pub process_exe: Option<String>,
+    #[serde(default)]
+    pub state: SessionState,
+    /// Unix seconds of the last state transition
+    #[serde(default)]
+    pub state_changed_at: u64,
+    #[serde(default)]
+    pub open_tools: Vec<OpenTool>,
+    /// Unix seconds when the session last started preventing sleep
+    #[serde(default)]
+    pub preventing_since: Option<u64>,
+    /// Deadline set by `asp extend`, overriding the safety cap until then
+    #[serde(default)]
+    pub cap_extended_until: Option<u64>,
+    /// Why the safety cap expired this session
+    #[serde(default)]
+    pub expired_reason: Option<String>,
+    /// Command line of a process kept awake by `asp run` or `asp watch`.
+    /// Such sessions last as long as the process, not until a hook says so.
+    #[serde(default)]
+    pub command: Option<String>,
+    /// Unix seconds since the machine has been kept awake for this session,
+    /// for the history. `None` while it isn't, even if the session is busy.
+    #[serde(default)]
+    pub awake_since: Option<u64>,
+}
+
+impl SessionRecord {
+    pub fn new(pid: u32, created_by: &str, now: u64) -> Self {
+        Self {
+            version: SESSION_RECORD_VERSION,
+            pid,
+            session_id: None,
+            agent: None,
+            cwd: None,
+            git_branch: None,
+            started_at: now,
+            last_heartbeat: now,
+            created_by: created_by.to_string(),
+            process_start_time: None,
+            process_exe: None,
+            state: SessionState::Working,
+            state_changed_at: now,
+            open_tools: Vec::new(),
+            preventing_since: Some(now),
+            cap_extended_until: None,
+            expired_reason: None,
+            command: None,
+            awake_since: None,
+        }
+    }
+
+    /// Record for a pre-JSON `working` file, dated by its mtime.
+    fn legacy(pid: u32, modified: u64) -> Self {
+        Self {
+            version: 0,
+            ..Self::new(pid, "legacy", modified)
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version < SESSION_RECORD_VERSION
+    }
+
+    /// Whether hooks drive this session, as opposed to the lifetime of a
+    /// process started or watched by `asp`.
+    pub fn is_hook_driven(&self) -> bool {
+        self.command.is_none()
+    }
+
+    /// Seconds since the last hook touched this session.
+    pub fn idle_secs(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_heartbeat)
+    }
+
+    /// Whether a live process with this record's PID is the agent that
+    /// registered it. Anything we didn't record at registration is not
+    /// compared, so legacy records only get the PID check.
+    pub fn matches_process(&self, start_time: u64, exe: Option<&str>) -> bool {
+        let same_start = self
+            .process_start_time
+            .is_none_or(|recorded| recorded == start_time);
+        let same_exe = match (self.process_exe.as_deref(), exe) {
+            (Some(recorded), Some(current)) => recorded == current,
+            _ => true,
+        };
+        same_start && same_exe
+    }
+
+    /// When the oldest still-open tool call started.
+    pub fn oldest_open_tool(&self) -> Option<u64> {
+        self.open_tools.iter().map(|tool| tool.started_at).min()
+    }
+
+    pub fn set_state(&mut self, state: SessionState, now: u64) {
+        if self.state == state {
+            return;
+        }
+        match (self.state.prevents_sleep(), state.prevents_sleep()) {
+            (false, true) => self.preventing_since = Some(now),
+            (true, false) => {
+                self.preventing_since = None;
+                self.cap_extended_until = None;
+            }
+            _ => {}
+        }
+        if self.state == SessionState::Expired {
+            self.expired_reason = None;
+        }
+        self.state = state;
+        self.state_changed_at = now;
+    }
+
+    /// When the session started preventing sleep this time. Records written
+    /// before this was tracked fall back to their last state change.
+    pub fn preventing_since(&self) -> Option<u64> {
+        if !self.state.prevents_sleep() {
+            return None;
+        }
+        Some(self.preventing_since.unwrap_or(self.state_changed_at))
+    }
+
+    /// `project git:(branch)` label shown in the menu and popover.
+    pub fn location(&self) -> String {
+        let Some(cwd) = self.cwd.as_deref() else {
+            return "unknown".to_string();
+        };
+        let dir_name = Path::new(cwd)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| cwd.to_string());
+        match &self.git_branch {
+            Some(branch) => format!("{} git:({})", dir_name, branch),
+            None => dir_name,
+        }
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn modified_secs(path: &Path) -> Option<u64> {
+    fs::metadata(path)
+        .ok()?
+        .modified()
+        .ok()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
+
+/// Keep only characters that are safe in a file name.
+fn sanitize_session_id(session_id: &str) -> String {
+    session_id
+        .chars()
+        .filter(|ch| ch.is_ascii_alphanumeric() || *ch == '-' || *ch == '_')
+        .take(128)
+        .collect()
+}
+
+/// Registry path for a session: by session id when we have a usable one,
+/// otherwise by agent PID.
+pub fn record_path(dir: &Path, session_id: Option<&str>, pid: u32) -> PathBuf {
+    match session_id.map(sanitize_session_id) {
+        Some(name) if !name.is_empty() => dir.join(format!("session-{}", name)),
+        _ => dir.join(pid.to_string()),
+    }
+}
+
+/// Read one registry file. Unparseable files are only accepted as legacy
+/// records when they're named after a PID.
+pub fn read_record(path: &Path) -> Option<SessionRecord> {
+    let content = fs::read_to_string(path).ok()?;
+    if let Ok(record) = serde_json::from_str::<SessionRecord>(&content) {
+        return Some(record);
+    }
+    let pid: u32 = path.file_name()?.to_string_lossy().parse().ok()?;
+    Some(SessionRecord::legacy(
+        pid,
+        modified_secs(path).unwrap_or_else(now_secs),
+    ))
+}
+
+pub fn write_record(path: &Path, record: &SessionRecord) -> Result<()> {
+    let content = serde_json::to_string(record).context("Failed to serialize session")?;
+    crate::registry::write_atomic(path, content)
+}
+
+/// Every readable session in the registry, sorted by PID. Files still
+/// being written are skipped.
+pub fn load_records(dir: &Path) -> Vec<(PathBuf, SessionRecord)> {
+    let mut records = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|entry| {
+                    !crate::registry::is_temporary(&entry.file_name().to_string_lossy())
+                })
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    read_record(&path).map(|record| (path, record))
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    records.sort_by_key(|(_, record)| record.pid);
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_registry(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("asp-sessions-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let dir = temp_registry("roundtrip");
+        let mut record = SessionRecord::new(4242, "start", 1_700_000_000);
+        record.agent = Some(AgentKind::new("codex"));
+        record.cwd = Some("/Users/me/src/asp".to_string());
+        record.git_branch = Some("main".to_string());
+        record.process_start_time = Some(1_699_999_000);
+        record.process_exe = Some("/Users/me/.local/bin/codex".to_string());
+
+        let path = dir.join("4242");
+        write_record(&path, &record).unwrap();
+
+        assert_eq!(read_record(&path), Some(record));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn legacy_working_file_is_readable() {
+        let dir = temp_registry("legacy");
+        let path = dir.join("99");
+        fs::write(&path, "working").unwrap();
+
+        let record = read_record(&path).unwrap();
+
+        assert_eq!(record.pid, 99);
+        assert!(record.is_legacy());
+        assert_eq!(record.location(), "unknown");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn non_pid_files_are_ignored() {
+        let dir = temp_registry("ignored");
+        fs::write(dir.join(".DS_Store"), "").unwrap();
+        fs::write(dir.join("12"), "working").unwrap();
+
+        let records = load_records(&dir);
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].1.pid, 12);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn record_path_prefers_session_id() {
+        let dir = Path::new("/tmp/registry");
+
+        assert_eq!(
+            record_path(dir, Some("3f2a-b1/../x"), 7),
+            dir.join("session-3f2a-b1x")
+        );
+        assert_eq!(record_path(dir, None, 7), dir.join("7"));
+        assert_eq!(record_path(dir, Some("/.."), 7), dir.join("7"));
+    }
+
+    #[test]
+    fn two_sessions_can_share_a_pid() {
+        let dir = temp_registry("shared-pid");
+        for id in ["first", "second"] {
+            let mut record = SessionRecord::new(500, "UserPromptSubmit", 0);
+            record.session_id = Some(id.to_string());
+            write_record(&record_path(&dir, Some(id), 500), &record).unwrap();
+        }
+
+        let records = load_records(&dir);
+
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|(_, record)| record.pid == 500));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn records_without_state_default_to_working() {
+        let record: SessionRecord =
+            serde_json::from_str(r#"{"version":1,"pid":3,"started_at":10,"last_heartbeat":20}"#)
+                .unwrap();
+
+        assert_eq!(record.state, SessionState::Working);
+        assert!(record.state.prevents_sleep());
+    }
+
+    #[test]
+    fn reused_pid_does_not_match() {
+        let mut record = SessionRecord::new(7, "UserPromptSubmit", 0);
+        record.process_start_time = Some(1_000);
+        record.process_exe = Some("/opt/homebrew/bin/claude".to_string());
+
+        assert!(record.matches_process(1_000, Some("/opt/homebrew/bin/claude")));
+        assert!(record.matches_process(1_000, None));
+        assert!(!record.matches_process(2_000, Some("/opt/homebrew/bin/claude")));
+        assert!(!record.matches_process(1_000, Some("/usr/sbin/cupsd")));
+
+        let legacy = SessionRecord::legacy(7, 0);
+        assert!(legacy.matches_process(2_000, Some("/usr/sbin/cupsd")));
+    }
+
+    #[test]
+    fn preventing_since_survives_busy_transitions() {
+        let mut record = SessionRecord::new(1, "UserPromptSubmit", 10);
+
+        record.set_state(SessionState::RunningTool, 20);
+        record.set_state(SessionState::Compacting, 30);
+        assert_eq!(record.preventing_since(), Some(10));
+
+        record.set_state(SessionState::Idle, 40);
+        assert_eq!(record.preventing_since(), None);
+
+        record.set_state(SessionState::Working, 50);
+        assert_eq!(record.preventing_since(), Some(50));
+    }
+
+    #[test]
+    fn location_includes_branch() {
+        let mut record = SessionRecord::new(1, "start", 0);
+        record.cwd = Some("/Users/me/src/asp".to_string());
+        assert_eq!(record.location(), "asp");
+
+        record.git_branch = Some("feature/x".to_string());
+        assert_eq!(record.location(), "asp git:(feature/x)");
+    }
+}
@@ -1,21 +1,71 @@
 //! Application settings with JSON persistence
 
+#[cfg(target_os = "macos")]
 pub mod window;
 
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::PathBuf;
 
+use crate::cap::SafetyCap;
+use crate::policy::PolicyRule;
+use crate::thermal::ThermalThresholds;
+
+/// How sleep is prevented
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerBackendKind {
+    /// `pmset disablesleep`: works with the lid closed, needs sudo
+    #[cfg_attr(target_os = "macos", default)]
+    Pmset,
+    /// IOPMAssertion held by the running app: no sudo, lid close still sleeps
+    Assertion,
+    /// systemd-logind inhibitor lock held by the daemon (Linux)
+    #[cfg_attr(not(target_os = "macos"), default)]
+    Logind,
+}
+
+impl PowerBackendKind {
+    #[cfg(target_os = "macos")]
+    pub const ALL: [PowerBackendKind; 2] = [PowerBackendKind::Pmset, PowerBackendKind::Assertion];
+
+    #[cfg(target_os = "macos")]
+    pub fn label(self) -> &'static str {
+        match self {
+            PowerBackendKind::Pmset => "pmset (stays awake with the lid closed)",
+            PowerBackendKind::Assertion => "Power assertion (no sudo, lid close sleeps)",
+            PowerBackendKind::Logind => "logind inhibitor lock",
+        }
+    }
+}
+
 /// Sleep prevention settings
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SleepPreventionSettings {
     #[serde(default = "default_true")]
     pub enabled: bool,
+    #[serde(default)]
+    pub backend: PowerBackendKind,
+    /// Battery and lid rules that can let the machine sleep anyway
+    #[serde(default = "crate::policy::default_rules")]
+    pub policy_rules: Vec<PolicyRule>,
+    /// When heat pauses sleep prevention and when it resumes
+    #[serde(default)]
+    pub thermal: ThermalThresholds,
+    /// Longest stretch sleep may be prevented, overall and per session
+    #[serde(default)]
+    pub safety_cap: SafetyCap,
 }
 
 impl Default for SleepPreventionSettings {
     fn default() -> Self {
-        Self { enabled: true }
+        Self {
+            enabled: true,
+            backend: PowerBackendKind::default(),
+            policy_rules: crate::policy::default_rules(),
+            thermal: ThermalThresholds::default(),
+            safety_cap: SafetyCap::default(),
+        }
     }
 }
 
@@ -89,6 +139,7 @@ impl AppSettings {
     }
 
     /// Save settings to disk
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
     pub fn save(&self) -> Result<(), String> {
         let path = Self::settings_path();
 
@@ -105,6 +156,7 @@ impl AppSettings {
     }
 
     /// Get the list of supported languages for speech-to-text
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
     pub fn supported_languages() -> Vec<(&'static str, &'static str)> {
         vec![
             ("auto", "Auto-detect"),
@@ -136,11 +188,25 @@ mod tests {
             .contains(&"Claude".to_string()));
     }
 
+    #[test]
+    fn test_deserialize_backend() {
+        let json = r#"{"sleep_prevention": {"enabled": true, "backend": "assertion"}}"#;
+        let settings: AppSettings = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            settings.sleep_prevention.backend,
+            PowerBackendKind::Assertion
+        );
+    }
+
     #[test]
     fn test_deserialize_partial() {
         let json = r#"{"sleep_prevention": {"enabled": false}}"#;
         let settings: AppSettings = serde_json::from_str(json).unwrap();
         assert!(!settings.sleep_prevention.enabled);
+        assert_eq!(
+            settings.sleep_prevention.backend,
+            PowerBackendKind::default()
+        );
         // speech_to_text should have defaults
         assert_eq!(settings.speech_to_text.language, "auto");
     }
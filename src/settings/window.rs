@@ -12,7 +12,7 @@ use crate::objc_utils::{
     NS_BACKING_STORE_BUFFERED,
 };
 
-use super::AppSettings;
+use super::{AppSettings, PowerBackendKind};
 
 const NS_WINDOW_STYLE_MASK_TITLED: usize = 1 << 0;
 const NS_WINDOW_STYLE_MASK_CLOSABLE: usize = 1 << 1;
@@ -110,6 +110,11 @@ impl SettingsState {
         settings.sleep_prevention.enabled = enabled;
     }
 
+    fn update_backend(&self, backend: PowerBackendKind) {
+        let mut settings = self.settings.lock().unwrap();
+        settings.sleep_prevention.backend = backend;
+    }
+
     fn update_language(&self, language: String) {
         let mut settings = self.settings.lock().unwrap();
         settings.speech_to_text.language = language;
@@ -152,6 +157,19 @@ extern "C" fn toggle_changed(this: &Object, _: Sel, sender: Id) {
     }
 }
 
+extern "C" fn backend_changed(this: &Object, _: Sel, sender: Id) {
+    unsafe {
+        let state_ptr: *mut c_void = *this.get_ivar("rustState");
+        if !state_ptr.is_null() {
+            let state = &*(state_ptr as *const SettingsState);
+            let selected_index: i64 = msg_send![sender, indexOfSelectedItem];
+            if let Some(backend) = PowerBackendKind::ALL.get(selected_index as usize) {
+                state.update_backend(*backend);
+            }
+        }
+    }
+}
+
 extern "C" fn language_changed(this: &Object, _: Sel, sender: Id) {
     unsafe {
         let state_ptr: *mut c_void = *this.get_ivar("rustState");
@@ -205,6 +223,10 @@ fn settings_target_class() -> &'static objc::runtime::Class {
                 sel!(toggleChanged:),
                 toggle_changed as extern "C" fn(&Object, Sel, Id),
             );
+            decl.add_method(
+                sel!(backendChanged:),
+                backend_changed as extern "C" fn(&Object, Sel, Id),
+            );
             decl.add_method(
                 sel!(languageChanged:),
                 language_changed as extern "C" fn(&Object, Sel, Id),
@@ -344,6 +366,30 @@ impl SettingsWindow {
                 let _: () = msg_send![checkbox, setAction: sel!(toggleChanged:)];
                 let _: () = msg_send![tab1_view, addSubview: checkbox];
 
+                // Sleep prevention method
+                let backend_label_frame =
+                    NSRect::new(NSPoint::new(20.0, 35.0), NSSize::new(70.0, 20.0));
+                let backend_label =
+                    create_label("Method", backend_label_frame, body_font, title_color);
+                let _: () = msg_send![tab1_view, addSubview: backend_label];
+
+                let backend_frame = NSRect::new(NSPoint::new(90.0, 30.0), NSSize::new(320.0, 26.0));
+                let backend_popup: Id = msg_send![class!(NSPopUpButton), alloc];
+                let backend_popup: Id =
+                    msg_send![backend_popup, initWithFrame: backend_frame pullsDown: false as BOOL];
+                let mut selected_backend: i64 = 0;
+                for (i, backend) in PowerBackendKind::ALL.iter().enumerate() {
+                    let _: () =
+                        msg_send![backend_popup, addItemWithTitle: nsstring(backend.label())];
+                    if *backend == settings.sleep_prevention.backend {
+                        selected_backend = i as i64;
+                    }
+                }
+                let _: () = msg_send![backend_popup, selectItemAtIndex: selected_backend];
+                let _: () = msg_send![backend_popup, setTarget: target];
+                let _: () = msg_send![backend_popup, setAction: sel!(backendChanged:)];
+                let _: () = msg_send![tab1_view, addSubview: backend_popup];
+
                 let _: () = msg_send![tab1, setView: tab1_view];
                 let _: () = msg_send![tab_view, addTabViewItem: tab1];
 
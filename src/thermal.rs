@@ -0,0 +1,377 @@
+//! Thermal state and when to stop keeping a hot machine awake
+//!
+//! `pmset -g therm` reports CPU limits and warning levels; macOS also
+//! publishes a thermal pressure level through notifyd. Both are folded into
+//! one [`ThermalLevel`]. [`ThermalMonitor`] pauses sleep prevention once the
+//! level reaches a threshold and only resumes after the machine has stayed
+//! cool for a while, so it doesn't flap around the limit.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::registry::JsonState;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThermalLevel {
+    #[default]
+    Nominal,
+    Fair,
+    Serious,
+    Critical,
+}
+
+impl ThermalLevel {
+    pub fn label(self) -> &'static str {
+        match self {
+            ThermalLevel::Nominal => "nominal",
+            ThermalLevel::Fair => "fair",
+            ThermalLevel::Serious => "serious",
+            ThermalLevel::Critical => "critical",
+        }
+    }
+
+    /// Level for a notifyd thermal pressure value (nominal, moderate,
+    /// heavy, trapping, sleeping).
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    pub fn from_pressure(pressure: u64) -> Self {
+        match pressure {
+            0 => ThermalLevel::Nominal,
+            1 => ThermalLevel::Fair,
+            2 => ThermalLevel::Serious,
+            _ => ThermalLevel::Critical,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ThermalState {
+    /// notifyd thermal pressure, where available
+    pub pressure: Option<ThermalLevel>,
+    /// Percent of full CPU speed allowed
+    pub cpu_speed_limit: Option<u8>,
+    /// Percent of CPU time the scheduler hands out
+    pub scheduler_limit: Option<u8>,
+    /// Thermal warning level, 0 when none was recorded
+    pub warning_level: Option<u32>,
+}
+
+impl ThermalState {
+    pub fn level(&self) -> ThermalLevel {
+        let speed = match self.cpu_speed_limit {
+            Some(limit) if limit < 50 => ThermalLevel::Critical,
+            Some(limit) if limit < 80 => ThermalLevel::Serious,
+            Some(limit) if limit < 100 => ThermalLevel::Fair,
+            _ => ThermalLevel::Nominal,
+        };
+        let scheduler = match self.scheduler_limit {
+            Some(limit) if limit < 100 => ThermalLevel::Fair,
+            _ => ThermalLevel::Nominal,
+        };
+        let warning = match self.warning_level {
+            Some(level) if level > 0 => ThermalLevel::Serious,
+            _ => ThermalLevel::Nominal,
+        };
+        [self.pressure.unwrap_or_default(), speed, scheduler, warning]
+            .into_iter()
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for ThermalState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.level().label())?;
+        let mut details = Vec::new();
+        if let Some(pressure) = self.pressure {
+            details.push(format!("pressure {}", pressure.label()));
+        }
+        if let Some(limit) = self.cpu_speed_limit {
+            details.push(format!("CPU speed {}%", limit));
+        }
+        if let Some(limit) = self.scheduler_limit {
+            details.push(format!("scheduler {}%", limit));
+        }
+        if let Some(level) = self.warning_level.filter(|level| *level > 0) {
+            details.push(format!("warning level {}", level));
+        }
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse `pmset -g therm`:
+///
+/// ```text
+/// Note: No thermal warning level has been recorded
+/// 2024-05-02 10:11:12 +0200 CPU Power notify
+///     CPU_Scheduler_Limit     = 100
+///     CPU_Available_CPUs      = 10
+///     CPU_Speed_Limit         = 100
+/// ```
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+pub fn parse_pmset_therm(output: &str) -> ThermalState {
+    let mut state = ThermalState::default();
+    for line in output.lines() {
+        let line = line.trim();
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim().parse().ok();
+            match key.trim() {
+                "CPU_Speed_Limit" => state.cpu_speed_limit = value,
+                "CPU_Scheduler_Limit" => state.scheduler_limit = value,
+                _ => {}
+            }
+        } else if line.to_lowercase().contains("thermal warning level") {
+            state.warning_level = if line.contains("No thermal warning") {
+                Some(0)
+            } else {
+                line.split(|c: char| !c.is_ascii_digit())
+                    .filter_map(|n| n.parse().ok())
+                    .next_back()
+            };
+        }
+    }
+    state
+}
+
+#[cfg(target_os = "macos")]
+pub fn read() -> ThermalState {
+    let mut state = std::process::Command::new("pmset")
+        .args(["-g", "therm"])
+        .output()
+        .ok()
+        .map(|output| parse_pmset_therm(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or_default();
+    state.pressure = pressure::read().map(ThermalLevel::from_pressure);
+    state
+}
+
+/// No thermal source on Linux yet.
+#[cfg(not(target_os = "macos"))]
+pub fn read() -> ThermalState {
+    ThermalState::default()
+}
+
+#[cfg(target_os = "macos")]
+mod pressure {
+    use std::os::raw::{c_char, c_int};
+
+    const NOTIFY_STATUS_OK: u32 = 0;
+    const THERMAL_PRESSURE_NOTIFICATION: &std::ffi::CStr = c"com.apple.system.thermalpressurelevel";
+
+    extern "C" {
+        fn notify_register_check(name: *const c_char, out_token: *mut c_int) -> u32;
+        fn notify_get_state(token: c_int, state: *mut u64) -> u32;
+        fn notify_cancel(token: c_int) -> u32;
+    }
+
+    /// Current thermal pressure level posted by the kernel through notifyd.
+    pub fn read() -> Option<u64> {
+        let mut token: c_int = 0;
+        if unsafe { notify_register_check(THERMAL_PRESSURE_NOTIFICATION.as_ptr(), &mut token) }
+            != NOTIFY_STATUS_OK
+        {
+            return None;
+        }
+        let mut state: u64 = 0;
+        let status = unsafe { notify_get_state(token, &mut state) };
+        unsafe {
+            notify_cancel(token);
+        }
+        (status == NOTIFY_STATUS_OK).then_some(state)
+    }
+}
+
+/// When to pause sleep prevention for heat, and when to resume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThermalThresholds {
+    /// Pause once the level reaches this
+    #[serde(default = "default_pause_at")]
+    pub pause_at: ThermalLevel,
+    /// Resume once the level is at or below this...
+    #[serde(default = "default_resume_at")]
+    pub resume_at: ThermalLevel,
+    /// ...for this many seconds
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+impl Default for ThermalThresholds {
+    fn default() -> Self {
+        Self {
+            pause_at: default_pause_at(),
+            resume_at: default_resume_at(),
+            cooldown_secs: default_cooldown_secs(),
+        }
+    }
+}
+
+fn default_pause_at() -> ThermalLevel {
+    ThermalLevel::Serious
+}
+
+fn default_resume_at() -> ThermalLevel {
+    ThermalLevel::Fair
+}
+
+fn default_cooldown_secs() -> u64 {
+    120
+}
+
+/// Thermal level and pause state carried between checks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThermalMonitor {
+    pub level: ThermalLevel,
+    pub paused: bool,
+    /// When the level first dropped to `resume_at` while paused
+    #[serde(default)]
+    pub cool_since: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThermalTransition {
+    pub from: ThermalLevel,
+    pub to: ThermalLevel,
+    pub was_paused: bool,
+    pub paused: bool,
+}
+
+impl fmt::Display for ThermalTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.from.label(), self.to.label())?;
+        match (self.was_paused, self.paused) {
+            (false, true) => write!(f, ", pausing sleep prevention"),
+            (true, false) => write!(f, ", resuming sleep prevention"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl JsonState for ThermalMonitor {}
+
+impl ThermalMonitor {
+    /// Feed the current level. Returns a transition when the level or the
+    /// pause state changed.
+    pub fn update(
+        &mut self,
+        level: ThermalLevel,
+        thresholds: &ThermalThresholds,
+        now: u64,
+    ) -> Option<ThermalTransition> {
+        let before = *self;
+
+        if level >= thresholds.pause_at {
+            self.paused = true;
+            self.cool_since = None;
+        } else if self.paused && level <= thresholds.resume_at {
+            let cool_since = *self.cool_since.get_or_insert(now);
+            if now.saturating_sub(cool_since) >= thresholds.cooldown_secs {
+                self.paused = false;
+                self.cool_since = None;
+            }
+        } else {
+            self.cool_since = None;
+        }
+        self.level = level;
+
+        (before.level != self.level || before.paused != self.paused).then_some(ThermalTransition {
+            from: before.level,
+            to: self.level,
+            was_paused: before.paused,
+            paused: self.paused,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOMINAL: &str = "\
+Note: No thermal warning level has been recorded
+Note: No performance warning level has been recorded
+2024-05-02 10:11:12 +0200 CPU Power notify
+\tCPU_Scheduler_Limit \t= 100
+\tCPU_Available_CPUs \t= 10
+\tCPU_Speed_Limit \t= 100
+";
+
+    const THROTTLED: &str = "\
+Thermal warning level set to 2.
+2024-05-02 10:15:40 +0200 CPU Power notify
+\tCPU_Scheduler_Limit \t= 90
+\tCPU_Available_CPUs \t= 10
+\tCPU_Speed_Limit \t= 62
+";
+
+    #[test]
+    fn parses_pmset_therm() {
+        let nominal = parse_pmset_therm(NOMINAL);
+        assert_eq!(
+            nominal,
+            ThermalState {
+                pressure: None,
+                cpu_speed_limit: Some(100),
+                scheduler_limit: Some(100),
+                warning_level: Some(0),
+            }
+        );
+        assert_eq!(nominal.level(), ThermalLevel::Nominal);
+
+        let throttled = parse_pmset_therm(THROTTLED);
+        assert_eq!(throttled.cpu_speed_limit, Some(62));
+        assert_eq!(throttled.scheduler_limit, Some(90));
+        assert_eq!(throttled.warning_level, Some(2));
+        assert_eq!(throttled.level(), ThermalLevel::Serious);
+        assert_eq!(
+            throttled.to_string(),
+            "serious (CPU speed 62%, scheduler 90%, warning level 2)"
+        );
+    }
+
+    #[test]
+    fn pressure_raises_level() {
+        let state = ThermalState {
+            pressure: Some(ThermalLevel::from_pressure(3)),
+            ..parse_pmset_therm(NOMINAL)
+        };
+
+        assert_eq!(state.level(), ThermalLevel::Critical);
+    }
+
+    #[test]
+    fn monitor_resumes_only_after_cooling_down() {
+        let thresholds = ThermalThresholds {
+            cooldown_secs: 60,
+            ..Default::default()
+        };
+        let mut monitor = ThermalMonitor::default();
+
+        assert_eq!(monitor.update(ThermalLevel::Nominal, &thresholds, 0), None);
+        let hot = monitor
+            .update(ThermalLevel::Serious, &thresholds, 10)
+            .unwrap();
+        assert!(hot.paused);
+        assert_eq!(
+            hot.to_string(),
+            "nominal -> serious, pausing sleep prevention"
+        );
+
+        // Cooling to resume_at starts the cooldown; heating up again cancels it.
+        monitor.update(ThermalLevel::Fair, &thresholds, 20);
+        assert!(monitor.paused);
+        assert_eq!(monitor.cool_since, Some(20));
+        monitor.update(ThermalLevel::Serious, &thresholds, 30);
+        assert_eq!(monitor.cool_since, None);
+
+        monitor.update(ThermalLevel::Nominal, &thresholds, 40);
+        assert_eq!(monitor.update(ThermalLevel::Nominal, &thresholds, 99), None);
+        assert!(monitor.paused);
+        let resumed = monitor
+            .update(ThermalLevel::Nominal, &thresholds, 100)
+            .unwrap();
+        assert!(!resumed.paused);
+    }
+}
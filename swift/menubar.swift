@@ -9,9 +9,23 @@ struct ActiveInstance {
     let location: String
 }
 
+struct ExpiredInstance {
+    let pid: Int
+    let location: String
+    let reason: String
+}
+
+struct Hold {
+    let id: Int
+    let reason: String?
+    let remainingSecs: Int
+}
+
 struct InstanceList {
     let active: [ActiveInstance]
     let inactive: [Int]
+    var expired: [ExpiredInstance] = []
+    var holds: [Hold] = []
     let hooksInstalled: Bool
     let sleepDisabled: Bool
 
@@ -112,6 +126,45 @@ final class AppDelegate: NSObject, NSApplicationDelegate, NSMenuDelegate {
         focusPid(sender.tag)
     }
 
+    @objc private func releaseHoldAction(_ sender: NSMenuItem) {
+        runAsp(["release", String(sender.tag)])
+    }
+
+    private func formatRemaining(_ secs: Int) -> String {
+        let hours = secs / 3600
+        let minutes = secs % 3600 / 60
+        if hours > 0 {
+            return minutes > 0 ? "\(hours)h \(minutes)m" : "\(hours)h"
+        }
+        return minutes > 0 ? "\(minutes)m" : "\(secs)s"
+    }
+
+    @objc private func extendAction() {
+        runAsp(["extend", "--minutes", "60"])
+    }
+
+    private func runAsp(_ arguments: [String]) {
+        let agentURL = Bundle.main.bundleURL
+            .appendingPathComponent("Contents/MacOS/asp")
+
+        DispatchQueue.global(qos: .userInitiated).async {
+            let process = Process()
+            process.executableURL = agentURL
+            process.arguments = arguments
+            process.standardOutput = FileHandle.nullDevice
+            process.standardError = FileHandle.nullDevice
+            do {
+                try process.run()
+                process.waitUntilExit()
+            } catch {
+                NSLog("Failed to run asp \(arguments.joined(separator: " ")): \(error)")
+            }
+            DispatchQueue.main.async {
+                self.refreshMenu()
+            }
+        }
+    }
+
     private func startAgent() {
         guard agentProcess == nil else { return }
         let agentURL = Bundle.main.bundleURL
@@ -154,7 +207,7 @@ final class AppDelegate: NSObject, NSApplicationDelegate, NSMenuDelegate {
         }
         isRefreshing = true
         DispatchQueue.global(qos: .background).async {
-            let list = self.fetchInstanceList()
+            let list = self.fetchInstanceList(checkHooks: true)
             DispatchQueue.main.async {
                 self.updateMenu(with: list)
                 self.isRefreshing = false
@@ -188,7 +241,9 @@ final class AppDelegate: NSObject, NSApplicationDelegate, NSMenuDelegate {
         }
     }
 
-    private func fetchInstanceList() -> InstanceList {
+    /// Checking hooks runs `asp status`, so only the menu asks for it; the
+    /// status title and hotkeys don't show it.
+    private func fetchInstanceList(checkHooks: Bool = false) -> InstanceList {
         let agentURL = Bundle.main.bundleURL
             .appendingPathComponent("Contents/MacOS/asp")
 
@@ -206,7 +261,7 @@ final class AppDelegate: NSObject, NSApplicationDelegate, NSMenuDelegate {
         }
 
         process.waitUntilExit()
-        let hooksInstalled = isHooksInstalled()
+        let hooksInstalled = checkHooks && isHooksInstalled()
 
         guard process.terminationStatus == 0 else {
             return InstanceList(active: [], inactive: [], hooksInstalled: hooksInstalled, sleepDisabled: false)
@@ -233,11 +288,43 @@ final class AppDelegate: NSObject, NSApplicationDelegate, NSMenuDelegate {
             active.append(ActiveInstance(pid: pid, ageSecs: ageSecs, cpu: cpu, location: location))
         }
 
+        let expiredArray = json["expired"] as? [[String: Any]] ?? []
+        let expired: [ExpiredInstance] = expiredArray.compactMap { item in
+            guard
+                let pid = (item["pid"] as? NSNumber)?.intValue,
+                let location = item["location"] as? String
+            else {
+                return nil
+            }
+            return ExpiredInstance(pid: pid, location: location, reason: item["reason"] as? String ?? "")
+        }
+
+        let holdsArray = json["holds"] as? [[String: Any]] ?? []
+        let holds: [Hold] = holdsArray.compactMap { item in
+            guard
+                let id = (item["id"] as? NSNumber)?.intValue,
+                let remainingSecs = (item["remaining_secs"] as? NSNumber)?.intValue
+            else {
+                return nil
+            }
+            return Hold(id: id, reason: item["reason"] as? String, remainingSecs: remainingSecs)
+        }
+
         let inactiveArray = json["inactive"] as? [Any] ?? []
-        let inactive = inactiveArray.compactMap { ($0 as? NSNumber)?.intValue }
+        let expiredPids = Set(expired.map { $0.pid })
+        let inactive = inactiveArray
+            .compactMap { ($0 as? NSNumber)?.intValue }
+            .filter { !expiredPids.contains($0) }
         let sleepDisabled = (json["sleep_disabled"] as? NSNumber)?.boolValue ?? false
 
-        return InstanceList(active: active, inactive: inactive, hooksInstalled: hooksInstalled, sleepDisabled: sleepDisabled)
+        return InstanceList(
+            active: active,
+            inactive: inactive,
+            expired: expired,
+            holds: holds,
+            hooksInstalled: hooksInstalled,
+            sleepDisabled: sleepDisabled
+        )
     }
 
     private func updateMenu(with list: InstanceList) {
@@ -265,6 +352,44 @@ final class AppDelegate: NSObject, NSApplicationDelegate, NSMenuDelegate {
             }
         }
 
+        if !list.holds.isEmpty {
+            menu.addItem(NSMenuItem.separator())
+            menu.addItem(disabledItem("Holds"))
+            for hold in list.holds {
+                let remaining = formatRemaining(hold.remainingSecs)
+                let title = "#\(hold.id) \(hold.reason ?? "Hold") - \(remaining) left"
+                let item = NSMenuItem(title: title, action: #selector(releaseHoldAction), keyEquivalent: "")
+                item.target = self
+                item.tag = hold.id
+                item.toolTip = "Click to release"
+                item.indentationLevel = 1
+                menu.addItem(item)
+            }
+        }
+
+        if !list.expired.isEmpty {
+            menu.addItem(NSMenuItem.separator())
+            menu.addItem(disabledItem("Expired by Safety Cap"))
+            for instance in list.expired.prefix(6) {
+                let item = NSMenuItem(
+                    title: "\(instance.location) [\(instance.pid)]",
+                    action: #selector(focusInstance),
+                    keyEquivalent: ""
+                )
+                item.target = self
+                item.tag = instance.pid
+                item.toolTip = instance.reason
+                item.indentationLevel = 1
+                menu.addItem(item)
+            }
+        }
+
+        if !list.active.isEmpty || !list.expired.isEmpty {
+            let extendItem = NSMenuItem(title: "Keep Awake 1 More Hour", action: #selector(extendAction), keyEquivalent: "")
+            extendItem.target = self
+            menu.addItem(extendItem)
+        }
+
         if list.inactiveCount > 0 {
             menu.addItem(NSMenuItem.separator())
             menu.addItem(disabledItem("Inactive Instances"))
@@ -476,24 +601,34 @@ final class AppDelegate: NSObject, NSApplicationDelegate, NSMenuDelegate {
         return item
     }
 
+    /// Ask `asp status --json`, which knows where each agent keeps its
+    /// config, instead of guessing paths here.
     private func isHooksInstalled() -> Bool {
-        let home = FileManager.default.homeDirectoryForCurrentUser
-        let claudeHooksPath = home
-            .appendingPathComponent(".claude/hooks/prevent-sleep.sh")
-        let hasClaudeHooks = FileManager.default.fileExists(atPath: claudeHooksPath.path)
+        let agentURL = Bundle.main.bundleURL
+            .appendingPathComponent("Contents/MacOS/asp")
+
+        let process = Process()
+        process.executableURL = agentURL
+        process.arguments = ["status", "--json"]
+        let pipe = Pipe()
+        process.standardOutput = pipe
+        process.standardError = FileHandle.nullDevice
 
-        let codexHooksPath = home.appendingPathComponent(".codex/hooks.json")
+        do {
+            try process.run()
+        } catch {
+            return false
+        }
+
+        let data = pipe.fileHandleForReading.readDataToEndOfFile()
+        process.waitUntilExit()
         guard
-            let hooksData = try? Data(contentsOf: codexHooksPath),
-            let hooksText = String(data: hooksData, encoding: .utf8)
+            process.terminationStatus == 0,
+            let json = try? JSONSerialization.jsonObject(with: data) as? [String: Any]
         else {
             return false
         }
-
-        let hasCodexHooks = hooksText.contains("AgentsSleepPreventer.app/Contents/MacOS/asp")
-            || hooksText.contains("/usr/local/bin/asp")
-            || hooksText.contains("/usr/local/bin/agents-sleep-preventer")
-        return hasClaudeHooks && hasCodexHooks
+        return (json["hooks_installed"] as? NSNumber)?.boolValue ?? false
     }
 
     private func promptInstallHooksIfNeeded() {
@@ -507,7 +642,7 @@ final class AppDelegate: NSObject, NSApplicationDelegate, NSMenuDelegate {
         let alert = NSAlert()
         alert.messageText = "Install Agent Hooks"
         alert.informativeText = """
-            This will configure Claude Code and Codex hooks to automatically prevent sleep while agents are working.
+            This will configure hooks for the coding agents installed on this Mac to automatically prevent sleep while they are working.
 
             The hooks will:
             • Prevent system sleep when an agent starts a task
@@ -12,6 +12,7 @@ const SPARKLE_KEY_ACCOUNT: &str = "CharlonTank-agents-sleep-preventer";
 const LEGACY_SPARKLE_KEY_ACCOUNT: &str = "CharlonTank-claude-sleep-preventer";
 const SPARKLE_APPCAST_ASSET_NAME: &str = "appcast.xml";
 const GITHUB_REPO: &str = "CharlonTank/agents-sleep-preventer";
+const HELPER_SOCKET_PATH: &str = "/var/run/agents-sleep-preventer.sock";
 const OWNED_HOOK_MARKERS: [&str; 4] = [
     "AgentsSleepPreventer.app/Contents/MacOS/asp",
     "/usr/local/bin/asp",
@@ -779,6 +780,25 @@ fn clean_codex_hooks(home: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Ask the installed privileged helper to turn `disablesleep` off. Without
+/// a helper there's nothing of ours holding sleep off.
+fn re_enable_sleep() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let Ok(mut stream) = UnixStream::connect(HELPER_SOCKET_PATH) else {
+        println!("  Privileged helper not running, skipping");
+        return;
+    };
+    let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(5)));
+    let _ = writeln!(stream, r#"{{"op":"set-disablesleep","enabled":false}}"#);
+    let mut answer = String::new();
+    let _ = BufReader::new(stream).read_line(&mut answer);
+    if !answer.contains(r#""ok":true"#) {
+        println!("  Helper did not confirm: {}", answer.trim());
+    }
+}
+
 fn clean(keep_model: bool) -> Result<()> {
     println!("=== Agents Sleep Preventer Cleanup ===\n");
     if keep_model {
@@ -891,13 +911,6 @@ fn clean(keep_model: bool) -> Result<()> {
     let _ = fs::remove_dir_all("/tmp/agents_working_pids");
     let _ = fs::remove_dir_all("/tmp/claude_working_pids");
 
-    // Remove sudoers config
-    println!("Removing sudoers config...");
-    for sudoers_path in ["/etc/sudoers.d/agents-pmset", "/etc/sudoers.d/claude-pmset"] {
-        let _ = Command::new("sudo")
-            .args(["rm", "-f", sudoers_path])
-            .status();
-    }
     let _ = fs::remove_file("/usr/local/bin/asp");
     let _ = fs::remove_file("/usr/local/bin/agents-sleep-preventer");
     let _ = fs::remove_file("/usr/local/bin/claude-sleep-preventer");
@@ -929,9 +942,7 @@ fn clean(keep_model: bool) -> Result<()> {
 
     // Re-enable sleep
     println!("Re-enabling sleep...");
-    let _ = Command::new("sudo")
-        .args(["pmset", "-a", "disablesleep", "0"])
-        .status();
+    re_enable_sleep();
 
     // Unmount any DMG
     println!("Unmounting DMG...");